@@ -0,0 +1,130 @@
+//! Energy benchmarks for the ways entrypoints read and write listings.
+//!
+//! The chain charges state access per host call and per byte moved, so the
+//! benchmarks run against [`MeteredStateApi`], which counts both.
+
+use super::*;
+use crate::test_state::MeteredStateApi;
+
+fn collection(index: u64) -> ContractAddress {
+    ContractAddress::new(index, 0)
+}
+
+fn listed(owner: AccountAddress) -> TokenState {
+    let mut token_state = TokenState::unlisted(owner);
+    token_state.curr_state = TokenListState::Listed;
+    token_state.price = Amount::from_ccd(100);
+    token_state.disallowed_countries = vec![*b"US", *b"KP"];
+    token_state
+}
+
+fn details() -> ListingDetails {
+    ListingDetails {
+        memo: vec![b'm'; MAX_MEMO_LEN],
+        metadata_verified: true,
+        display_hash: Some([7u8; 32]),
+    }
+}
+
+/// A map of `count` listings, and the key of one in the middle.
+fn listing_map(
+    api: &MeteredStateApi,
+    count: u32,
+) -> (StateMap<ListingKey, TokenState, MeteredStateApi>, ListingKey) {
+    let mut state_builder = StateBuilder::open(api.clone());
+    let mut map = state_builder.new_map();
+    for id in 0..count {
+        let key = ListingKey::new(0, TokenIdU32(id));
+        let _ = map.insert(key, listed(AccountAddress([1u8; 32])));
+    }
+    (map, ListingKey::new(0, TokenIdU32(count / 2)))
+}
+
+#[test]
+fn entry_mutation_beats_get_clone_insert() {
+    let api = MeteredStateApi::default();
+    let (mut map, key) = listing_map(&api, 100);
+    let buyer = AccountAddress([2u8; 32]);
+
+    let get_clone_insert = api.measure(|| {
+        let mut token_state = map.get(&key).unwrap().to_owned();
+        token_state.highest_bidder = buyer;
+        let _ = map.insert(key, token_state);
+    });
+    let entry_mutation = api.measure(|| {
+        let mut token_state = map.entry(key).occupied_or(()).unwrap();
+        token_state.highest_bidder = buyer;
+    });
+
+    assert_eq!(map.get(&key).unwrap().highest_bidder, buyer);
+    assert!(entry_mutation.calls < get_clone_insert.calls);
+    assert!(entry_mutation.bytes_read < get_clone_insert.bytes_read);
+    assert!(entry_mutation.energy() < get_clone_insert.energy());
+}
+
+#[test]
+fn presence_check_before_entry_doubles_the_read() {
+    let api = MeteredStateApi::default();
+    let (mut map, key) = listing_map(&api, 100);
+
+    let checked = api.measure(|| {
+        assert!(map.get(&key).is_some());
+        let _ = map.entry(key).occupied_or(()).unwrap().to_owned();
+    });
+    let unchecked = api.measure(|| {
+        let _ = map.entry(key).occupied_or(()).unwrap().to_owned();
+    });
+
+    assert_eq!(checked.bytes_read, 2 * unchecked.bytes_read);
+    assert!(unchecked.energy() < checked.energy());
+}
+
+#[test]
+fn unmodified_entry_is_not_written_back() {
+    let api = MeteredStateApi::default();
+    let (mut map, key) = listing_map(&api, 100);
+
+    let cost = api.measure(|| {
+        let token_state = map.entry(key).occupied_or(()).unwrap();
+        assert_eq!(token_state.curr_state, TokenListState::Listed);
+    });
+
+    assert_eq!(cost.bytes_written, 0);
+}
+
+#[test]
+fn child_map_keeps_details_off_the_purchase_path() {
+    let api = MeteredStateApi::default();
+    let mut state_builder = StateBuilder::open(api.clone());
+    let owner = AccountAddress([1u8; 32]);
+    let key = ListingKey::new(0, TokenIdU32(1));
+
+    let mut inline: StateMap<ListingKey, (TokenState, ListingDetails), _> =
+        state_builder.new_map();
+    let _ = inline.insert(key, (listed(owner), details()));
+    let mut split: StateMap<ListingKey, TokenState, _> = state_builder.new_map();
+    let mut split_details: StateMap<ListingKey, ListingDetails, _> = state_builder.new_map();
+    let _ = split.insert(key, listed(owner));
+    let _ = split_details.insert(key, details());
+
+    let inline_purchase = api.measure(|| {
+        let mut listing = inline.entry(key).occupied_or(()).unwrap();
+        listing.0.curr_state = TokenListState::UnListed;
+    });
+    let split_purchase = api.measure(|| {
+        let mut token_state = split.entry(key).occupied_or(()).unwrap();
+        token_state.curr_state = TokenListState::UnListed;
+    });
+
+    assert!(split_purchase.bytes_read + MAX_MEMO_LEN as u64 <= inline_purchase.bytes_read);
+    assert!(split_purchase.bytes_written + MAX_MEMO_LEN as u64 <= inline_purchase.bytes_written);
+}
+
+#[test]
+fn compact_keys_are_shorter_than_token_info() {
+    let info = TokenInfo::new(TokenIdU32(1), collection(5000));
+    let key = ListingKey::new(0, info.id);
+
+    assert_eq!(to_bytes(&key).len(), 8);
+    assert!(to_bytes(&key).len() < to_bytes(&info).len());
+}
@@ -0,0 +1,377 @@
+//! Thin client for invoking CIS-2 token contracts from another contract.
+//!
+//! The client works with any host state, so contracts other than the
+//! marketplace can reuse it by enabling the `cis2-client` feature. All calls
+//! go through `invoke_contract_raw_read_only`: the invoked token contract must
+//! not call back into a state-modifying entrypoint of the caller.
+//!
+//! Errors carry the entrypoint that failed and, for rejections, the reject
+//! reason and return value of the token contract.
+
+use concordium_cis2::*;
+use concordium_std::*;
+
+pub const SUPPORTS_ENTRYPOINT_NAME: &str = "supports";
+pub const OPERATOR_OF_ENTRYPOINT_NAME: &str = "operatorOf";
+pub const BALANCE_OF_ENTRYPOINT_NAME: &str = "balanceOf";
+pub const TRANSFER_ENTRYPOINT_NAME: &str = "transfer";
+pub const TOKEN_METADATA_ENTRYPOINT_NAME: &str = "tokenMetadata";
+pub const ROYALTY_INFO_ENTRYPOINT_NAME: &str = "royaltyInfo";
+
+const SUPPORTS_CIS2_PARAMETER: [u8; 8] = [1, 0, 5, b'C', b'I', b'S', b'-', b'2'];
+const INLINE_PARAMETER_SIZE: usize = 256;
+
+/// Error returned by [`Cis2Client`] calls.
+#[derive(Serialize, Debug, PartialEq, Eq, Reject)]
+pub enum Cis2ClientError {
+    /// The token contract could not be invoked or gave no usable response.
+    InvokeContractError(Cis2CallError),
+    /// The parameter could not be serialized.
+    ParseParams,
+    /// The response of the token contract could not be parsed.
+    ParseResult,
+}
+
+/// A failed call to a token contract entrypoint.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Cis2CallError {
+    pub entrypoint: OwnedEntrypointName,
+    pub kind: Cis2CallErrorKind,
+}
+
+/// Why a call to a token contract failed.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub enum Cis2CallErrorKind {
+    AmountTooLarge,
+    MissingAccount,
+    MissingContract,
+    MissingEntrypoint,
+    MessageFailed,
+    /// The token contract rejected the call with its own reject reason.
+    LogicReject {
+        reason: i32,
+        return_value: Vec<u8>,
+    },
+    Trap,
+    /// The call succeeded but returned fewer results than were queried.
+    EmptyResponse,
+}
+
+impl Cis2CallError {
+    fn new(entrypoint_name: &str, kind: Cis2CallErrorKind) -> Self {
+        Cis2CallError {
+            entrypoint: OwnedEntrypointName::new_unchecked(entrypoint_name.to_string()),
+            kind,
+        }
+    }
+}
+
+impl<R: HasCallResponse> From<CallContractError<R>> for Cis2CallErrorKind {
+    fn from(error: CallContractError<R>) -> Self {
+        match error {
+            CallContractError::AmountTooLarge => Cis2CallErrorKind::AmountTooLarge,
+            CallContractError::MissingAccount => Cis2CallErrorKind::MissingAccount,
+            CallContractError::MissingContract => Cis2CallErrorKind::MissingContract,
+            CallContractError::MissingEntrypoint => Cis2CallErrorKind::MissingEntrypoint,
+            CallContractError::MessageFailed => Cis2CallErrorKind::MessageFailed,
+            CallContractError::LogicReject {
+                reason,
+                mut return_value,
+            } => {
+                let mut bytes = vec![0u8; return_value.size() as usize];
+                let read = return_value.read(&mut bytes).unwrap_or(0);
+                bytes.truncate(read);
+                Cis2CallErrorKind::LogicReject {
+                    reason,
+                    return_value: bytes,
+                }
+            }
+            CallContractError::Trap => Cis2CallErrorKind::Trap,
+        }
+    }
+}
+
+/// Royalty a collection configures for a token under the CIS-2 royalty
+/// extension: `rate_bps` basis points of each sale go to `receiver`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct RoyaltyInfo {
+    pub receiver: AccountAddress,
+    pub rate_bps: u16,
+}
+
+struct ParameterBuffer {
+    bytes: [u8; INLINE_PARAMETER_SIZE],
+    len: usize,
+}
+
+impl Write for ParameterBuffer {
+    type Err = ();
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Err> {
+        let end = self
+            .len
+            .checked_add(buf.len())
+            .filter(|end| *end <= INLINE_PARAMETER_SIZE)
+            .ok_or(())?;
+        self.bytes[self.len..end].copy_from_slice(buf);
+        self.len = end;
+        Ok(buf.len())
+    }
+}
+
+struct QueryList<'a, T>(&'a [T]);
+
+impl<T: Serial> Serial for QueryList<'_, T> {
+    fn serial<W: Write>(&self, out: &mut W) -> Result<(), W::Err> {
+        (self.0.len() as u16).serial(out)?;
+        for query in self.0 {
+            query.serial(out)?;
+        }
+        Ok(())
+    }
+}
+
+struct FirstResult<T>(Option<T>);
+
+impl<T: Deserial> Deserial for FirstResult<T> {
+    fn deserial<R: Read>(source: &mut R) -> ParseResult<Self> {
+        let len = u16::deserial(source)?;
+        if len == 0 {
+            return Ok(FirstResult(None));
+        }
+        Ok(FirstResult(Some(T::deserial(source)?)))
+    }
+}
+
+/// Read-only invocations of the standard CIS-2 entrypoints.
+pub struct Cis2Client;
+
+impl Cis2Client {
+    /// Whether the contract reports support for CIS-2 itself (not by a
+    /// delegate contract).
+    pub fn supports_cis2<T>(
+        host: &impl HasHost<T>,
+        nft_contract_address: &ContractAddress,
+    ) -> Result<bool, Cis2ClientError> {
+        let parsed_res: FirstResult<SupportResult> = Cis2Client::invoke_raw(
+            host,
+            nft_contract_address,
+            SUPPORTS_ENTRYPOINT_NAME,
+            &SUPPORTS_CIS2_PARAMETER,
+        )?;
+        let supports_cis2 = match parsed_res
+            .0
+            .ok_or_else(|| empty_response(SUPPORTS_ENTRYPOINT_NAME))?
+        {
+            SupportResult::NoSupport => false,
+            SupportResult::Support => true,
+            SupportResult::SupportBy(_) => false,
+        };
+
+        Ok(supports_cis2)
+    }
+
+    /// Whether `current_contract_address` is an operator of `owner`.
+    pub fn is_operator_of<T>(
+        host: &impl HasHost<T>,
+        owner: Address,
+        current_contract_address: ContractAddress,
+        nft_contract_address: &ContractAddress,
+    ) -> Result<bool, Cis2ClientError> {
+        let queries = [OperatorOfQuery {
+            owner,
+            address: Address::Contract(current_contract_address),
+        }];
+        let is_operator = Cis2Client::operator_of_many(host, nft_contract_address, &queries)?;
+
+        Ok(is_operator[0])
+    }
+
+    /// Runs all `queries` in a single `operatorOf` call. The result has one
+    /// entry per query, in order.
+    pub fn operator_of_many<T>(
+        host: &impl HasHost<T>,
+        nft_contract_address: &ContractAddress,
+        queries: &[OperatorOfQuery],
+    ) -> Result<Vec<bool>, Cis2ClientError> {
+        let parsed_res: OperatorOfQueryResponse = Cis2Client::invoke_contract_read_only(
+            host,
+            nft_contract_address,
+            OPERATOR_OF_ENTRYPOINT_NAME,
+            &QueryList(queries),
+        )?;
+        if parsed_res.0.len() != queries.len() {
+            return Err(empty_response(OPERATOR_OF_ENTRYPOINT_NAME));
+        }
+
+        Ok(parsed_res.0)
+    }
+
+    /// Whether `owner` holds at least `amount` units of `token_id`.
+    pub fn has_balance<T, I: IsTokenId, A: IsTokenAmount + PartialOrd>(
+        host: &impl HasHost<T>,
+        token_id: I,
+        nft_contract_address: &ContractAddress,
+        owner: Address,
+        amount: A,
+    ) -> Result<bool, Cis2ClientError> {
+        let queries = [BalanceOfQuery {
+            token_id,
+            address: owner,
+        }];
+        let parsed_res: FirstResult<A> = Cis2Client::invoke_contract_read_only(
+            host,
+            nft_contract_address,
+            BALANCE_OF_ENTRYPOINT_NAME,
+            &QueryList(&queries),
+        )?;
+        let balance = parsed_res
+            .0
+            .ok_or_else(|| empty_response(BALANCE_OF_ENTRYPOINT_NAME))?;
+
+        Result::Ok(balance >= amount)
+    }
+
+    /// Runs all `queries` in a single `balanceOf` call. The result has one
+    /// entry per query, in order.
+    pub fn balance_of_many<T, I: IsTokenId, A: IsTokenAmount>(
+        host: &impl HasHost<T>,
+        nft_contract_address: &ContractAddress,
+        queries: &[BalanceOfQuery<I>],
+    ) -> Result<Vec<A>, Cis2ClientError> {
+        let parsed_res: BalanceOfQueryResponse<A> = Cis2Client::invoke_contract_read_only(
+            host,
+            nft_contract_address,
+            BALANCE_OF_ENTRYPOINT_NAME,
+            &QueryList(queries),
+        )?;
+        if parsed_res.0.len() != queries.len() {
+            return Err(empty_response(BALANCE_OF_ENTRYPOINT_NAME));
+        }
+
+        Ok(parsed_res.0)
+    }
+
+    /// The metadata URL and optional content hash of `token_id`.
+    pub fn token_metadata<T, I: IsTokenId>(
+        host: &impl HasHost<T>,
+        token_id: I,
+        nft_contract_address: &ContractAddress,
+    ) -> Result<MetadataUrl, Cis2ClientError> {
+        let queries = [token_id];
+        let parsed_res: FirstResult<MetadataUrl> = Cis2Client::invoke_contract_read_only(
+            host,
+            nft_contract_address,
+            TOKEN_METADATA_ENTRYPOINT_NAME,
+            &QueryList(&queries),
+        )?;
+
+        parsed_res
+            .0
+            .ok_or_else(|| empty_response(TOKEN_METADATA_ENTRYPOINT_NAME))
+    }
+
+    /// The royalty configured for `token_id`, or `None` if the contract does
+    /// not implement the royalty extension or reports no royalty.
+    pub fn royalty_info<T, I: IsTokenId>(
+        host: &impl HasHost<T>,
+        token_id: I,
+        nft_contract_address: &ContractAddress,
+    ) -> Result<Option<RoyaltyInfo>, Cis2ClientError> {
+        let queries = [token_id];
+        let parsed_res: Result<FirstResult<RoyaltyInfo>, _> = Cis2Client::invoke_contract_read_only(
+            host,
+            nft_contract_address,
+            ROYALTY_INFO_ENTRYPOINT_NAME,
+            &QueryList(&queries),
+        );
+        match parsed_res {
+            Ok(FirstResult(royalty)) => Ok(royalty.filter(|royalty| royalty.rate_bps > 0)),
+            Err(Cis2ClientError::InvokeContractError(Cis2CallError {
+                kind: Cis2CallErrorKind::MissingEntrypoint,
+                ..
+            })) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Transfers `amount` of `token_id` from `from` to `to`, passing `data`
+    /// on to the receive hook when `to` is a contract. The calling contract
+    /// must be an operator of `from`, unless it is `from` itself.
+    pub fn transfer<T, I: IsTokenId, A: IsTokenAmount>(
+        host: &impl HasHost<T>,
+        token_id: I,
+        nft_contract_address: ContractAddress,
+        amount: A,
+        from: Address,
+        to: Receiver,
+        data: AdditionalData,
+    ) -> Result<bool, Cis2ClientError> {
+        let transfers = [Transfer {
+            token_id,
+            amount,
+            from,
+            data,
+            to,
+        }];
+
+        Cis2Client::invoke_contract_read_only::<T, (), _>(
+            host,
+            &nft_contract_address,
+            TRANSFER_ENTRYPOINT_NAME,
+            &QueryList(&transfers),
+        )?;
+
+        Result::Ok(true)
+    }
+
+    fn invoke_contract_read_only<T, R: Deserial, P: Serial>(
+        host: &impl HasHost<T>,
+        contract_address: &ContractAddress,
+        entrypoint_name: &'static str,
+        params: &P,
+    ) -> Result<R, Cis2ClientError> {
+        let mut buffer = ParameterBuffer {
+            bytes: [0u8; INLINE_PARAMETER_SIZE],
+            len: 0,
+        };
+        if params.serial(&mut buffer).is_ok() {
+            Cis2Client::invoke_raw(host, contract_address, entrypoint_name, &buffer.bytes[..buffer.len])
+        } else {
+            Cis2Client::invoke_raw(host, contract_address, entrypoint_name, &to_bytes(params))
+        }
+    }
+
+    fn invoke_raw<T, R: Deserial>(
+        host: &impl HasHost<T>,
+        contract_address: &ContractAddress,
+        entrypoint_name: &'static str,
+        parameter: &[u8],
+    ) -> Result<R, Cis2ClientError> {
+        let invoke_contract_result = host
+            .invoke_contract_raw_read_only(
+                contract_address,
+                Parameter::new_unchecked(parameter),
+                EntrypointName::new_unchecked(entrypoint_name),
+                Amount::zero(),
+            )
+            .map_err(|e| {
+                Cis2ClientError::InvokeContractError(Cis2CallError::new(entrypoint_name, e.into()))
+            })?;
+        let mut invoke_contract_res = match invoke_contract_result {
+            Some(s) => s,
+            None => return Result::Err(empty_response(entrypoint_name)),
+        };
+        let parsed_res =
+            R::deserial(&mut invoke_contract_res).map_err(|_e| Cis2ClientError::ParseResult)?;
+
+        Ok(parsed_res)
+    }
+}
+
+fn empty_response(entrypoint_name: &str) -> Cis2ClientError {
+    Cis2ClientError::InvokeContractError(Cis2CallError::new(
+        entrypoint_name,
+        Cis2CallErrorKind::EmptyResponse,
+    ))
+}
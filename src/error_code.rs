@@ -0,0 +1,263 @@
+use concordium_std::*;
+
+use crate::MarketplaceError;
+
+impl MarketplaceError {
+    /// Frozen reject code of each variant. Codes are never reused or
+    /// renumbered; new variants take the next code down.
+    pub fn error_code(&self) -> i32 {
+        match self {
+            MarketplaceError::ParseParams => -1,
+            MarketplaceError::CalledByAContract => -2,
+            MarketplaceError::TokenNotListed => -3,
+            MarketplaceError::Cis2ClientError(_) => -4,
+            MarketplaceError::CollectionNotCis2 => -5,
+            MarketplaceError::InvalidAmountPaid => -6,
+            MarketplaceError::InvokeTransferError => -7,
+            MarketplaceError::NoBalance => -8,
+            MarketplaceError::NotOperator => -9,
+            MarketplaceError::NotMatchedSaleType => -10,
+            MarketplaceError::NotEnoughBalance => -11,
+            MarketplaceError::ExpiredAlready => -12,
+            MarketplaceError::CanNotBidYourSelf => -13,
+            MarketplaceError::CanceledAlready => -14,
+            MarketplaceError::Unauthorized => -15,
+            MarketplaceError::NotBidded => -16,
+            MarketplaceError::InvalidSignerSet => -17,
+            MarketplaceError::ProposalNotFound => -18,
+            MarketplaceError::AlreadyApproved => -19,
+            MarketplaceError::ContractPaused => -20,
+            MarketplaceError::NotPaused => -21,
+            MarketplaceError::BatchTooLarge => -22,
+            MarketplaceError::SelfTrade => -23,
+            MarketplaceError::LogError => -24,
+            MarketplaceError::AuctionHasBids => -25,
+            MarketplaceError::InvalidExpiry => -26,
+            MarketplaceError::ExceedsMaxDuration => -27,
+            MarketplaceError::NothingToClaim => -28,
+            MarketplaceError::NoPendingTransfer => -29,
+            MarketplaceError::NotBidOperator => -30,
+            MarketplaceError::InsufficientDeposit => -31,
+            MarketplaceError::DropNotFound => -32,
+            MarketplaceError::PurchaseLimitReached => -33,
+            MarketplaceError::RelistCooldown => -34,
+            MarketplaceError::MemoTooLong => -35,
+            MarketplaceError::ReserveNotMet => -36,
+            MarketplaceError::InvalidReserve => -37,
+            MarketplaceError::InvalidRate => -38,
+            MarketplaceError::NoRates => -39,
+            MarketplaceError::ReceiptMintFailed => -40,
+            MarketplaceError::InsufficientPoints => -41,
+            MarketplaceError::ReferralCodeTaken => -42,
+            MarketplaceError::UnknownReferralCode => -43,
+            MarketplaceError::InvalidReferralCode => -44,
+            MarketplaceError::OfferNotFound => -45,
+            MarketplaceError::InvalidOffer => -46,
+            MarketplaceError::NotAllowlisted => -47,
+            MarketplaceError::BidTooSoon => -48,
+            MarketplaceError::MysteryBoxNotFound => -49,
+            MarketplaceError::SoldOut => -50,
+            MarketplaceError::AlreadyRevealed => -51,
+            MarketplaceError::NotRevealed => -52,
+            MarketplaceError::InvalidReveal => -53,
+            MarketplaceError::AlreadyOpened => -54,
+            MarketplaceError::CurveSaleNotFound => -55,
+            MarketplaceError::InvalidCurve => -56,
+            MarketplaceError::BuyOrderNotFound => -57,
+            MarketplaceError::NoBuyOrders => -58,
+            MarketplaceError::ContractBuyerNotAllowed => -59,
+            MarketplaceError::CollectionNotFound => -60,
+            MarketplaceError::CollectionTrapped => -61,
+            MarketplaceError::MetadataMismatch => -62,
+            MarketplaceError::InvalidTranche => -63,
+            MarketplaceError::DropNotActive => -64,
+            MarketplaceError::BidderNotAllowlisted => -65,
+            MarketplaceError::IdentityTierTooLow => -66,
+            MarketplaceError::NotIdentityVerifier => -67,
+            MarketplaceError::ResidencyNotProven => -68,
+            MarketplaceError::JurisdictionRestricted => -69,
+            MarketplaceError::InvalidCandle => -70,
+            MarketplaceError::CandleNotClosed => -71,
+            MarketplaceError::RevealDeadlinePassed => -72,
+            MarketplaceError::PriceBelowMinimum => -73,
+            MarketplaceError::DurationOutOfRange => -74,
+            MarketplaceError::NotCollectionStudio => -75,
+            MarketplaceError::NotModerator => -76,
+            MarketplaceError::SellerNotVerified => -77,
+            MarketplaceError::EscrowNotFound => -78,
+            MarketplaceError::NotArbiter => -79,
+            MarketplaceError::EscrowLocked => -80,
+            MarketplaceError::RepairMismatch => -81,
+            MarketplaceError::InvalidPageSize => -82,
+            MarketplaceError::InvalidRoyaltyRecipient => -83,
+            MarketplaceError::InsufficientFeePool => -84,
+            MarketplaceError::CollectionNotWhitelisted => -85,
+            MarketplaceError::CollectionBlocked => -86,
+            MarketplaceError::AccountBlocked => -87,
+            MarketplaceError::UpgradeFailed => -88,
+            MarketplaceError::MigrationFailed => -89,
+            MarketplaceError::UnsupportedStateVersion => -90,
+            MarketplaceError::PaymentTokenUnsupported => -91,
+            MarketplaceError::CurrencyMismatch => -92,
+            MarketplaceError::InvalidQuantity => -93,
+            MarketplaceError::AlreadyListed => -94,
+            MarketplaceError::BidIncrementTooSmall => -95,
+            MarketplaceError::InvalidDutchAuction => -96,
+            MarketplaceError::InsufficientRefund => -97,
+            MarketplaceError::ProposalNotApproved => -98,
+            MarketplaceError::NotAnUpgrade => -99,
+            MarketplaceError::MigrationRequired => -100,
+            MarketplaceError::OutstandingPenaltyDebt => -101,
+            MarketplaceError::AmountOverflow => -102,
+        }
+    }
+}
+
+impl From<MarketplaceError> for Reject {
+    fn from(error: MarketplaceError) -> Self {
+        let error_code = num::NonZeroI32::new(error.error_code()).unwrap_abort();
+        Reject {
+            error_code,
+            return_value: Some(to_bytes(&error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cis2ClientError;
+
+    fn frozen_codes() -> Vec<(MarketplaceError, i32)> {
+        vec![
+            (MarketplaceError::ParseParams, -1),
+            (MarketplaceError::CalledByAContract, -2),
+            (MarketplaceError::TokenNotListed, -3),
+            (MarketplaceError::Cis2ClientError(Cis2ClientError::ParseResult), -4),
+            (MarketplaceError::CollectionNotCis2, -5),
+            (MarketplaceError::InvalidAmountPaid, -6),
+            (MarketplaceError::InvokeTransferError, -7),
+            (MarketplaceError::NoBalance, -8),
+            (MarketplaceError::NotOperator, -9),
+            (MarketplaceError::NotMatchedSaleType, -10),
+            (MarketplaceError::NotEnoughBalance, -11),
+            (MarketplaceError::ExpiredAlready, -12),
+            (MarketplaceError::CanNotBidYourSelf, -13),
+            (MarketplaceError::CanceledAlready, -14),
+            (MarketplaceError::Unauthorized, -15),
+            (MarketplaceError::NotBidded, -16),
+            (MarketplaceError::InvalidSignerSet, -17),
+            (MarketplaceError::ProposalNotFound, -18),
+            (MarketplaceError::AlreadyApproved, -19),
+            (MarketplaceError::ContractPaused, -20),
+            (MarketplaceError::NotPaused, -21),
+            (MarketplaceError::BatchTooLarge, -22),
+            (MarketplaceError::SelfTrade, -23),
+            (MarketplaceError::LogError, -24),
+            (MarketplaceError::AuctionHasBids, -25),
+            (MarketplaceError::InvalidExpiry, -26),
+            (MarketplaceError::ExceedsMaxDuration, -27),
+            (MarketplaceError::NothingToClaim, -28),
+            (MarketplaceError::NoPendingTransfer, -29),
+            (MarketplaceError::NotBidOperator, -30),
+            (MarketplaceError::InsufficientDeposit, -31),
+            (MarketplaceError::DropNotFound, -32),
+            (MarketplaceError::PurchaseLimitReached, -33),
+            (MarketplaceError::RelistCooldown, -34),
+            (MarketplaceError::MemoTooLong, -35),
+            (MarketplaceError::ReserveNotMet, -36),
+            (MarketplaceError::InvalidReserve, -37),
+            (MarketplaceError::InvalidRate, -38),
+            (MarketplaceError::NoRates, -39),
+            (MarketplaceError::ReceiptMintFailed, -40),
+            (MarketplaceError::InsufficientPoints, -41),
+            (MarketplaceError::ReferralCodeTaken, -42),
+            (MarketplaceError::UnknownReferralCode, -43),
+            (MarketplaceError::InvalidReferralCode, -44),
+            (MarketplaceError::OfferNotFound, -45),
+            (MarketplaceError::InvalidOffer, -46),
+            (MarketplaceError::NotAllowlisted, -47),
+            (MarketplaceError::BidTooSoon, -48),
+            (MarketplaceError::MysteryBoxNotFound, -49),
+            (MarketplaceError::SoldOut, -50),
+            (MarketplaceError::AlreadyRevealed, -51),
+            (MarketplaceError::NotRevealed, -52),
+            (MarketplaceError::InvalidReveal, -53),
+            (MarketplaceError::AlreadyOpened, -54),
+            (MarketplaceError::CurveSaleNotFound, -55),
+            (MarketplaceError::InvalidCurve, -56),
+            (MarketplaceError::BuyOrderNotFound, -57),
+            (MarketplaceError::NoBuyOrders, -58),
+            (MarketplaceError::ContractBuyerNotAllowed, -59),
+            (MarketplaceError::CollectionNotFound, -60),
+            (MarketplaceError::CollectionTrapped, -61),
+            (MarketplaceError::MetadataMismatch, -62),
+            (MarketplaceError::InvalidTranche, -63),
+            (MarketplaceError::DropNotActive, -64),
+            (MarketplaceError::BidderNotAllowlisted, -65),
+            (MarketplaceError::IdentityTierTooLow, -66),
+            (MarketplaceError::NotIdentityVerifier, -67),
+            (MarketplaceError::ResidencyNotProven, -68),
+            (MarketplaceError::JurisdictionRestricted, -69),
+            (MarketplaceError::InvalidCandle, -70),
+            (MarketplaceError::CandleNotClosed, -71),
+            (MarketplaceError::RevealDeadlinePassed, -72),
+            (MarketplaceError::PriceBelowMinimum, -73),
+            (MarketplaceError::DurationOutOfRange, -74),
+            (MarketplaceError::NotCollectionStudio, -75),
+            (MarketplaceError::NotModerator, -76),
+            (MarketplaceError::SellerNotVerified, -77),
+            (MarketplaceError::EscrowNotFound, -78),
+            (MarketplaceError::NotArbiter, -79),
+            (MarketplaceError::EscrowLocked, -80),
+            (MarketplaceError::RepairMismatch, -81),
+            (MarketplaceError::InvalidPageSize, -82),
+            (MarketplaceError::InvalidRoyaltyRecipient, -83),
+            (MarketplaceError::InsufficientFeePool, -84),
+            (MarketplaceError::CollectionNotWhitelisted, -85),
+            (MarketplaceError::CollectionBlocked, -86),
+            (MarketplaceError::AccountBlocked, -87),
+            (MarketplaceError::UpgradeFailed, -88),
+            (MarketplaceError::MigrationFailed, -89),
+            (MarketplaceError::UnsupportedStateVersion, -90),
+            (MarketplaceError::PaymentTokenUnsupported, -91),
+            (MarketplaceError::CurrencyMismatch, -92),
+            (MarketplaceError::InvalidQuantity, -93),
+            (MarketplaceError::AlreadyListed, -94),
+            (MarketplaceError::BidIncrementTooSmall, -95),
+            (MarketplaceError::InvalidDutchAuction, -96),
+            (MarketplaceError::InsufficientRefund, -97),
+            (MarketplaceError::ProposalNotApproved, -98),
+            (MarketplaceError::NotAnUpgrade, -99),
+            (MarketplaceError::MigrationRequired, -100),
+            (MarketplaceError::OutstandingPenaltyDebt, -101),
+            (MarketplaceError::AmountOverflow, -102),
+        ]
+    }
+
+    #[test]
+    fn error_codes_are_frozen() {
+        for (error, code) in frozen_codes() {
+            assert_eq!(error.error_code(), code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn error_codes_are_unique_and_negative() {
+        let codes: Vec<i32> = frozen_codes().iter().map(|(error, _)| error.error_code()).collect();
+        for (i, code) in codes.iter().enumerate() {
+            assert!(*code < 0);
+            assert!(!codes[i + 1..].contains(code));
+        }
+    }
+
+    #[test]
+    fn reject_uses_error_code() {
+        for (error, code) in frozen_codes() {
+            let bytes = to_bytes(&error);
+            let reject = Reject::from(error);
+            assert_eq!(reject.error_code.get(), code);
+            assert_eq!(reject.return_value, Some(bytes));
+        }
+    }
+}
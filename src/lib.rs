@@ -3,7 +3,30 @@
 use concordium_std::*;
 use concordium_cis2::*;
 
-#[derive(Serialize, Debug, PartialEq, Eq, Reject)]
+#[cfg(feature = "cis2-client")]
+pub mod cis2_client;
+#[cfg(not(feature = "cis2-client"))]
+mod cis2_client;
+mod error_code;
+mod listings;
+mod migration;
+mod randomness;
+#[cfg(test)]
+mod benches;
+#[cfg(test)]
+mod test_state;
+#[cfg(test)]
+mod tests;
+
+use listings::{ListingKey, Listings};
+
+pub use cis2_client::{
+    Cis2CallError, Cis2CallErrorKind, Cis2Client, Cis2ClientError, BALANCE_OF_ENTRYPOINT_NAME,
+    OPERATOR_OF_ENTRYPOINT_NAME, SUPPORTS_ENTRYPOINT_NAME, TOKEN_METADATA_ENTRYPOINT_NAME,
+    TRANSFER_ENTRYPOINT_NAME,
+};
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
 pub enum MarketplaceError {
     ParseParams,
     CalledByAContract,
@@ -21,30 +44,147 @@ pub enum MarketplaceError {
     CanceledAlready,
     Unauthorized,
     NotBidded,
+    InvalidSignerSet,
+    ProposalNotFound,
+    AlreadyApproved,
+    ContractPaused,
+    NotPaused,
+    BatchTooLarge,
+    SelfTrade,
+    LogError,
+    AuctionHasBids,
+    InvalidExpiry,
+    ExceedsMaxDuration,
+    NothingToClaim,
+    NoPendingTransfer,
+    NotBidOperator,
+    InsufficientDeposit,
+    DropNotFound,
+    PurchaseLimitReached,
+    RelistCooldown,
+    MemoTooLong,
+    ReserveNotMet,
+    InvalidReserve,
+    InvalidRate,
+    NoRates,
+    ReceiptMintFailed,
+    InsufficientPoints,
+    ReferralCodeTaken,
+    UnknownReferralCode,
+    InvalidReferralCode,
+    OfferNotFound,
+    InvalidOffer,
+    NotAllowlisted,
+    BidTooSoon,
+    MysteryBoxNotFound,
+    SoldOut,
+    AlreadyRevealed,
+    NotRevealed,
+    InvalidReveal,
+    AlreadyOpened,
+    CurveSaleNotFound,
+    InvalidCurve,
+    BuyOrderNotFound,
+    NoBuyOrders,
+    ContractBuyerNotAllowed,
+    CollectionNotFound,
+    CollectionTrapped,
+    MetadataMismatch,
+    InvalidTranche,
+    DropNotActive,
+    BidderNotAllowlisted,
+    IdentityTierTooLow,
+    NotIdentityVerifier,
+    ResidencyNotProven,
+    JurisdictionRestricted,
+    InvalidCandle,
+    CandleNotClosed,
+    RevealDeadlinePassed,
+    PriceBelowMinimum,
+    DurationOutOfRange,
+    NotCollectionStudio,
+    NotModerator,
+    SellerNotVerified,
+    EscrowNotFound,
+    NotArbiter,
+    EscrowLocked,
+    RepairMismatch,
+    InvalidPageSize,
+    InvalidRoyaltyRecipient,
+    InsufficientFeePool,
+    CollectionNotWhitelisted,
+    CollectionBlocked,
+    AccountBlocked,
+    UpgradeFailed,
+    MigrationFailed,
+    UnsupportedStateVersion,
+    PaymentTokenUnsupported,
+    CurrencyMismatch,
+    InvalidQuantity,
+    AlreadyListed,
+    BidIncrementTooSmall,
+    InvalidDutchAuction,
+    InsufficientRefund,
+    ProposalNotApproved,
+    NotAnUpgrade,
+    MigrationRequired,
+    OutstandingPenaltyDebt,
+    AmountOverflow,
 }
 
-#[derive(Serialize, Debug, PartialEq, Eq, Reject)]
-pub enum Cis2ClientError {
-    InvokeContractError,
-    ParseParams,
-    ParseResult,
+pub const ON_RECEIVING_CIS2_ENTRYPOINT_NAME: &str = "onReceivingCIS2";
+/// `data` of the transfers the marketplace makes into its own custody.
+const CUSTODY_TAG: &[u8] = b"pixpel:custody";
+
+impl From<randomness::RevealError> for MarketplaceError {
+    fn from(error: randomness::RevealError) -> Self {
+        match error {
+            randomness::RevealError::Mismatch => MarketplaceError::InvalidReveal,
+            randomness::RevealError::DeadlinePassed => MarketplaceError::RevealDeadlinePassed,
+        }
+    }
+}
+
+impl From<Cis2ClientError> for MarketplaceError {
+    fn from(error: Cis2ClientError) -> Self {
+        match &error {
+            Cis2ClientError::InvokeContractError(call) => match call.kind {
+                Cis2CallErrorKind::MissingContract => MarketplaceError::CollectionNotFound,
+                Cis2CallErrorKind::MissingEntrypoint => MarketplaceError::CollectionNotCis2,
+                Cis2CallErrorKind::Trap => MarketplaceError::CollectionTrapped,
+                _ => MarketplaceError::Cis2ClientError(error),
+            },
+            _ => MarketplaceError::Cis2ClientError(error),
+        }
+    }
 }
 
-pub const SUPPORTS_ENTRYPOINT_NAME: &str = "supports";
-pub const OPERATOR_OF_ENTRYPOINT_NAME: &str = "operatorOf";
-pub const BALANCE_OF_ENTRYPOINT_NAME: &str = "balanceOf";
-pub const TRANSFER_ENTRYPOINT_NAME: &str = "transfer";
+pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Bumped whenever the layout of `State` changes.
+pub const STATE_VERSION: u16 = 23;
+pub const MAX_BATCH_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 50;
+pub const MAX_MEMO_LEN: usize = 256;
+pub const BASIS_POINTS: u16 = 10_000;
+/// Upper bound on the share of seller proceeds paid to whoever finalises an
+/// expired auction.
+pub const MAX_KEEPER_REWARD_BPS: u16 = 100;
+pub const MAX_REFERRAL_CODE_LEN: usize = 32;
+/// Time buyers of a mystery box have to reveal their secrets once the creator
+/// has revealed the seed.
+pub const BOX_SECRET_REVEAL_WINDOW: u64 = 86_400_000;
+/// Age a refund must reach before keepers are paid to push it out, unless
+/// changed by the signers.
+pub const DEFAULT_REFUND_MIN_AGE: u64 = 30 * 86_400_000;
 
-pub type ContractTokenAmount = TokenAmountU8;
-type ContractBalanceOfQueryParams = BalanceOfQueryParams<ContractTokenId>;
-type ContractBalanceOfQueryResponse = BalanceOfQueryResponse<ContractTokenAmount>;
-type TransferParameter = TransferParams<ContractTokenId, ContractTokenAmount>;
+pub type ContractTokenAmount = TokenAmountU64;
+type ContractOnReceivingCis2Params = OnReceivingCis2Params<ContractTokenId, ContractTokenAmount>;
 
 type ContractResult<A> = Result<A, MarketplaceError>;
 
 pub type ContractTokenId = TokenIdU32;
 
-#[derive(Clone, Serialize, SchemaType)]
+#[derive(Clone, Serialize, SchemaType, PartialEq, Eq, Debug)]
 struct TokenInfo {
     pub id: ContractTokenId,
     pub address: ContractAddress,
@@ -66,6 +206,208 @@ enum TokenListState {
 enum TokenSaleTypeState {
     Fixed,
     Auction,
+    DutchAuction,
+}
+
+impl TokenSaleTypeState {
+    /// Sale type from its parameter code: 0 fixed price, 2 Dutch auction and
+    /// any other value an English auction.
+    fn from_code(code: u8) -> Self {
+        match code {
+            0 => TokenSaleTypeState::Fixed,
+            2 => TokenSaleTypeState::DutchAuction,
+            _ => TokenSaleTypeState::Auction,
+        }
+    }
+}
+
+/// A price falling linearly from `start_price` at `start` to `end_price`
+/// `duration` milliseconds later; the first buyer to pay the current price
+/// wins.
+#[derive(Clone, Serialize, SchemaType)]
+struct DutchAuction {
+    start_price: Amount,
+    end_price: Amount,
+    start: u64,
+    duration: u64,
+}
+
+impl DutchAuction {
+    fn price_at(&self, now: u64) -> Amount {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed >= self.duration {
+            return self.end_price;
+        }
+        let drop = self.start_price.micro_ccd - self.end_price.micro_ccd;
+        let fallen = u128::from(drop) * u128::from(elapsed) / u128::from(self.duration);
+        Amount::from_micro_ccd(self.start_price.micro_ccd - fallen as u64)
+    }
+}
+
+#[derive(Clone, Serialize, SchemaType)]
+struct CharityShare {
+    account: AccountAddress,
+    rate_bps: u16,
+}
+
+/// Refundable bond a seller posts on a high-value auction. It is forfeited
+/// to the winning bidder if the token cannot be delivered at settlement.
+#[derive(Clone, Serialize, SchemaType)]
+struct SellerBond {
+    account: AccountAddress,
+    amount: Amount,
+}
+
+/// Arbiter who must release the seller's proceeds of a sale. After
+/// `timeout` milliseconds without a decision the seller may claim them.
+#[derive(Clone, Serialize, SchemaType)]
+struct ArbiterTerms {
+    arbiter: AccountAddress,
+    timeout: u64,
+}
+
+/// CIS-2 token a listing is paid in, and the price (or highest bid) in units
+/// of that token. Buyers and bidders must have made the marketplace an
+/// operator on the payment contract. A purchase moves the tokens directly from
+/// the buyer to the payees; bids are held in custody and, once outbid or
+/// settled, credited to the account for `claim_token_credits`.
+#[derive(Clone, Serialize, SchemaType, Debug, PartialEq, Eq)]
+struct PaymentToken {
+    contract: ContractAddress,
+    token_id: TokenIdVec,
+    amount: TokenAmountU64,
+}
+
+/// Settlement currency of a listing. `price`, `reserve` and the other CCD
+/// amounts of a listing only apply to `Ccd`.
+#[derive(Clone, Serialize, SchemaType, Debug, PartialEq, Eq)]
+enum Currency {
+    Ccd,
+    Cis2(PaymentToken),
+}
+
+/// Share of every sale paid to the creator. Collections with a sale template
+/// pay their studio; other listings name the recipient themselves.
+#[derive(Clone, Serialize, SchemaType)]
+struct Royalty {
+    account: AccountAddress,
+    rate_bps: u16,
+}
+
+/// Sale defaults a studio registers for its collection. Listings inherit the
+/// royalty and default auction duration and must stay within the bounds;
+/// a zero `max_duration` leaves auction length unbounded.
+#[derive(Clone, Serialize, SchemaType)]
+struct SaleTemplate {
+    royalty_bps: u16,
+    min_price: Amount,
+    default_duration: u64,
+    min_duration: u64,
+    max_duration: u64,
+}
+
+impl SaleTemplate {
+    /// Checks a listing against the template, filling in the default auction
+    /// duration, and returns the royalty rate to apply.
+    fn apply(
+        &self,
+        sale_type: TokenSaleTypeState,
+        price: Amount,
+        expiry: &mut u64,
+        royalty_bps: Option<u16>,
+        now: u64,
+    ) -> ContractResult<u16> {
+        ensure!(price >= self.min_price, MarketplaceError::PriceBelowMinimum);
+        if sale_type == TokenSaleTypeState::Auction {
+            if *expiry == 0 && self.default_duration > 0 {
+                *expiry = now.saturating_add(self.default_duration);
+            }
+            let duration = expiry.saturating_sub(now);
+            ensure!(
+                (*expiry == 0 && self.max_duration == 0)
+                    || (*expiry != 0
+                        && duration >= self.min_duration
+                        && (self.max_duration == 0 || duration <= self.max_duration)),
+                MarketplaceError::DurationOutOfRange
+            );
+        }
+        let rate_bps = royalty_bps.unwrap_or(self.royalty_bps);
+        ensure!(
+            rate_bps >= self.royalty_bps && rate_bps <= BASIS_POINTS,
+            MarketplaceError::InvalidRate
+        );
+        Ok(rate_bps)
+    }
+}
+
+/// One bid on an auction; `amount` is in the smallest unit of the auction's
+/// currency.
+#[derive(Clone, Serialize, SchemaType)]
+struct BidRecord {
+    bidder: AccountAddress,
+    amount: u64,
+    placed_at: u64,
+}
+
+/// Extends an auction by `extension` milliseconds whenever a bid arrives
+/// within `threshold` milliseconds of its expiry.
+#[derive(Clone, Serialize, SchemaType)]
+struct AntiSnipe {
+    threshold: u64,
+    extension: u64,
+}
+
+/// Minimum amount by which a bid must beat the current highest bid, either
+/// in the smallest unit of the listing's currency or in basis points of the
+/// highest bid.
+#[derive(Clone, Serialize, SchemaType)]
+enum BidIncrement {
+    Absolute(u64),
+    Percentage(u16),
+}
+
+impl BidIncrement {
+    /// Smallest bid that beats `current` by the increment.
+    fn min_next_bid(&self, current: u64) -> u64 {
+        let step = match self {
+            BidIncrement::Absolute(step) => *step,
+            BidIncrement::Percentage(bps) => {
+                (u128::from(current) * u128::from(*bps) / u128::from(BASIS_POINTS)) as u64
+            }
+        };
+        current.saturating_add(step)
+    }
+}
+
+/// Restricts who may bid on an auction, either to an explicit set of
+/// accounts or to the leaves of a Merkle tree of account addresses.
+#[derive(Clone, Serialize, SchemaType)]
+enum BidderAllowlist {
+    Accounts(Vec<AccountAddress>),
+    MerkleRoot([u8; 32]),
+}
+
+/// Candle close for an auction: the auction effectively ends at a random
+/// point within the last `window` milliseconds before expiry, derived from a
+/// seed the seller commits to at listing and reveals after expiry, mixed with
+/// the bids placed in the window.
+#[derive(Clone, Serialize, SchemaType)]
+struct CandleClose {
+    window: u64,
+    commitment: randomness::Commitment,
+}
+
+#[derive(Clone, Serialize, SchemaType)]
+struct CandleParams {
+    window: u64,
+    commitment: [u8; 32],
+}
+
+#[derive(Clone, Serialize, SchemaType)]
+struct CandleBid {
+    bidder: AccountAddress,
+    amount: Amount,
+    placed_at: u64,
 }
 
 #[derive(Clone, Serialize, SchemaType)]
@@ -76,448 +418,7965 @@ struct TokenState {
     expiry: u64,
     highest_bidder: AccountAddress,
     price: Amount,
+    drop_id: Option<u64>,
+    sold_at: u64,
+    reserve: Amount,
+    buy_now: Option<Amount>,
+    charity: Option<CharityShare>,
+    bid_count: u32,
+    referrer: Option<AccountAddress>,
+    min_bid_interval: u64,
+    participation_deposit: Amount,
+    convert_to_fixed: bool,
+    allow_contract_buyers: bool,
+    settlement_data: AdditionalData,
+    metadata_hash: Option<[u8; 32]>,
+    bidder_allowlist: Option<BidderAllowlist>,
+    required_tier: u8,
+    disallowed_countries: Vec<[u8; 2]>,
+    candle: Option<CandleClose>,
+    royalty: Option<Royalty>,
+    bond: Option<SellerBond>,
+    arbiter: Option<ArbiterTerms>,
+    currency: Currency,
+    custodian: Option<ContractAddress>,
+    amount: ContractTokenAmount,
+    min_increment: Option<BidIncrement>,
+    anti_snipe: Option<AntiSnipe>,
+    dutch_auction: Option<DutchAuction>,
+    keeper: Option<AccountAddress>,
+    /// Distinguishes each listing of the token, so that a listing transfer
+    /// offered on one listing cannot be accepted on a later one. Zero for
+    /// listings made before nonces were introduced.
+    listing_nonce: u64,
+    /// Index in the token's bid history of the first bid on this listing.
+    /// Earlier records belong to earlier auctions of the token.
+    bid_history_start: u32,
+    /// Opening price of the listing, in micro CCD or in units of its payment
+    /// token, restored when the standing bid is withdrawn.
+    starting_bid: u64,
 }
 
-#[derive(Serial, DeserialWithState, StateClone)]
-#[concordium(state_parameter = "S")]
-struct State<S>
-{
-    tokens: StateMap<TokenInfo, TokenState, S>,
+/// Listing data that settlement never reads, kept apart from `TokenState` so
+/// that buying and bidding do not load it.
+#[derive(Serialize, SchemaType, Clone)]
+struct ListingDetails {
+    memo: Vec<u8>,
+    metadata_verified: bool,
+    display_hash: Option<[u8; 32]>,
+}
+
+impl TokenState {
+    fn unlisted(owner: AccountAddress) -> Self {
+        TokenState {
+            sale_type: TokenSaleTypeState::Fixed,
+            curr_state: TokenListState::UnListed,
+            owner,
+            expiry: 0,
+            highest_bidder: AccountAddress([0u8; 32]),
+            price: Amount::zero(),
+            drop_id: None,
+            sold_at: 0,
+            reserve: Amount::zero(),
+            buy_now: None,
+            charity: None,
+            bid_count: 0,
+            referrer: None,
+            min_bid_interval: 0,
+            participation_deposit: Amount::zero(),
+            convert_to_fixed: false,
+            allow_contract_buyers: false,
+            settlement_data: AdditionalData::empty(),
+            metadata_hash: None,
+            bidder_allowlist: None,
+            required_tier: 0,
+            disallowed_countries: Vec::new(),
+            candle: None,
+            royalty: None,
+            bond: None,
+            arbiter: None,
+            currency: Currency::Ccd,
+            custodian: None,
+            amount: TokenAmountU64(1),
+            min_increment: None,
+            anti_snipe: None,
+            dutch_auction: None,
+            keeper: None,
+            listing_nonce: 0,
+            bid_history_start: 0,
+            starting_bid: 0,
+        }
+    }
+
+    /// Price a buyer pays now: the falling price of a Dutch auction, the
+    /// listing price otherwise.
+    fn asking_price(&self, now: u64) -> Amount {
+        match &self.dutch_auction {
+            Some(dutch) => dutch.price_at(now),
+            None => self.price,
+        }
+    }
+
+    /// Address currently holding the listed token.
+    fn holder(&self) -> Address {
+        match self.custodian {
+            Some(custodian) => Address::Contract(custodian),
+            None => Address::Account(self.owner),
+        }
+    }
+
+    /// Checks a bid of `bid` against the increment over the highest bid
+    /// `current`; the first bid only has to beat the starting price.
+    fn ensure_bid_increment(&self, bid: u64, current: u64) -> ContractResult<()> {
+        if let Some(increment) = &self.min_increment {
+            ensure!(
+                !self.has_bidder() || bid >= increment.min_next_bid(current),
+                MarketplaceError::BidIncrementTooSmall
+            );
+        }
+        Ok(())
+    }
+
+    /// Smallest bid `place_bid` accepts on this auction.
+    fn min_next_bid(&self) -> Amount {
+        let above = self.price.micro_ccd.saturating_add(1);
+        match &self.min_increment {
+            Some(increment) if self.has_bidder() => {
+                Amount::from_micro_ccd(cmp::max(above, increment.min_next_bid(self.price.micro_ccd)))
+            }
+            _ => Amount::from_micro_ccd(above),
+        }
+    }
+
+    /// Standing bid, or the starting price while there is none, in micro CCD
+    /// or in units of the payment token.
+    fn highest_bid(&self) -> u64 {
+        match &self.currency {
+            Currency::Ccd => self.price.micro_ccd,
+            Currency::Cis2(payment) => payment.amount.0,
+        }
+    }
+
+    fn set_highest_bid(&mut self, amount: u64) {
+        match &mut self.currency {
+            Currency::Ccd => self.price = Amount::from_micro_ccd(amount),
+            Currency::Cis2(payment) => payment.amount = TokenAmountU64(amount),
+        }
+    }
+
+    fn has_bidder(&self) -> bool {
+        self.highest_bidder != AccountAddress([0u8; 32])
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expiry != 0 && now > self.expiry
+    }
+
+    fn unlist(&mut self) {
+        self.sale_type = TokenSaleTypeState::Fixed;
+        self.curr_state = TokenListState::UnListed;
+        self.expiry = 0u64;
+        self.highest_bidder = AccountAddress([0u8; 32]);
+        self.price = Amount { micro_ccd: 0u64 };
+        self.drop_id = None;
+        self.reserve = Amount::zero();
+        self.buy_now = None;
+        self.charity = None;
+        self.bid_count = 0;
+        self.referrer = None;
+        self.min_bid_interval = 0;
+        self.participation_deposit = Amount::zero();
+        self.convert_to_fixed = false;
+        self.allow_contract_buyers = false;
+        self.settlement_data = AdditionalData::empty();
+        self.metadata_hash = None;
+        self.bidder_allowlist = None;
+        self.required_tier = 0;
+        self.disallowed_countries = Vec::new();
+        self.candle = None;
+        self.royalty = None;
+        self.bond = None;
+        self.arbiter = None;
+        self.currency = Currency::Ccd;
+        self.custodian = None;
+        self.amount = TokenAmountU64(1);
+        self.min_increment = None;
+        self.anti_snipe = None;
+        self.dutch_auction = None;
+        self.keeper = None;
+        self.starting_bid = 0;
+    }
+
+    fn in_candle_window(&self, now: u64) -> bool {
+        self.candle
+            .as_ref()
+            .is_some_and(|candle| now >= self.expiry.saturating_sub(candle.window))
+    }
+
+    fn reserve_met(&self) -> bool {
+        self.price >= self.reserve
+    }
+
+    fn should_convert_to_fixed(&self, now: u64) -> bool {
+        self.convert_to_fixed
+            && self.curr_state == TokenListState::Listed
+            && self.sale_type == TokenSaleTypeState::Auction
+            && !self.has_bidder()
+            && self.is_expired(now)
+    }
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct PackageOffer {
+    offerer: AccountAddress,
+    amount: Amount,
+    expiry: u64,
+    items: Vec<TokenInfo>,
+    approvals: Vec<Option<AccountAddress>>,
+    data: AdditionalData,
+    /// Tokens of the offerer held in custody and handed to the counterparty
+    /// on acceptance. An offer with tokens attached has a single counterparty.
+    give: Vec<TokenInfo>,
+}
+
+#[derive(Serialize, SchemaType, Clone, Default)]
+struct AccountReport {
+    items_sold: u64,
+    items_bought: u64,
+    gross_proceeds: Amount,
+    fees_paid: Amount,
+    royalties_received: Amount,
+}
+
+/// Track record of a seller. Disputes are counted once a dispute is
+/// resolved against the seller.
+#[derive(Serialize, SchemaType, Clone, Default)]
+struct SellerReputation {
+    completed_sales: u64,
+    cancelled_with_bids: u64,
+    disputes_lost: u64,
+}
+
+/// Part of a cancellation penalty the seller could not cover from their
+/// deposit, owed to the outbid bidder from the seller's next proceeds.
+#[derive(Serialize, SchemaType, Clone)]
+struct PenaltyDebt {
+    bidder: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct Escrow {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    arbiter: AccountAddress,
+    amount: Amount,
+    claimable_at: u64,
+}
+
+impl Escrow {
+    fn event(&self, escrow_id: u64) -> EscrowEvent {
+        EscrowEvent {
+            escrow_id,
+            seller: self.seller,
+            buyer: self.buyer,
+            arbiter: self.arbiter,
+            amount: self.amount,
+        }
+    }
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct Drop {
+    creator: AccountAddress,
+    max_per_account: u32,
+    allowlist_root: Option<[u8; 32]>,
+    tranches: Vec<DropTranche>,
+}
+
+impl Drop {
+    /// Index of the tranche open at `now`, or `None` for a drop without
+    /// tranches.
+    fn active_tranche(&self, now: u64) -> ContractResult<Option<usize>> {
+        if self.tranches.is_empty() {
+            return Ok(None);
+        }
+        self.tranches
+            .iter()
+            .position(|tranche| tranche.start <= now && now < tranche.end)
+            .map(Some)
+            .ok_or(MarketplaceError::DropNotActive)
+    }
+}
+
+/// A sale phase of a drop. While a tranche is open its price replaces the
+/// listing price and its allowlist replaces the drop-wide one.
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+struct DropTranche {
+    start: u64,
+    end: u64,
+    price: Amount,
+    max_per_account: u32,
+    allowlist_root: Option<[u8; 32]>,
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct MysteryBox {
+    creator: AccountAddress,
+    price: Amount,
+    drop_id: Option<u64>,
+    pool: Vec<TokenInfo>,
+    commitment: randomness::Commitment,
+    entropy: [u8; 32],
+    buyers: Vec<AccountAddress>,
+    opened: Vec<bool>,
+    revealed: bool,
+}
+
+/// A mystery box sale, paid out to the creator once the item it got is
+/// known. `commitment` is the hash of the buyer's secret until revealed.
+#[derive(Serialize, SchemaType, Clone)]
+struct BoxPurchase {
+    price: Amount,
+    commitment: Option<[u8; 32]>,
+}
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+enum PriceCurve {
+    Linear {
+        base: Amount,
+        slope: Amount,
+    },
+    Step {
+        base: Amount,
+        step_size: u32,
+        increment: Amount,
+    },
+}
+
+impl PriceCurve {
+    fn price_at(&self, units_sold: u32) -> Amount {
+        let (base, increment, steps) = match self {
+            PriceCurve::Linear { base, slope } => (base, slope, u64::from(units_sold)),
+            PriceCurve::Step {
+                base,
+                step_size,
+                increment,
+            } => (base, increment, u64::from(units_sold / step_size)),
+        };
+        Amount::from_micro_ccd(
+            base.micro_ccd
+                .saturating_add(increment.micro_ccd.saturating_mul(steps)),
+        )
+    }
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct CurveSale {
+    creator: AccountAddress,
+    nft_contract_address: ContractAddress,
+    first_token_id: u32,
+    supply: u32,
+    sold: u32,
+    curve: PriceCurve,
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct BuyOrder {
+    buyer: AccountAddress,
+    collection: ContractAddress,
+    price: Amount,
+    data: AdditionalData,
+}
+
+#[derive(Serial, SchemaType)]
+struct AuctionRescheduledEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    old_expiry: u64,
+    new_expiry: u64,
+}
+
+#[derive(Serial, SchemaType)]
+struct ListingTransferEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    from: AccountAddress,
+    to: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct ReserveUpdatedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    old_reserve: Amount,
+    new_reserve: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct BuyNowSettledEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    price: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct DisplayHashEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    display_hash: [u8; 32],
+}
+
+#[derive(Serial, SchemaType)]
+struct ComplianceWithheldEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    account: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct SellerVerificationEvent {
+    seller: AccountAddress,
+    verified: bool,
+    moderator: AccountAddress,
+}
+
+#[derive(Serialize, SchemaType, Clone, Copy)]
+enum BlocklistEntry {
+    Collection(ContractAddress),
+    Account(AccountAddress),
+}
+
+#[derive(Serial, SchemaType)]
+struct BlocklistEvent {
+    entry: BlocklistEntry,
+    blocked: bool,
+    moderator: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct CancellationPenaltyEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    bidder: AccountAddress,
+    paid: Amount,
+    deferred: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct BondForfeitedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    bidder: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct EscrowEvent {
+    escrow_id: u64,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    arbiter: AccountAddress,
+    amount: Amount,
+}
+
+/// Token states are logged as hashes of their serialized `Option<TokenState>`
+/// to stay within the event size limit.
+#[derive(Serial, SchemaType)]
+enum StateRepairedEvent {
+    Token {
+        nft_contract_address: ContractAddress,
+        token_id: ContractTokenId,
+        before: [u8; 32],
+        after: [u8; 32],
+    },
+    Refund {
+        account: AccountAddress,
+        before: Amount,
+        after: Amount,
+    },
+}
+
+#[derive(Serial, SchemaType)]
+enum InvalidationReason {
+    NotHeld,
+    NotApproved,
+}
+
+#[derive(Serial, SchemaType)]
+struct ListingInvalidatedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    owner: AccountAddress,
+    reason: InvalidationReason,
+}
+
+#[derive(Serial, SchemaType)]
+struct RoyaltyPaidEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    account: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct DonationPaidEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    charity: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct BidHeartbeatEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    highest_bidder: AccountAddress,
+    /// In micro CCD, or in units of the payment token of a token auction.
+    highest_bid: u64,
+    bid_count: u32,
+    time_remaining: u64,
+}
+
+#[derive(Serial, SchemaType)]
+struct PackageOfferEvent {
+    offer_id: u64,
+    offerer: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct ReceiptMintFailedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    buyer: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct PackageApprovalEvent {
+    offer_id: u64,
+    approver: AccountAddress,
+    items: u32,
+}
+
+#[derive(Serial, SchemaType)]
+struct MysteryBoxRevealedEvent {
+    box_id: u64,
+    seed: Option<[u8; 32]>,
+    boxes_sold: u32,
+}
+
+#[derive(Serial, SchemaType)]
+struct MysteryBoxPurchasedEvent {
+    box_id: u64,
+    index: u32,
+    buyer: AccountAddress,
+    price: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct MysteryBoxOpenedEvent {
+    box_id: u64,
+    index: u32,
+    recipient: AccountAddress,
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
 }
 
-impl<S: HasStateApi> State<S> {
-    fn new(state_builder: &mut StateBuilder<S>) -> Self {
-        State {
-            tokens: state_builder.new_map(),
+#[derive(Serial, SchemaType)]
+struct BuyOrderEvent {
+    order_id: u64,
+    buyer: AccountAddress,
+    collection: ContractAddress,
+    price: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct AuctionConvertedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    price: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct ListedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    sale_type: TokenSaleTypeState,
+    price: Amount,
+    amount: ContractTokenAmount,
+    expiry: u64,
+}
+
+/// A completed sale; logged as `Purchased` for every sale and additionally
+/// as `Finalized` when it settles an auction.
+#[derive(Serial, SchemaType)]
+struct SaleEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    price: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct BidPlacedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    bidder: AccountAddress,
+    price: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct TokenBidEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    bidder: AccountAddress,
+    payment: PaymentToken,
+}
+
+/// A purchase of part of a quantity listing, which stays open for the
+/// `remaining` units.
+#[derive(Serial, SchemaType)]
+struct PartialFillEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    amount: ContractTokenAmount,
+    remaining: ContractTokenAmount,
+}
+
+#[derive(Serial, SchemaType)]
+struct CancelledEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct BidWithdrawnEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    bidder: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct DropCreatedEvent {
+    drop_id: u64,
+    creator: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct CandleAuctionClosedEvent {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    end: u64,
+    seed_revealed: bool,
+}
+
+#[derive(Serial, SchemaType)]
+struct LoyaltyRedeemedEvent {
+    account: AccountAddress,
+    points: u64,
+}
+
+#[derive(Serial, SchemaType)]
+struct ReferralCodeEvent {
+    code: String,
+    referrer: AccountAddress,
+}
+
+/// Where the CCD of a `Withdrawn` payout came from.
+#[derive(Serial, SchemaType)]
+enum WithdrawalSource {
+    Deposit,
+    Refund,
+    ReferralEarnings,
+    Fees,
+}
+
+#[derive(Serial, SchemaType)]
+struct WithdrawalEvent {
+    account: AccountAddress,
+    amount: Amount,
+    source: WithdrawalSource,
+}
+
+#[derive(Serial, SchemaType)]
+struct PausedEvent {
+    signer: AccountAddress,
+}
+
+#[derive(Serial, SchemaType)]
+struct DepositEvent {
+    account: AccountAddress,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct BidOperatorEvent {
+    principal: AccountAddress,
+    operator: AccountAddress,
+    added: bool,
+}
+
+#[derive(Serial, SchemaType)]
+struct IdentityTierEvent {
+    account: AccountAddress,
+    tier: u8,
+}
+
+#[derive(Serial, SchemaType)]
+struct ResidencyEvent {
+    account: AccountAddress,
+    country: [u8; 2],
+}
+
+#[derive(Serial, SchemaType)]
+struct ListingsMigratedEvent {
+    moved: u32,
+}
+
+#[derive(Serial, SchemaType)]
+struct SaleTemplateEvent {
+    collection: ContractAddress,
+    template: Option<SaleTemplate>,
+}
+
+#[derive(Serial, SchemaType)]
+struct MysteryBoxCreatedEvent {
+    box_id: u64,
+    creator: AccountAddress,
+    price: Amount,
+    items: u32,
+}
+
+#[derive(Serial, SchemaType)]
+struct BoxSecretRevealedEvent {
+    box_id: u64,
+    index: u32,
+    shuffled: bool,
+}
+
+#[derive(Serial, SchemaType)]
+struct CurveSaleCreatedEvent {
+    sale_id: u64,
+    creator: AccountAddress,
+    nft_contract_address: ContractAddress,
+    supply: u32,
+}
+
+#[derive(Serial, SchemaType)]
+struct TokenCreditsClaimedEvent {
+    account: AccountAddress,
+    contract: ContractAddress,
+    token_id: TokenIdVec,
+    amount: TokenAmountU64,
+}
+
+#[derive(Serial, SchemaType)]
+struct FeePoolFundedEvent {
+    from: Address,
+    amount: Amount,
+}
+
+#[derive(Serial, SchemaType)]
+struct ProposalEvent {
+    proposal_id: u64,
+    signer: AccountAddress,
+    executed: bool,
+}
+
+#[derive(Serial, SchemaType)]
+enum MarketplaceEvent {
+    Listed(ListedEvent),
+    Purchased(SaleEvent),
+    BidPlaced(BidPlacedEvent),
+    Cancelled(CancelledEvent),
+    Finalized(SaleEvent),
+    PurchasedWithToken(TokenSettlementReceipt),
+    TokenBidPlaced(TokenBidEvent),
+    AuctionRescheduled(AuctionRescheduledEvent),
+    ListingTransferOffered(ListingTransferEvent),
+    ListingTransferred(ListingTransferEvent),
+    ReserveUpdated(ReserveUpdatedEvent),
+    BuyNowSettled(BuyNowSettledEvent),
+    ComplianceWithheld(ComplianceWithheldEvent),
+    DonationPaid(DonationPaidEvent),
+    BidHeartbeat(BidHeartbeatEvent),
+    PackageOfferMade(PackageOfferEvent),
+    PackageOfferAccepted(PackageOfferEvent),
+    PackageOfferCancelled(PackageOfferEvent),
+    MysteryBoxRevealed(MysteryBoxRevealedEvent),
+    MysteryBoxOpened(MysteryBoxOpenedEvent),
+    BuyOrderPlaced(BuyOrderEvent),
+    BuyOrderFilled(BuyOrderEvent),
+    BuyOrderCancelled(BuyOrderEvent),
+    AuctionConverted(AuctionConvertedEvent),
+    DisplayHashRecorded(DisplayHashEvent),
+    RoyaltyPaid(RoyaltyPaidEvent),
+    SellerVerificationChanged(SellerVerificationEvent),
+    BlocklistChanged(BlocklistEvent),
+    CancellationPenalty(CancellationPenaltyEvent),
+    BondForfeited(BondForfeitedEvent),
+    EscrowOpened(EscrowEvent),
+    EscrowReleased(EscrowEvent),
+    EscrowRefunded(EscrowEvent),
+    ListingInvalidated(ListingInvalidatedEvent),
+    StateRepaired(StateRepairedEvent),
+    PartialFill(PartialFillEvent),
+    Extended(AuctionRescheduledEvent),
+    ListingTransferCancelled(ListingTransferEvent),
+    MysteryBoxPurchased(MysteryBoxPurchasedEvent),
+    BidWithdrawn(BidWithdrawnEvent),
+    PackageApprovalRevoked(PackageApprovalEvent),
+    ReceiptMintFailed(ReceiptMintFailedEvent),
+    DropCreated(DropCreatedEvent),
+    CandleAuctionClosed(CandleAuctionClosedEvent),
+    RatesPosted(ReferenceRates),
+    LoyaltyRedeemed(LoyaltyRedeemedEvent),
+    ReferralCodeRegistered(ReferralCodeEvent),
+    Withdrawn(WithdrawalEvent),
+    Paused(PausedEvent),
+    Deposited(DepositEvent),
+    BidOperatorChanged(BidOperatorEvent),
+    IdentityTierSet(IdentityTierEvent),
+    ResidencyRecorded(ResidencyEvent),
+    ListingsMigrated(ListingsMigratedEvent),
+    SaleTemplateChanged(SaleTemplateEvent),
+    MysteryBoxCreated(MysteryBoxCreatedEvent),
+    BoxSecretRevealed(BoxSecretRevealedEvent),
+    CurveSaleCreated(CurveSaleCreatedEvent),
+    TokenCreditsClaimed(TokenCreditsClaimedEvent),
+    FeePoolFunded(FeePoolFundedEvent),
+    ProposalMade(ProposalEvent),
+    ProposalApproved(ProposalEvent),
+}
+
+/// Every logged event carries the next value of a contract-wide counter, so
+/// indexers can detect gaps and resume from the last `seq` they processed.
+#[derive(Serial, SchemaType)]
+struct LoggedEvent {
+    seq: u64,
+    event: MarketplaceEvent,
+}
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+struct ComplianceConfig {
+    account: AccountAddress,
+    rate_bps: u16,
+    threshold: Amount,
+}
+
+#[derive(Serialize, SchemaType, Clone, Copy)]
+struct Rate {
+    numerator: u64,
+    denominator: u64,
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct ReferenceRates {
+    ccd_eur: Rate,
+    pixp_ccd: Rate,
+    updated_at: u64,
+}
+
+#[derive(Serialize, SchemaType)]
+struct SettlementReceipt {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    price: Amount,
+    fees: Amount,
+    royalty: Amount,
+    donation: Amount,
+    seller_proceeds: Amount,
+}
+
+impl SettlementReceipt {
+    fn sale_event(&self) -> SaleEvent {
+        SaleEvent {
+            nft_contract_address: self.nft_contract_address,
+            token_id: self.token_id,
+            seller: self.seller,
+            buyer: self.buyer,
+            price: self.price,
+        }
+    }
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct TokenSettlementReceipt {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    payment: PaymentToken,
+    fees: TokenAmountU64,
+    royalty: TokenAmountU64,
+    seller_proceeds: TokenAmountU64,
+}
+
+#[derive(Serialize, SchemaType)]
+struct ProceedsBreakdown {
+    platform_fee: Amount,
+    referral_fee: Amount,
+    compliance_withholding: Amount,
+    royalty: Amount,
+    donation: Amount,
+    keeper_reward: Amount,
+    seller_proceeds: Amount,
+}
+
+#[derive(Serialize, SchemaType)]
+struct PurchaseQuote {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    sale_type: TokenSaleTypeState,
+    display_hash: Option<[u8; 32]>,
+    seller_verified: bool,
+    total: Amount,
+    price: Amount,
+    participation_deposit: Amount,
+    breakdown: ProceedsBreakdown,
+}
+
+#[derive(Serialize, SchemaType)]
+struct ListingResult {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    owner: AccountAddress,
+    sale_type: TokenSaleTypeState,
+    price: Amount,
+    expiry: u64,
+    relisted: bool,
+    display_hash: Option<[u8; 32]>,
+    seller_verified: bool,
+}
+
+#[derive(Serialize, SchemaType)]
+struct BidResult {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    bidder: AccountAddress,
+    amount: Amount,
+    bid_count: u32,
+    expiry: u64,
+}
+
+#[derive(Serialize, SchemaType)]
+enum TradeResult {
+    Purchased(SettlementReceipt),
+    PurchasedWithToken(TokenSettlementReceipt),
+    BidPlaced(BidResult),
+    BuyNowSettled(SettlementReceipt),
+}
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+struct ReceiptConfig {
+    contract: ContractAddress,
+    entrypoint: OwnedEntrypointName,
+}
+
+#[derive(Serial, SchemaType)]
+struct MintReceiptParams {
+    owner: AccountAddress,
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seller: AccountAddress,
+    /// Zero for sales paid in a CIS-2 token.
+    price: Amount,
+    payment: Option<PaymentToken>,
+}
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+struct FeeTier {
+    min_volume: Amount,
+    discount_bps: u16,
+}
+
+#[derive(Serialize, SchemaType, Clone, Default)]
+struct SellerVolume {
+    window_start: u64,
+    volume: Amount,
+}
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+enum AdminAction {
+    UpdateSigners {
+        signers: Vec<AccountAddress>,
+        threshold: u8,
+    },
+    SetPaused(bool),
+    SetSweepBounty(Amount),
+    SetMaxAuctionDuration(u64),
+    SetRefundBounty(Amount),
+    SetRelistCooldown {
+        collection: ContractAddress,
+        cooldown: u64,
+    },
+    SetCompliance(Option<ComplianceConfig>),
+    SetMaxRateAge(u64),
+    SetReceiptContract(Option<ReceiptConfig>),
+    SetLoyaltyRate(u64),
+    SetLoyaltyRedeemer(Option<ContractAddress>),
+    SetPlatformFee(u16),
+    SetFeeAccount(Option<AccountAddress>),
+    SetFeeTiers {
+        window: u64,
+        tiers: Vec<FeeTier>,
+    },
+    SetReferralShare(u16),
+    InvalidateCollection(ContractAddress),
+    SetVerifiedSellersOnly(bool),
+    SetCollectionWhitelistOnly(bool),
+    WhitelistCollection(ContractAddress),
+    UnwhitelistCollection(ContractAddress),
+    SetCancelPenalty(u16),
+    SetSellerBond {
+        threshold: Amount,
+        bond: Amount,
+    },
+    AddModerator(AccountAddress),
+    RemoveModerator(AccountAddress),
+    AddIdentityVerifier(AccountAddress),
+    SetCollectionStudio {
+        collection: ContractAddress,
+        studio: Option<AccountAddress>,
+    },
+    RemoveIdentityVerifier(AccountAddress),
+    RepairTokens(Vec<TokenRepair>),
+    RepairRefunds(Vec<RefundRepair>),
+    Upgrade(UpgradeParams),
+    SetKeeperReward(u16),
+    SetMinRevealDeposit(Amount),
+    SetRefundThresholds {
+        min_age: u64,
+        min_amount: Amount,
+    },
+}
+
+impl AdminAction {
+    fn is_upgrade(&self) -> bool {
+        matches!(self, AdminAction::Upgrade(_))
+    }
+}
+
+/// Replaces the contract module in place, keeping the address, state and
+/// balance. `migrate` names an entrypoint of the new module, and its
+/// parameter, to invoke right after the upgrade; it is required when the new
+/// module expects another state version. Once approved, the proposal is
+/// executed through the `upgrade` entrypoint.
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+struct UpgradeParams {
+    module: ModuleReference,
+    migrate: Option<(OwnedEntrypointName, OwnedParameter)>,
+}
+
+/// Compare-and-swap patches of single state entries. `before` must match the
+/// stored value exactly (`None` or zero for an absent entry).
+#[derive(Serialize, SchemaType, Clone)]
+struct TokenRepair {
+    token: TokenInfo,
+    before: Option<TokenState>,
+    after: Option<TokenState>,
+}
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+struct RefundRepair {
+    account: AccountAddress,
+    before: Amount,
+    after: Amount,
+}
+
+// `TokenState` holds CIS-2 `AdditionalData`, which has no `PartialEq`, so
+// repairs compare by their serialization.
+impl PartialEq for TokenRepair {
+    fn eq(&self, other: &Self) -> bool {
+        to_bytes(self) == to_bytes(other)
+    }
+}
+
+impl Eq for TokenRepair {}
+
+impl fmt::Debug for TokenRepair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenRepair").field("token", &self.token).finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct Proposal {
+    action: AdminAction,
+    approvals: Vec<AccountAddress>,
+}
+
+#[derive(Serialize, SchemaType, Clone)]
+struct PendingListingTransfer {
+    from: AccountAddress,
+    to: AccountAddress,
+    listing_nonce: u64,
+}
+
+#[derive(Serial, DeserialWithState)]
+#[concordium(state_parameter = "S")]
+struct State<S>
+{
+    /// Serialized first so that `migrate` can read it from any layout.
+    state_version: u16,
+    tokens: Listings<S>,
+    signers: Vec<AccountAddress>,
+    threshold: u8,
+    proposals: StateMap<u64, Proposal, S>,
+    next_proposal_id: u64,
+    paused: bool,
+    fee_pool: Amount,
+    sweep_bounty: Amount,
+    max_auction_duration: u64,
+    refunds: StateMap<AccountAddress, Amount, S>,
+    refund_bounty: Amount,
+    listing_transfers: StateMap<TokenInfo, PendingListingTransfer, S>,
+    deposits: StateMap<AccountAddress, Amount, S>,
+    bid_operators: StateSet<(AccountAddress, AccountAddress), S>,
+    drops: StateMap<u64, Drop, S>,
+    next_drop_id: u64,
+    drop_purchases: StateMap<(u64, AccountAddress), u32, S>,
+    relist_cooldowns: StateMap<ContractAddress, u64, S>,
+    reports: StateMap<AccountAddress, AccountReport, S>,
+    compliance: Option<ComplianceConfig>,
+    rates: Option<ReferenceRates>,
+    max_rate_age: u64,
+    receipt: Option<ReceiptConfig>,
+    loyalty_points: StateMap<AccountAddress, u64, S>,
+    loyalty_rate: u64,
+    loyalty_redeemer: Option<ContractAddress>,
+    platform_fee_bps: u16,
+    fee_tiers: Vec<FeeTier>,
+    volume_window: u64,
+    seller_volumes: StateMap<AccountAddress, SellerVolume, S>,
+    referral_codes: StateMap<String, AccountAddress, S>,
+    referral_share_bps: u16,
+    referral_earnings: StateMap<AccountAddress, Amount, S>,
+    package_offers: StateMap<u64, PackageOffer, S>,
+    next_offer_id: u64,
+    last_bids: StateMap<(TokenInfo, AccountAddress), u64, S>,
+    auction_participants: StateMap<TokenInfo, Vec<AccountAddress>, S>,
+    mystery_boxes: StateMap<u64, MysteryBox, S>,
+    next_mystery_box_id: u64,
+    curve_sales: StateMap<u64, CurveSale, S>,
+    next_curve_sale_id: u64,
+    buy_orders: StateMap<u64, BuyOrder, S>,
+    order_books: StateMap<ContractAddress, Vec<u64>, S>,
+    next_buy_order_id: u64,
+    validated_collections: StateSet<ContractAddress, S>,
+    tranche_purchases: StateMap<(u64, u32, AccountAddress), u32, S>,
+    identity_verifiers: StateSet<AccountAddress, S>,
+    identity_tiers: StateMap<AccountAddress, u8, S>,
+    residencies: StateMap<AccountAddress, [u8; 2], S>,
+    candle_bids: StateMap<TokenInfo, Vec<CandleBid>, S>,
+    collection_studios: StateMap<ContractAddress, AccountAddress, S>,
+    sale_templates: StateMap<ContractAddress, SaleTemplate, S>,
+    moderators: StateSet<AccountAddress, S>,
+    verified_sellers: StateSet<AccountAddress, S>,
+    verified_sellers_only: bool,
+    reputations: StateMap<AccountAddress, SellerReputation, S>,
+    cancel_penalty_bps: u16,
+    penalty_debts: StateMap<AccountAddress, Vec<PenaltyDebt>, S>,
+    bond_threshold: Amount,
+    seller_bond: Amount,
+    escrows: StateMap<u64, Escrow, S>,
+    next_escrow_id: u64,
+    next_event_seq: u64,
+    listing_details: StateMap<TokenInfo, ListingDetails, S>,
+    fee_account: Option<AccountAddress>,
+    collection_whitelist: StateSet<ContractAddress, S>,
+    collection_whitelist_only: bool,
+    blocked_collections: StateSet<ContractAddress, S>,
+    blocked_accounts: StateSet<AccountAddress, S>,
+    token_credits: StateMap<(AccountAddress, ContractAddress, TokenIdVec), TokenAmountU64, S>,
+    keeper_reward_bps: u16,
+    bid_history: StateMap<(TokenInfo, u32), BidRecord, S>,
+    bid_history_len: StateMap<TokenInfo, u32, S>,
+    next_listing_nonce: u64,
+    min_reveal_deposit: Amount,
+    box_purchases: StateMap<(u64, u32), BoxPurchase, S>,
+    box_seeded_at: StateMap<u64, u64, S>,
+    refund_credited_at: StateMap<AccountAddress, u64, S>,
+    refund_min_age: u64,
+    refund_min_amount: Amount,
+}
+
+impl<S: HasStateApi> State<S> {
+    fn new(
+        state_builder: &mut StateBuilder<S>,
+        signers: Vec<AccountAddress>,
+        threshold: u8,
+    ) -> Self {
+        State {
+            state_version: STATE_VERSION,
+            tokens: Listings::new(state_builder),
+            signers,
+            threshold,
+            proposals: state_builder.new_map(),
+            next_proposal_id: 0,
+            paused: false,
+            fee_pool: Amount::zero(),
+            sweep_bounty: Amount::zero(),
+            max_auction_duration: 0,
+            refunds: state_builder.new_map(),
+            refund_bounty: Amount::zero(),
+            listing_transfers: state_builder.new_map(),
+            deposits: state_builder.new_map(),
+            bid_operators: state_builder.new_set(),
+            drops: state_builder.new_map(),
+            next_drop_id: 0,
+            drop_purchases: state_builder.new_map(),
+            relist_cooldowns: state_builder.new_map(),
+            reports: state_builder.new_map(),
+            compliance: None,
+            rates: None,
+            max_rate_age: 0,
+            receipt: None,
+            loyalty_points: state_builder.new_map(),
+            loyalty_rate: 0,
+            loyalty_redeemer: None,
+            platform_fee_bps: 0,
+            fee_tiers: Vec::new(),
+            volume_window: 0,
+            seller_volumes: state_builder.new_map(),
+            referral_codes: state_builder.new_map(),
+            referral_share_bps: 0,
+            referral_earnings: state_builder.new_map(),
+            package_offers: state_builder.new_map(),
+            next_offer_id: 0,
+            last_bids: state_builder.new_map(),
+            auction_participants: state_builder.new_map(),
+            mystery_boxes: state_builder.new_map(),
+            next_mystery_box_id: 0,
+            curve_sales: state_builder.new_map(),
+            next_curve_sale_id: 0,
+            buy_orders: state_builder.new_map(),
+            order_books: state_builder.new_map(),
+            next_buy_order_id: 0,
+            validated_collections: state_builder.new_set(),
+            tranche_purchases: state_builder.new_map(),
+            identity_verifiers: state_builder.new_set(),
+            identity_tiers: state_builder.new_map(),
+            residencies: state_builder.new_map(),
+            candle_bids: state_builder.new_map(),
+            collection_studios: state_builder.new_map(),
+            sale_templates: state_builder.new_map(),
+            moderators: state_builder.new_set(),
+            verified_sellers: state_builder.new_set(),
+            verified_sellers_only: false,
+            reputations: state_builder.new_map(),
+            cancel_penalty_bps: 0,
+            penalty_debts: state_builder.new_map(),
+            bond_threshold: Amount::zero(),
+            seller_bond: Amount::zero(),
+            escrows: state_builder.new_map(),
+            next_escrow_id: 0,
+            next_event_seq: 0,
+            listing_details: state_builder.new_map(),
+            fee_account: None,
+            collection_whitelist: state_builder.new_set(),
+            collection_whitelist_only: false,
+            blocked_collections: state_builder.new_set(),
+            blocked_accounts: state_builder.new_set(),
+            token_credits: state_builder.new_map(),
+            keeper_reward_bps: 0,
+            bid_history: state_builder.new_map(),
+            bid_history_len: state_builder.new_map(),
+            next_listing_nonce: 1,
+            min_reveal_deposit: Amount::zero(),
+            box_purchases: state_builder.new_map(),
+            box_seeded_at: state_builder.new_map(),
+            refund_credited_at: state_builder.new_map(),
+            refund_min_age: DEFAULT_REFUND_MIN_AGE,
+            refund_min_amount: Amount::zero(),
+        }
+    }
+
+    fn display_hash(&self, info: &TokenInfo) -> Option<[u8; 32]> {
+        self.listing_details.get(info).and_then(|details| details.display_hash)
+    }
+
+    fn log_event(
+        &mut self,
+        logger: &mut impl HasLogger,
+        event: MarketplaceEvent,
+    ) -> ContractResult<()> {
+        let seq = self.next_event_seq;
+        self.next_event_seq += 1;
+        logger
+            .log(&LoggedEvent { seq, event })
+            .map_err(|_| MarketplaceError::LogError)
+    }
+
+    fn repair_token(
+        &mut self,
+        crypto_primitives: &impl HasCryptoPrimitives,
+        repair: TokenRepair,
+    ) -> ContractResult<StateRepairedEvent> {
+        let TokenRepair { token, before, after } = repair;
+        let current = self.tokens.get(&token).map(|token_state| token_state.to_owned());
+        ensure!(to_bytes(&current) == to_bytes(&before), MarketplaceError::RepairMismatch);
+        match &after {
+            Some(token_state) => {
+                let _ = self.tokens.insert(token.clone(), token_state.clone());
+            }
+            None => self.tokens.remove(&token),
+        }
+
+        ContractResult::Ok(StateRepairedEvent::Token {
+            nft_contract_address: token.address,
+            token_id: token.id,
+            before: crypto_primitives.hash_sha2_256(&to_bytes(&before)).0,
+            after: crypto_primitives.hash_sha2_256(&to_bytes(&after)).0,
+        })
+    }
+
+    fn repair_refund(&mut self, repair: RefundRepair) -> ContractResult<StateRepairedEvent> {
+        let RefundRepair { account, before, after } = repair;
+        let current = self.refunds.get(&account).map_or(Amount::zero(), |amount| *amount);
+        ensure!(current == before, MarketplaceError::RepairMismatch);
+        if after == Amount::zero() {
+            self.refunds.remove(&account);
+            self.refund_credited_at.remove(&account);
+        } else {
+            let _ = self.refunds.insert(account, after);
+        }
+
+        ContractResult::Ok(StateRepairedEvent::Refund { account, before, after })
+    }
+
+    /// Adds to the refunds owed to `account`, which count as credited at the
+    /// time of the oldest one still unclaimed.
+    fn credit_refund(&mut self, account: AccountAddress, amount: Amount, now: u64) {
+        *self.refunds.entry(account).or_insert(Amount::zero()) += amount;
+        let _ = self.refund_credited_at.entry(account).or_insert(now);
+    }
+
+    fn credit_token(
+        &mut self,
+        account: AccountAddress,
+        payment: &PaymentToken,
+        amount: TokenAmountU64,
+    ) {
+        let key = (account, payment.contract, payment.token_id.clone());
+        self.token_credits.entry(key).or_insert(TokenAmountU64(0)).0 += amount.0;
+    }
+
+    /// Credits the highest bid back to its bidder in the listing's currency.
+    fn refund_highest_bid(&mut self, token_state: &TokenState, now: u64) {
+        match &token_state.currency {
+            Currency::Ccd => self.credit_refund(token_state.highest_bidder, token_state.price, now),
+            Currency::Cis2(payment) => {
+                self.credit_token(token_state.highest_bidder, payment, payment.amount)
+            }
+        }
+    }
+
+    /// Number of bids ever recorded on `info`, where the history of its next
+    /// auction starts.
+    fn bid_history_end(&self, info: &TokenInfo) -> u32 {
+        self.bid_history_len.get(info).map_or(0, |len| *len)
+    }
+
+    /// Appends a bid to the history of the current auction on `info`.
+    fn record_bid(&mut self, info: &TokenInfo, bidder: AccountAddress, amount: u64, now: u64) {
+        let index = {
+            let mut len = self.bid_history_len.entry(info.clone()).or_insert(0);
+            *len += 1;
+            *len - 1
+        };
+        let _ = self.bid_history.insert(
+            (info.clone(), index),
+            BidRecord {
+                bidder,
+                amount,
+                placed_at: now,
+            },
+        );
+    }
+
+    /// Checks and records a bid of `amount`, in micro CCD or in units of the
+    /// listing's payment token, and credits the outbid bid back.
+    fn place_bid(
+        &mut self,
+        info: &TokenInfo,
+        token_state: &mut TokenState,
+        bidder: AccountAddress,
+        amount: u64,
+        now: u64,
+    ) -> ContractResult<()> {
+        ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+        ensure!(now <= token_state.expiry, MarketplaceError::ExpiredAlready);
+        ensure!(bidder != token_state.owner, MarketplaceError::CanNotBidYourSelf);
+        let current = token_state.highest_bid();
+        ensure!(amount > current, MarketplaceError::NotEnoughBalance);
+        token_state.ensure_bid_increment(amount, current)?;
+
+        if token_state.min_bid_interval > 0 {
+            let mut last_bid = self.last_bids.entry((info.clone(), bidder)).or_insert(0);
+            ensure!(
+                *last_bid == 0 || now >= last_bid.saturating_add(token_state.min_bid_interval),
+                MarketplaceError::BidTooSoon
+            );
+            *last_bid = now;
+        }
+
+        if token_state.in_candle_window(now) {
+            // Outbid bids stay escrowed until the candle is resolved, as any
+            // of them may turn out to be the winning bid.
+            let mut bids = self.candle_bids.entry(info.clone()).or_default();
+            if bids.is_empty() && token_state.has_bidder() {
+                bids.push(CandleBid {
+                    bidder: token_state.highest_bidder,
+                    amount: token_state.price,
+                    placed_at: 0,
+                });
+            }
+            bids.push(CandleBid {
+                bidder,
+                amount: Amount::from_micro_ccd(amount),
+                placed_at: now,
+            });
+        } else if token_state.has_bidder() {
+            self.refund_highest_bid(token_state, now);
+        }
+
+        token_state.highest_bidder = bidder;
+        token_state.set_highest_bid(amount);
+        token_state.bid_count += 1;
+        token_state.referrer = None;
+        self.record_bid(info, bidder, amount, now);
+        Ok(())
+    }
+
+    /// Checks the buyer against the drop's caps and allowlist and counts the
+    /// purchase. Returns the tranche price when the drop is scheduled in
+    /// tranches.
+    fn record_drop_purchase(
+        &mut self,
+        crypto_primitives: &impl HasCryptoPrimitives,
+        drop_id: u64,
+        buyer: AccountAddress,
+        allowlist_proof: &[[u8; 32]],
+        now: u64,
+    ) -> ContractResult<Option<Amount>> {
+        let drop = self.drops.get(&drop_id).ok_or(MarketplaceError::DropNotFound)?.to_owned();
+
+        let tranche = drop
+            .active_tranche(now)?
+            .map(|index| (index as u32, drop.tranches[index].clone()));
+
+        let allowlist_root = match &tranche {
+            Some((_, tranche)) => tranche.allowlist_root,
+            None => drop.allowlist_root,
+        };
+        if let Some(root) = allowlist_root {
+            ensure!(
+                verify_allowlist_proof(crypto_primitives, &root, &buyer, allowlist_proof),
+                MarketplaceError::NotAllowlisted
+            );
+        }
+
+        if let Some((index, tranche)) = &tranche {
+            let mut purchased = self.tranche_purchases.entry((drop_id, *index, buyer)).or_insert(0);
+            ensure!(*purchased < tranche.max_per_account, MarketplaceError::PurchaseLimitReached);
+            *purchased += 1;
+        }
+
+        let mut purchased = self.drop_purchases.entry((drop_id, buyer)).or_insert(0);
+        ensure!(*purchased < drop.max_per_account, MarketplaceError::PurchaseLimitReached);
+        *purchased += 1;
+        Ok(tranche.map(|(_, tranche)| tranche.price))
+    }
+
+    fn ensure_identity_tier(&self, token_state: &TokenState, account: &AccountAddress) -> ContractResult<()> {
+        if token_state.required_tier == 0 {
+            return Ok(());
+        }
+        let tier = self.identity_tiers.get(account).map_or(0, |tier| *tier);
+        ensure!(tier >= token_state.required_tier, MarketplaceError::IdentityTierTooLow);
+        Ok(())
+    }
+
+    fn ensure_jurisdiction(&self, token_state: &TokenState, account: &AccountAddress) -> ContractResult<()> {
+        if token_state.disallowed_countries.is_empty() {
+            return Ok(());
+        }
+        let country = self.residencies.get(account).ok_or(MarketplaceError::ResidencyNotProven)?;
+        ensure!(
+            !token_state.disallowed_countries.contains(&*country),
+            MarketplaceError::JurisdictionRestricted
+        );
+        Ok(())
+    }
+
+    fn ensure_not_blocked(
+        &self,
+        collection: &ContractAddress,
+        accounts: &[AccountAddress],
+    ) -> ContractResult<()> {
+        ensure!(!self.blocked_collections.contains(collection), MarketplaceError::CollectionBlocked);
+        for account in accounts {
+            ensure!(!self.blocked_accounts.contains(account), MarketplaceError::AccountBlocked);
+        }
+        Ok(())
+    }
+
+    fn record_sale(&mut self, seller: AccountAddress, buyer: AccountAddress, price: Amount) {
+        self.reputations.entry(seller).or_default().completed_sales += 1;
+        {
+            let mut report = self.reports.entry(seller).or_default();
+            report.items_sold += 1;
+            report.gross_proceeds += price;
+        }
+        self.reports
+            .entry(buyer)
+            .or_default()
+            .items_bought += 1;
+    }
+
+    fn seller_volume(&self, seller: &AccountAddress, now: u64) -> Amount {
+        match self.seller_volumes.get(seller) {
+            Some(entry) if now < entry.window_start.saturating_add(self.volume_window) => entry.volume,
+            _ => Amount::zero(),
+        }
+    }
+
+    fn seller_discount_bps(&self, volume: Amount) -> u16 {
+        self.fee_tiers
+            .iter()
+            .filter(|tier| volume >= tier.min_volume)
+            .map(|tier| tier.discount_bps)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn platform_fee(&self, seller: &AccountAddress, price: Amount, now: u64) -> Amount {
+        let fee = bps_of(price, self.platform_fee_bps);
+        let discount_bps = self.seller_discount_bps(self.seller_volume(seller, now));
+        fee - bps_of(fee, discount_bps)
+    }
+
+    fn proceeds_breakdown(
+        &self,
+        listing: &TokenState,
+        royalty: Option<&Royalty>,
+        price: Amount,
+        now: u64,
+    ) -> ProceedsBreakdown {
+        let platform_fee = self.platform_fee(&listing.owner, price, now);
+        let mut remaining = price - platform_fee;
+
+        let referral_fee = match listing.referrer {
+            Some(_) => bps_of(platform_fee, self.referral_share_bps),
+            None => Amount::zero(),
+        };
+
+        // The threshold is in CCD, so sales in a payment token always pay it.
+        let compliance_withholding = match &self.compliance {
+            Some(compliance) if listing.currency != Currency::Ccd || price > compliance.threshold => {
+                cmp::min(bps_of(price, compliance.rate_bps), remaining)
+            }
+            _ => Amount::zero(),
+        };
+        remaining -= compliance_withholding;
+
+        let royalty = match royalty {
+            Some(royalty) => bps_of(remaining, royalty.rate_bps),
+            None => Amount::zero(),
+        };
+        remaining -= royalty;
+
+        let donation = match &listing.charity {
+            Some(charity) => bps_of(remaining, charity.rate_bps),
+            None => Amount::zero(),
+        };
+        remaining -= donation;
+
+        let keeper_reward = match listing.keeper {
+            Some(_) => bps_of(remaining, self.keeper_reward_bps),
+            None => Amount::zero(),
+        };
+        remaining -= keeper_reward;
+
+        ProceedsBreakdown {
+            platform_fee,
+            referral_fee,
+            compliance_withholding,
+            royalty,
+            donation,
+            keeper_reward,
+            seller_proceeds: remaining,
+        }
+    }
+
+    fn record_seller_volume(&mut self, seller: AccountAddress, price: Amount, now: u64) {
+        let window = self.volume_window;
+        let mut entry = self.seller_volumes.entry(seller).or_default();
+        if now >= entry.window_start.saturating_add(window) {
+            entry.window_start = now;
+            entry.volume = Amount::zero();
+        }
+        entry.volume += price;
+    }
+
+    fn resolve_referral(&self, code: &Option<String>) -> ContractResult<Option<AccountAddress>> {
+        match code {
+            Some(code) => {
+                let referrer = self
+                    .referral_codes
+                    .get(code)
+                    .ok_or(MarketplaceError::UnknownReferralCode)?;
+                Ok(Some(*referrer))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Buyers of a mystery box who have not revealed their secret yet.
+    fn pending_box_secrets(&self, box_id: u64, mystery_box: &MysteryBox) -> usize {
+        (0..mystery_box.buyers.len() as u32)
+            .filter(|index| {
+                self.box_purchases
+                    .get(&(box_id, *index))
+                    .is_some_and(|purchase| purchase.commitment.is_some())
+            })
+            .count()
+    }
+
+    fn shuffle_box(
+        &mut self,
+        crypto_primitives: &impl HasCryptoPrimitives,
+        box_id: u64,
+        mystery_box: &mut MysteryBox,
+    ) {
+        randomness::shuffle(crypto_primitives, mystery_box.entropy, &mut mystery_box.pool);
+        mystery_box.revealed = true;
+        self.box_seeded_at.remove(&box_id);
+    }
+
+    fn accrue_loyalty(&mut self, buyer: AccountAddress, spent: Amount) {
+        let points = u128::from(spent.micro_ccd) * u128::from(self.loyalty_rate) / 1_000_000;
+        if points > 0 {
+            let mut balance = self.loyalty_points.entry(buyer).or_insert(0);
+            *balance = balance.saturating_add(points as u64);
+        }
+    }
+
+    fn join_auction(
+        &mut self,
+        info: &TokenInfo,
+        token_state: &TokenState,
+        bidder: AccountAddress,
+    ) -> Amount {
+        if token_state.participation_deposit == Amount::zero() {
+            return Amount::zero();
+        }
+        let mut participants = self.auction_participants.entry(info.clone()).or_default();
+        if participants.contains(&bidder) {
+            Amount::zero()
+        } else {
+            participants.push(bidder);
+            token_state.participation_deposit
+        }
+    }
+
+    fn release_participants(&mut self, info: &TokenInfo, token_state: &TokenState, now: u64) {
+        if let Some(participants) = self.auction_participants.remove_and_get(info) {
+            for participant in participants {
+                self.credit_refund(participant, token_state.participation_deposit, now);
+            }
+        }
+    }
+
+    /// Resolves a candle auction at `end`: the last bid placed by then wins,
+    /// every other escrowed bid is refunded.
+    fn resolve_candle(
+        &mut self,
+        info: &TokenInfo,
+        token_state: &mut TokenState,
+        end: u64,
+        now: u64,
+    ) {
+        let bids = match self.candle_bids.remove_and_get(info) {
+            Some(bids) => bids,
+            None => return,
+        };
+        let winner = bids.iter().rposition(|bid| bid.placed_at <= end);
+        for (index, bid) in bids.iter().enumerate() {
+            if Some(index) != winner {
+                self.credit_refund(bid.bidder, bid.amount, now);
+            }
+        }
+        match winner {
+            Some(index) => {
+                token_state.highest_bidder = bids[index].bidder;
+                token_state.price = bids[index].amount;
+            }
+            None => token_state.highest_bidder = AccountAddress([0u8; 32]),
+        }
+    }
+
+    /// Refunds the escrowed candle bids of a listing closed without resolving
+    /// its candle. The latest bid is the standing bid and is left to the
+    /// caller.
+    fn release_candle_bids(&mut self, info: &TokenInfo, now: u64) {
+        if let Some(mut bids) = self.candle_bids.remove_and_get(info) {
+            bids.pop();
+            for bid in bids {
+                self.credit_refund(bid.bidder, bid.amount, now);
+            }
+        }
+    }
+
+    fn close_listing(&mut self, info: &TokenInfo, token_state: &mut TokenState, now: u64) {
+        self.release_candle_bids(info, now);
+        if let Some(candle) = token_state.candle.take() {
+            self.credit_refund(candle.commitment.committer, candle.commitment.deposit, now);
+        }
+        if let Some(bond) = token_state.bond.take() {
+            self.credit_refund(bond.account, bond.amount, now);
+        }
+        self.release_participants(info, token_state, now);
+        self.listing_details.remove(info);
+        self.listing_transfers.remove(info);
+        token_state.unlist();
+    }
+
+    fn new_listing_nonce(&mut self) -> u64 {
+        let nonce = self.next_listing_nonce;
+        self.next_listing_nonce += 1;
+        nonce
+    }
+
+    /// Cancels a listing at the seller's request. Cancelling an auction with
+    /// bids costs the seller a penalty paid to the highest bidder, taken from
+    /// the seller's deposit and otherwise from their future proceeds.
+    fn withdraw_listing(
+        &mut self,
+        info: &TokenInfo,
+        token_state: &mut TokenState,
+        now: u64,
+    ) -> Option<CancellationPenaltyEvent> {
+        let mut penalty = None;
+        if token_state.has_bidder() {
+            let seller = token_state.owner;
+            let bidder = token_state.highest_bidder;
+            self.reputations.entry(seller).or_default().cancelled_with_bids += 1;
+
+            let amount = bps_of(token_state.price, self.cancel_penalty_bps);
+            if amount > Amount::zero() {
+                let paid = match self.deposits.get_mut(&seller) {
+                    Some(mut deposit) => {
+                        let paid = cmp::min(*deposit, amount);
+                        *deposit -= paid;
+                        paid
+                    }
+                    None => Amount::zero(),
+                };
+                self.credit_refund(bidder, paid, now);
+                let deferred = amount - paid;
+                if deferred > Amount::zero() {
+                    self.penalty_debts.entry(seller).or_default().push(PenaltyDebt {
+                        bidder,
+                        amount: deferred,
+                    });
+                }
+                penalty = Some(CancellationPenaltyEvent {
+                    nft_contract_address: info.address,
+                    token_id: info.id,
+                    seller,
+                    bidder,
+                    paid,
+                    deferred,
+                });
+            }
+        }
+        self.cancel_listing(info, token_state, now);
+        penalty
+    }
+
+    /// Pays outstanding penalty debts of `seller` out of `proceeds` and
+    /// returns what is left for the seller.
+    fn settle_penalty_debts(
+        &mut self,
+        seller: AccountAddress,
+        proceeds: Amount,
+        now: u64,
+    ) -> Amount {
+        let debts = match self.penalty_debts.remove_and_get(&seller) {
+            Some(debts) => debts,
+            None => return proceeds,
+        };
+        let mut remaining = proceeds;
+        let mut outstanding = Vec::new();
+        for mut debt in debts {
+            let paid = cmp::min(remaining, debt.amount);
+            remaining -= paid;
+            debt.amount -= paid;
+            if paid > Amount::zero() {
+                self.credit_refund(debt.bidder, paid, now);
+            }
+            if debt.amount > Amount::zero() {
+                outstanding.push(debt);
+            }
+        }
+        if !outstanding.is_empty() {
+            let _ = self.penalty_debts.insert(seller, outstanding);
+        }
+        remaining
+    }
+
+    /// Bond a seller must post to list an auction of the given value.
+    fn required_bond(&self, sale_type: TokenSaleTypeState, value: Amount) -> Amount {
+        if sale_type == TokenSaleTypeState::Auction
+            && self.seller_bond > Amount::zero()
+            && value >= self.bond_threshold
+        {
+            self.seller_bond
+        } else {
+            Amount::zero()
+        }
+    }
+
+    fn cancel_listing(&mut self, info: &TokenInfo, token_state: &mut TokenState, now: u64) {
+        if token_state.has_bidder() {
+            self.refund_highest_bid(token_state, now);
+        }
+        self.close_listing(info, token_state, now);
+    }
+
+    fn insert_buy_order(&mut self, order_id: u64, order: BuyOrder) {
+        let position = {
+            let book = self.order_books.get(&order.collection);
+            book.map_or(0, |book| {
+                book.iter()
+                    .take_while(|id| {
+                        self.buy_orders
+                            .get(id)
+                            .is_some_and(|other| other.price >= order.price)
+                    })
+                    .count()
+            })
+        };
+        self.order_books
+            .entry(order.collection)
+            .or_default()
+            .insert(position, order_id);
+        let _ = self.buy_orders.insert(order_id, order);
+    }
+
+    fn remove_buy_order(&mut self, order_id: u64) -> Option<BuyOrder> {
+        let order = self.buy_orders.remove_and_get(&order_id)?;
+        if let Some(mut book) = self.order_books.get_mut(&order.collection) {
+            book.retain(|id| *id != order_id);
+        }
+        Some(order)
+    }
+
+    fn best_buy_order(&self, collection: &ContractAddress) -> Option<u64> {
+        self.order_books
+            .get(collection)
+            .and_then(|book| book.first().copied())
+    }
+
+    fn ensure_not_paused(&self) -> ContractResult<()> {
+        ensure!(!self.paused, MarketplaceError::ContractPaused);
+        Ok(())
+    }
+
+    fn ensure_signer(&self, sender: Address) -> ContractResult<AccountAddress> {
+        match sender {
+            Address::Account(account) if self.signers.contains(&account) => Ok(account),
+            _ => bail!(MarketplaceError::Unauthorized),
+        }
+    }
+
+    fn approvals_reach_threshold(&self, approvals: &[AccountAddress]) -> bool {
+        let valid = approvals
+            .iter()
+            .filter(|account| self.signers.contains(account))
+            .count();
+        valid >= usize::from(self.threshold)
+    }
+}
+
+fn ensure_valid_signer_set(signers: &[AccountAddress], threshold: u8) -> ContractResult<()> {
+    ensure!(
+        threshold > 0 && usize::from(threshold) <= signers.len(),
+        MarketplaceError::InvalidSignerSet
+    );
+    for (i, signer) in signers.iter().enumerate() {
+        ensure!(
+            !signers[i + 1..].contains(signer),
+            MarketplaceError::InvalidSignerSet
+        );
+    }
+    Ok(())
+}
+
+/// Deployment configuration. The signers administer the contract through
+/// multisig proposals; the fee settings can later be changed the same way.
+#[derive(Serial, Deserial, SchemaType)]
+struct InitParams {
+    signers: Vec<AccountAddress>,
+    threshold: u8,
+    platform_fee_bps: u16,
+    fee_account: Option<AccountAddress>,
+}
+
+#[init(
+    contract = "Pixpel-NFTMarketplace",
+    parameter = "InitParams",
+    event = "LoggedEvent"
+)]
+fn init<S: HasStateApi>(
+    ctx: &impl HasInitContext,
+    state_builder: &mut StateBuilder<S>,
+) -> InitResult<State<S>> {
+    let params: InitParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ensure_valid_signer_set(&params.signers, params.threshold)?;
+    ensure!(params.platform_fee_bps <= BASIS_POINTS, MarketplaceError::InvalidRate.into());
+
+    let mut state = State::new(state_builder, params.signers, params.threshold);
+    state.platform_fee_bps = params.platform_fee_bps;
+    state.fee_account = params.fee_account;
+    Ok(state)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct PlaceIntoMarketParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    price: Amount,
+    sale_type: u8,
+    expiry: u64,
+    drop_id: Option<u64>,
+    memo: Vec<u8>,
+    reserve: Amount,
+    buy_now: Option<Amount>,
+    charity: Option<CharityShare>,
+    min_bid_interval: u64,
+    participation_deposit: Amount,
+    convert_to_fixed: bool,
+    allow_contract_buyers: bool,
+    metadata_hash: Option<[u8; 32]>,
+    display_hash: Option<[u8; 32]>,
+    bidder_allowlist: Option<BidderAllowlist>,
+    required_tier: u8,
+    disallowed_countries: Vec<[u8; 2]>,
+    candle: Option<CandleParams>,
+    royalty_bps: Option<u16>,
+    royalty_recipient: Option<AccountAddress>,
+    arbiter: Option<ArbiterTerms>,
+    currency: Currency,
+    amount: ContractTokenAmount,
+    min_increment: Option<BidIncrement>,
+    anti_snipe: Option<AntiSnipe>,
+    dutch_auction: Option<DutchAuction>,
+}
+
+/// Lists a token. The attached amount covers the seller bond required for
+/// high-value auctions plus the deposit for a candle close, which must be
+/// non-zero and at least the configured minimum, and must be exactly the bond
+/// otherwise.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "place_into_market",
+    parameter = "PlaceIntoMarketParams",
+    return_value = "ListingResult",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn add<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<ListingResult> {
+    let params: PlaceIntoMarketParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let owner = ensure_account_sender(ctx)?;
+    ensure!(params.memo.len() <= MAX_MEMO_LEN, MarketplaceError::MemoTooLong);
+    if let Some(charity) = &params.charity {
+        ensure!(charity.rate_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+    }
+    let sale_type = TokenSaleTypeState::from_code(params.sale_type);
+    match (&params.dutch_auction, sale_type) {
+        (Some(dutch), TokenSaleTypeState::DutchAuction) => {
+            ensure!(
+                dutch.start_price >= dutch.end_price && dutch.duration > 0,
+                MarketplaceError::InvalidDutchAuction
+            );
+            ensure!(
+                params.currency == Currency::Ccd
+                    && params.reserve == Amount::zero()
+                    && params.buy_now.is_none()
+                    && params.participation_deposit == Amount::zero()
+                    && !params.convert_to_fixed,
+                MarketplaceError::InvalidDutchAuction
+            );
+        }
+        (None, TokenSaleTypeState::DutchAuction) | (Some(_), _) => {
+            bail!(MarketplaceError::NotMatchedSaleType)
+        }
+        (None, _) => {}
+    }
+    if let Some(anti_snipe) = &params.anti_snipe {
+        ensure!(
+            sale_type == TokenSaleTypeState::Auction && params.candle.is_none(),
+            MarketplaceError::NotMatchedSaleType
+        );
+        ensure!(anti_snipe.extension > 0, MarketplaceError::InvalidExpiry);
+    }
+    if let Some(increment) = &params.min_increment {
+        ensure!(sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+        if let BidIncrement::Percentage(bps) = increment {
+            ensure!(*bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+        }
+    }
+    ensure!(params.amount > TokenAmountU64(0), MarketplaceError::InvalidQuantity);
+    if params.amount != TokenAmountU64(1) {
+        ensure!(
+            sale_type == TokenSaleTypeState::Fixed
+                && params.currency == Currency::Ccd
+                && params.drop_id.is_none(),
+            MarketplaceError::InvalidQuantity
+        );
+    }
+    if let Currency::Cis2(payment) = &params.currency {
+        ensure!(
+            params.price == Amount::zero()
+                && params.reserve == Amount::zero()
+                && params.buy_now.is_none()
+                && params.participation_deposit == Amount::zero()
+                && !params.convert_to_fixed
+                && params.candle.is_none()
+                && params.drop_id.is_none()
+                && params.charity.is_none()
+                && params.arbiter.is_none(),
+            MarketplaceError::PaymentTokenUnsupported
+        );
+        ensure!(
+            host.state().penalty_debts.get(&owner).is_none(),
+            MarketplaceError::OutstandingPenaltyDebt
+        );
+        ensure_supports_cis2(host, &payment.contract)?;
+    }
+
+    host.state()
+        .ensure_not_blocked(&params.nft_contract_address, &[owner])?;
+    ensure!(
+        !host.state().collection_whitelist_only
+            || host.state().collection_whitelist.contains(&params.nft_contract_address),
+        MarketplaceError::CollectionNotWhitelisted
+    );
+    ensure_supports_cis2(host, &params.nft_contract_address)?;
+    ensure_is_operator(host, ctx, &params.nft_contract_address)?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let existing = host.state().tokens.get(&info).map(|token_state| token_state.to_owned());
+    // Relisting a token that is already in custody keeps it there.
+    let escrowed = match &existing {
+        Some(token_state)
+            if token_state.curr_state == TokenListState::Listed
+                && token_state.custodian.is_some() =>
+        {
+            ensure!(token_state.owner == owner, MarketplaceError::AlreadyListed);
+            ensure!(token_state.amount == params.amount, MarketplaceError::InvalidQuantity);
+            true
+        }
+        _ => false,
+    };
+    if !escrowed {
+        ensure_balance(host, params.token_id, &params.nft_contract_address, ctx, params.amount)?;
+    }
+    ensure_metadata_hash(host, &info, &params.metadata_hash)?;
+
+    let curr_state = TokenListState::Listed;
+    if let Some(terms) = &params.arbiter {
+        ensure!(terms.arbiter != owner, MarketplaceError::SelfTrade);
+    }
+    ensure!(
+        !host.state().verified_sellers_only || host.state().verified_sellers.contains(&owner),
+        MarketplaceError::SellerNotVerified
+    );
+    let highest_bidder = AccountAddress([0u8; 32]);
+    let mut expiry = params.expiry;
+    let price = params
+        .dutch_auction
+        .as_ref()
+        .map_or(params.price, |dutch| dutch.start_price);
+    let drop_id = params.drop_id;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let template = host
+        .state()
+        .sale_templates
+        .get(&params.nft_contract_address)
+        .map(|template| template.to_owned());
+    let royalty = match template {
+        Some(template) => {
+            ensure!(params.royalty_recipient.is_none(), MarketplaceError::InvalidRoyaltyRecipient);
+            let rate_bps = template.apply(sale_type, price, &mut expiry, params.royalty_bps, now)?;
+            let studio = *host
+                .state()
+                .collection_studios
+                .get(&params.nft_contract_address)
+                .ok_or(MarketplaceError::NotCollectionStudio)?;
+            (rate_bps > 0).then_some(Royalty {
+                account: studio,
+                rate_bps,
+            })
+        }
+        None => match (params.royalty_bps, params.royalty_recipient) {
+            (None, None) => None,
+            (Some(rate_bps), Some(account)) => {
+                ensure!(rate_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+                (rate_bps > 0).then_some(Royalty {
+                    account,
+                    rate_bps,
+                })
+            }
+            _ => bail!(MarketplaceError::InvalidRoyaltyRecipient),
+        },
+    };
+
+    let required_bond = host.state().required_bond(sale_type, cmp::max(price, params.reserve));
+    ensure!(amount >= required_bond, MarketplaceError::InvalidAmountPaid);
+    let deposit = amount - required_bond;
+    let bond = (required_bond > Amount::zero()).then_some(SellerBond {
+        account: owner,
+        amount: required_bond,
+    });
+
+    let candle = match params.candle {
+        Some(candle) => {
+            ensure!(sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+            ensure!(candle.window > 0, MarketplaceError::InvalidCandle);
+            ensure!(
+                deposit > Amount::zero() && deposit >= host.state().min_reveal_deposit,
+                MarketplaceError::InsufficientDeposit
+            );
+            Some(CandleClose {
+                window: candle.window,
+                commitment: randomness::Commitment {
+                    committer: owner,
+                    hash: candle.commitment,
+                    deposit,
+                    reveal_deadline: expiry.saturating_add(candle.window),
+                },
+            })
+        }
+        None => {
+            ensure!(deposit == Amount::zero(), MarketplaceError::InvalidAmountPaid);
+            None
+        }
+    };
+
+    if let Some(drop_id) = drop_id {
+        ensure!(sale_type == TokenSaleTypeState::Fixed, MarketplaceError::NotMatchedSaleType);
+        let drop = host
+            .state()
+            .drops
+            .get(&drop_id)
+            .ok_or(MarketplaceError::DropNotFound)?;
+        ensure!(drop.creator == owner, MarketplaceError::Unauthorized);
+    }
+
+    if let Some(token_state) = &existing {
+        let cooldown = host
+            .state()
+            .relist_cooldowns
+            .get(&params.nft_contract_address)
+            .map_or(0, |cooldown| *cooldown);
+        ensure!(
+            token_state.sold_at == 0 || now >= token_state.sold_at.saturating_add(cooldown),
+            MarketplaceError::RelistCooldown
+        );
+    }
+    if !escrowed {
+        Cis2Client::transfer(
+            host,
+            params.token_id,
+            params.nft_contract_address,
+            params.amount,
+            Address::Account(owner),
+            custody(ctx),
+            custody_data(),
+        )
+        .map_err(MarketplaceError::from)?;
+    }
+
+    let result = ListingResult {
+        nft_contract_address: params.nft_contract_address,
+        token_id: params.token_id,
+        owner,
+        sale_type,
+        price,
+        expiry,
+        relisted: existing.is_some(),
+        display_hash: params.display_hash,
+        seller_verified: host.state().verified_sellers.contains(&owner),
+    };
+
+    let listing_nonce = host.state_mut().new_listing_nonce();
+    // A new listing starts a new bid history.
+    let bid_history_start = host.state().bid_history_end(&info);
+    let starting_bid = match &params.currency {
+        Currency::Ccd => price.micro_ccd,
+        Currency::Cis2(payment) => payment.amount.0,
+    };
+    if let Some(mut token_state) = existing {
+        if token_state.curr_state == TokenListState::Listed {
+            host.state_mut().cancel_listing(&info, &mut token_state, now);
+        }
+        token_state.owner = owner;
+        token_state.highest_bidder = highest_bidder;
+        token_state.sale_type = sale_type;
+        token_state.curr_state = curr_state;
+        token_state.expiry = expiry;
+        token_state.price = price;
+        token_state.drop_id = drop_id;
+        token_state.reserve = params.reserve;
+        token_state.buy_now = params.buy_now;
+        token_state.charity = params.charity;
+        token_state.bid_count = 0;
+        token_state.referrer = None;
+        token_state.min_bid_interval = params.min_bid_interval;
+        token_state.participation_deposit = params.participation_deposit;
+        token_state.convert_to_fixed = params.convert_to_fixed;
+        token_state.allow_contract_buyers = params.allow_contract_buyers;
+        token_state.metadata_hash = params.metadata_hash;
+        token_state.bidder_allowlist = params.bidder_allowlist;
+        token_state.required_tier = params.required_tier;
+        token_state.disallowed_countries = params.disallowed_countries;
+        token_state.arbiter = params.arbiter;
+        token_state.currency = params.currency;
+        token_state.dutch_auction = params.dutch_auction;
+        token_state.anti_snipe = params.anti_snipe;
+        token_state.min_increment = params.min_increment;
+        token_state.amount = params.amount;
+        token_state.custodian = Some(ctx.self_address());
+        token_state.royalty = royalty;
+        token_state.candle = candle;
+        token_state.bond = bond;
+        token_state.settlement_data = AdditionalData::empty();
+        token_state.listing_nonce = listing_nonce;
+        token_state.bid_history_start = bid_history_start;
+        token_state.starting_bid = starting_bid;
+        let _ = host.state_mut().tokens.insert(info.clone(), token_state);
+    } else {
+        let _ = host.state_mut().tokens.insert(
+            info.clone(),
+            TokenState {
+                sale_type,
+                curr_state,
+                owner,
+                expiry,
+                highest_bidder,
+                price,
+                drop_id,
+                sold_at: 0,
+                reserve: params.reserve,
+                buy_now: params.buy_now,
+                charity: params.charity,
+                bid_count: 0,
+                referrer: None,
+                min_bid_interval: params.min_bid_interval,
+                participation_deposit: params.participation_deposit,
+                convert_to_fixed: params.convert_to_fixed,
+                allow_contract_buyers: params.allow_contract_buyers,
+                metadata_hash: params.metadata_hash,
+                bidder_allowlist: params.bidder_allowlist,
+                required_tier: params.required_tier,
+                disallowed_countries: params.disallowed_countries,
+                arbiter: params.arbiter,
+                currency: params.currency,
+                dutch_auction: params.dutch_auction,
+                anti_snipe: params.anti_snipe,
+                min_increment: params.min_increment,
+                amount: params.amount,
+                custodian: Some(ctx.self_address()),
+                keeper: None,
+                listing_nonce,
+                bid_history_start,
+                starting_bid,
+                royalty,
+                candle,
+                bond,
+                settlement_data: AdditionalData::empty(),
+            },
+        );
+    }
+    let _ = host.state_mut().listing_details.insert(
+        info,
+        ListingDetails {
+            memo: params.memo,
+            metadata_verified: params.metadata_hash.is_some(),
+            display_hash: params.display_hash,
+        },
+    );
+
+    if let Some(display_hash) = result.display_hash {
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::DisplayHashRecorded(DisplayHashEvent {
+                nft_contract_address: result.nft_contract_address,
+                token_id: result.token_id,
+                display_hash,
+            }),
+        )?;
+    }
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Listed(ListedEvent {
+            nft_contract_address: result.nft_contract_address,
+            token_id: result.token_id,
+            seller: owner,
+            sale_type,
+            price,
+            amount: params.amount,
+            expiry,
+        }),
+    )?;
+    ContractResult::Ok(result)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct TradeNftParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    price: Amount,
+    amount: ContractTokenAmount,
+    sale_type: u8,
+    referral_code: Option<String>,
+    allowlist_proof: Vec<[u8; 32]>,
+    data: AdditionalData,
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct CreateDropParams {
+    max_per_account: u32,
+    allowlist_root: Option<[u8; 32]>,
+    tranches: Vec<DropTranche>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "create_drop",
+    parameter = "CreateDropParams",
+    return_value = "u64",
+    mutable,
+    enable_logger
+)]
+fn create_drop<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u64> {
+    let params: CreateDropParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let creator = ensure_account_sender(ctx)?;
+    ensure!(
+        params.tranches.iter().all(|tranche| tranche.start < tranche.end)
+            && params.tranches.windows(2).all(|pair| pair[0].end <= pair[1].start),
+        MarketplaceError::InvalidTranche
+    );
+
+    let drop_id = host.state().next_drop_id;
+    host.state_mut().next_drop_id += 1;
+    let _ = host.state_mut().drops.insert(
+        drop_id,
+        Drop {
+            creator,
+            max_per_account: params.max_per_account,
+            allowlist_root: params.allowlist_root,
+            tranches: params.tranches,
+        },
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::DropCreated(DropCreatedEvent {
+            drop_id,
+            creator,
+        }),
+    )?;
+
+    ContractResult::Ok(drop_id)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "trade_market",
+    parameter = "TradeNftParams",
+    return_value = "TradeResult",
+    mutable,
+    payable,
+    enable_logger,
+    crypto_primitives
+)]
+fn trade_nft<S:HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<TradeResult> {
+    let params: TradeNftParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+
+    host.state()
+        .ensure_not_blocked(&info.address, &[ctx.invoker(), token_state.owner])?;
+    ensure_buyer_allowed(ctx, &token_state)?;
+    host.state().ensure_identity_tier(&token_state, &ctx.invoker())?;
+    host.state().ensure_jurisdiction(&token_state, &ctx.invoker())?;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    convert_bidless_auction(host, logger, &info, &mut token_state, now)?;
+
+    let referrer = host.state().resolve_referral(&params.referral_code)?;
+
+    let result = if params.sale_type == 0 || params.sale_type == 2 {
+        ensure!(
+            token_state.sale_type == TokenSaleTypeState::from_code(params.sale_type),
+            MarketplaceError::NotMatchedSaleType
+        );
+        ensure!(!token_state.is_expired(now), MarketplaceError::ExpiredAlready);
+        ensure_not_self_trade(ctx, &token_state.owner)?;
+        ensure_metadata_hash(host, &info, &token_state.metadata_hash)?;
+
+        let mut price = token_state.asking_price(now);
+        if let Some(drop_id) = token_state.drop_id {
+            if let Some(tranche_price) = host.state_mut().record_drop_purchase(
+                crypto_primitives,
+                drop_id,
+                ctx.invoker(),
+                &params.allowlist_proof,
+                now,
+            )? {
+                price = tranche_price;
+            }
+        }
+        ensure!(
+            params.amount > TokenAmountU64(0) && params.amount <= token_state.amount,
+            MarketplaceError::InvalidQuantity
+        );
+        let price = price_of(price, params.amount)?;
+        let currency = token_state.currency.clone();
+        match currency {
+            Currency::Cis2(_) => {
+                ensure!(amount == Amount::zero(), MarketplaceError::InvalidAmountPaid)
+            }
+            Currency::Ccd => ensure!(amount >= price, MarketplaceError::NotEnoughBalance),
+        }
+
+        Cis2Client::transfer(
+            host,
+            params.token_id,
+            params.nft_contract_address,
+            params.amount,
+            token_state.holder(),
+            concordium_cis2::Receiver::Account(ctx.invoker()),
+            params.data.clone(),
+        )
+        .map_err(MarketplaceError::from)?;
+
+        token_state.referrer = referrer;
+        let result = match currency {
+            Currency::Cis2(payment) => TradeResult::PurchasedWithToken(settle_token_sale(
+                host,
+                logger,
+                &info,
+                &token_state,
+                payment,
+                ctx.invoker(),
+                false,
+                now,
+            )?),
+            Currency::Ccd => {
+                // The buyer pays the price at execution time and gets back
+                // whatever was attached on top of it.
+                if amount > price {
+                    host.state_mut().credit_refund(ctx.invoker(), amount - price, now);
+                }
+                TradeResult::Purchased(distribute_proceeds(
+                    host,
+                    logger,
+                    &info,
+                    &token_state,
+                    ctx.invoker(),
+                    price,
+                    now,
+                )?)
+            }
+        };
+
+        if params.amount < token_state.amount {
+            token_state.amount = TokenAmountU64(token_state.amount.0 - params.amount.0);
+            token_state.referrer = None;
+            host.state_mut().log_event(
+                logger,
+                MarketplaceEvent::PartialFill(PartialFillEvent {
+                    nft_contract_address: info.address,
+                    token_id: info.id,
+                    seller: token_state.owner,
+                    buyer: ctx.invoker(),
+                    amount: params.amount,
+                    remaining: token_state.amount,
+                }),
+            )?;
+        } else {
+            token_state.owner = ctx.invoker();
+            token_state.sold_at = now;
+            host.state_mut().close_listing(&info, &mut token_state, now);
+        }
+        result
+    } else if params.sale_type == 1 {
+        ensure!(token_state.currency == Currency::Ccd, MarketplaceError::CurrencyMismatch);
+        ensure!(amount >= token_state.price, MarketplaceError::NotEnoughBalance);
+        ensure_bidder_allowed(crypto_primitives, &token_state, &ctx.invoker(), &params.allowlist_proof)?;
+        let deposit = host.state_mut().join_auction(&info, &token_state, ctx.invoker());
+        ensure!(amount > deposit, MarketplaceError::NotEnoughBalance);
+        let bid = (amount - deposit).micro_ccd;
+        host.state_mut().place_bid(&info, &mut token_state, ctx.invoker(), bid, now)?;
+        token_state.referrer = referrer;
+        token_state.settlement_data = params.data;
+        extend_for_late_bid(host, logger, &info, &mut token_state, now)?;
+        log_bid(host, logger, &info, &token_state, now)?;
+        bid_outcome(host, logger, &info, &mut token_state, now)?
+    } else {
+        bail!(MarketplaceError::NotMatchedSaleType)
+    };
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(result)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct BuyBatchParams {
+    items: Vec<TokenInfo>,
+    data: AdditionalData,
+}
+
+/// Buys several fixed-price CCD listings at once. The attached amount must
+/// cover the sum of the prices; any excess is returned to the buyer. Either
+/// every purchase succeeds or the whole call fails.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "buy_batch",
+    parameter = "BuyBatchParams",
+    return_value = "Vec<SettlementReceipt>",
+    mutable,
+    payable,
+    enable_logger,
+    crypto_primitives
+)]
+fn buy_batch<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<Vec<SettlementReceipt>> {
+    let params: BuyBatchParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    ensure!(params.items.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let buyer = ctx.invoker();
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut remaining = amount;
+    let mut receipts = Vec::with_capacity(params.items.len());
+    for info in params.items {
+        let mut token_state = host
+            .state()
+            .tokens
+            .get(&info)
+            .ok_or(MarketplaceError::TokenNotListed)?
+            .to_owned();
+        ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+        ensure!(token_state.currency == Currency::Ccd, MarketplaceError::CurrencyMismatch);
+
+        host.state().ensure_not_blocked(&info.address, &[buyer, token_state.owner])?;
+        ensure_buyer_allowed(ctx, &token_state)?;
+        host.state().ensure_identity_tier(&token_state, &buyer)?;
+        host.state().ensure_jurisdiction(&token_state, &buyer)?;
+        convert_bidless_auction(host, logger, &info, &mut token_state, now)?;
+        ensure!(
+            token_state.sale_type == TokenSaleTypeState::Fixed,
+            MarketplaceError::NotMatchedSaleType
+        );
+        ensure!(!token_state.is_expired(now), MarketplaceError::ExpiredAlready);
+        ensure_not_self_trade(ctx, &token_state.owner)?;
+        ensure_metadata_hash(host, &info, &token_state.metadata_hash)?;
+
+        let mut price = token_state.price;
+        if let Some(drop_id) = token_state.drop_id {
+            if let Some(tranche_price) = host.state_mut().record_drop_purchase(
+                crypto_primitives,
+                drop_id,
+                buyer,
+                &[],
+                now,
+            )? {
+                price = tranche_price;
+            }
+        }
+        let price = price_of(price, token_state.amount)?;
+        ensure!(remaining >= price, MarketplaceError::NotEnoughBalance);
+        remaining -= price;
+
+        Cis2Client::transfer(
+            host,
+            info.id,
+            info.address,
+            token_state.amount,
+            token_state.holder(),
+            concordium_cis2::Receiver::Account(buyer),
+            params.data.clone(),
+        )
+        .map_err(MarketplaceError::from)?;
+        receipts.push(distribute_proceeds(host, logger, &info, &token_state, buyer, price, now)?);
+
+        token_state.owner = buyer;
+        token_state.sold_at = now;
+        host.state_mut().close_listing(&info, &mut token_state, now);
+        let _ = host.state_mut().tokens.insert(info, token_state);
+    }
+
+    if remaining > Amount::zero() {
+        host.invoke_transfer(&buyer, remaining)
+            .map_err(|_| MarketplaceError::InvokeTransferError)?;
+    }
+
+    ContractResult::Ok(receipts)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct CancelTradeParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    sale_type: u8,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "cancel_trade",
+    parameter = "CancelTradeParams",
+    mutable,
+    enable_logger
+)]
+fn cancel_trade<S:HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: CancelTradeParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::CanceledAlready);
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let sender = ctx.sender();
+    ensure!(
+        sender.matches_account(&token_state.owner),
+        MarketplaceError::Unauthorized
+    );
+
+    if params.sale_type == 0 {
+        ensure!(token_state.sale_type == TokenSaleTypeState::Fixed, MarketplaceError::NotMatchedSaleType);   
+    } else if params.sale_type == 1 {
+        ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+    } else if params.sale_type == 2 {
+        ensure!(
+            token_state.sale_type == TokenSaleTypeState::DutchAuction,
+            MarketplaceError::NotMatchedSaleType
+        );
+    }
+
+    withdraw_by_seller(host, logger, &info, &mut token_state, now)?;
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "cancel_batch",
+    parameter = "Vec<TokenInfo>",
+    mutable,
+    enable_logger
+)]
+fn cancel_batch<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let tokens: Vec<TokenInfo> = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    ensure!(tokens.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let sender = ctx.sender();
+    for info in tokens {
+        let mut token_state = host
+            .state()
+            .tokens
+            .get(&info)
+            .ok_or(MarketplaceError::TokenNotListed)?
+            .to_owned();
+
+        ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::CanceledAlready);
+        ensure!(
+            sender.matches_account(&token_state.owner),
+            MarketplaceError::Unauthorized
+        );
+
+        withdraw_by_seller(host, logger, &info, &mut token_state, now)?;
+        let _ = host.state_mut().tokens.insert(info, token_state);
+    }
+
+    ContractResult::Ok(())
+}
+
+/// Outcome of `cancel_trade_batch`, sorting the requested tokens by what
+/// happened to them.
+#[derive(Serialize, SchemaType, Default)]
+struct CancelSummary {
+    cancelled: Vec<TokenInfo>,
+    not_listed: Vec<TokenInfo>,
+    unauthorized: Vec<TokenInfo>,
+}
+
+/// Like `cancel_batch`, but skips tokens the sender cannot cancel instead of
+/// failing, and reports which tokens were cancelled.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "cancel_trade_batch",
+    parameter = "Vec<TokenInfo>",
+    return_value = "CancelSummary",
+    mutable,
+    enable_logger
+)]
+fn cancel_trade_batch<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<CancelSummary> {
+    let tokens: Vec<TokenInfo> = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    ensure!(tokens.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let sender = ctx.sender();
+    let mut summary = CancelSummary::default();
+    for info in tokens {
+        let mut token_state = match host.state().tokens.get(&info) {
+            Some(token_state) if token_state.curr_state == TokenListState::Listed => {
+                token_state.to_owned()
+            }
+            _ => {
+                summary.not_listed.push(info);
+                continue;
+            }
+        };
+        if !sender.matches_account(&token_state.owner) {
+            summary.unauthorized.push(info);
+            continue;
+        }
+
+        withdraw_by_seller(host, logger, &info, &mut token_state, now)?;
+        let _ = host.state_mut().tokens.insert(info.clone(), token_state);
+        summary.cancelled.push(info);
+    }
+
+    ContractResult::Ok(summary)
+}
+
+/// Closes a listing at its seller's request, returning an escrowed token and
+/// charging any cancellation penalty.
+fn withdraw_by_seller<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<()> {
+    return_escrowed_token(host, info, token_state)?;
+    if let Some(penalty) = host.state_mut().withdraw_listing(info, token_state, now) {
+        host.state_mut().log_event(logger, MarketplaceEvent::CancellationPenalty(penalty))?;
+    }
+    log_cancelled(host, logger, info, token_state)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct FinaliseTradeParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    sale_type: u8,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "finalise_trade",
+    parameter = "FinaliseTradeParams",
+    return_value = "TradeResult",
+    mutable,
+    enable_logger
+)]
+fn finalise_trade<S:HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<TradeResult> {
+    let params: FinaliseTradeParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    
+    ensure!(params.sale_type.cmp(&1u8).is_ge(), MarketplaceError::NotMatchedSaleType);
+    
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let sender = ctx.sender();
+    if !sender.matches_account(&token_state.owner) {
+        // Anyone may settle an expired auction, for a share of the proceeds.
+        ensure!(
+            token_state.expiry != 0 && token_state.is_expired(now),
+            MarketplaceError::Unauthorized
+        );
+        token_state.keeper = Some(ctx.invoker());
+    }
+
+    ensure!(token_state.candle.is_none(), MarketplaceError::CandleNotClosed);
+    ensure!(token_state.has_bidder(), MarketplaceError::NotBidded);
+    ensure!(token_state.reserve_met(), MarketplaceError::ReserveNotMet);
+
+    deliver_auctioned_token(host, &info, &token_state)?;
+    let result = match token_state.currency.clone() {
+        Currency::Ccd => {
+            TradeResult::Purchased(settle_auction(host, logger, &info, &mut token_state, now)?)
+        }
+        Currency::Cis2(payment) => TradeResult::PurchasedWithToken(settle_token_auction(
+            host,
+            logger,
+            &info,
+            &mut token_state,
+            payment,
+            now,
+        )?),
+    };
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(result)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct CloseCandleParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    seed: Option<[u8; 32]>,
+}
+
+/// Fixes the effective end of an expired candle auction and refunds the bids
+/// placed after it. The end is drawn from the committed seed mixed with the
+/// bids escrowed in the window, which the seller cannot know when committing.
+/// Anyone may close with the seed; without it the auction can only be closed
+/// once `window` has passed after expiry, the seller's deposit is slashed and
+/// the end is drawn from the bids alone. The listing is then finalised as a
+/// regular auction.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "close_candle_auction",
+    parameter = "CloseCandleParams",
+    return_value = "u64",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn close_candle_auction<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<u64> {
+    let params: CloseCandleParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    let candle = token_state.candle.clone().ok_or(MarketplaceError::NotMatchedSaleType)?;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    ensure!(token_state.is_expired(now), MarketplaceError::InvalidExpiry);
+
+    let mut entropy = to_bytes(&info);
+    if let Some(bids) = host.state().candle_bids.get(&info) {
+        entropy.extend(to_bytes(&*bids));
+    }
+    let commitment = &candle.commitment;
+    let random = match params.seed {
+        Some(seed) => {
+            commitment.verify(crypto_primitives, &seed, now)?;
+            host.state_mut().credit_refund(commitment.committer, commitment.deposit, now);
+            randomness::mix(crypto_primitives, Some(&seed), &entropy)
+        }
+        None => {
+            ensure!(commitment.is_forfeit(now), MarketplaceError::CandleNotClosed);
+            host.state_mut().fee_pool += commitment.deposit;
+            randomness::mix(crypto_primitives, None, &entropy)
+        }
+    };
+    let end = token_state.expiry.saturating_sub(candle.window)
+        + randomness::draw(&random, candle.window.saturating_add(1));
+
+    host.state_mut().resolve_candle(&info, &mut token_state, end, now);
+    token_state.candle = None;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::CandleAuctionClosed(CandleAuctionClosedEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            end,
+            seed_revealed: params.seed.is_some(),
+        }),
+    )?;
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(end)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct EmergencyExitParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+}
+
+/// Lets users reach their assets while the market is paused, without
+/// settling anything. The standing bidder takes their bid back and the auction
+/// stays listed from its starting price; the seller closes the listing and
+/// takes the token back.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "emergency_exit",
+    parameter = "EmergencyExitParams",
+    mutable,
+    enable_logger
+)]
+fn emergency_exit<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: EmergencyExitParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ensure!(host.state().paused, MarketplaceError::NotPaused);
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let sender = ctx.sender();
+    if token_state.has_bidder() && sender.matches_account(&token_state.highest_bidder) {
+        // Escrowed candle bids below the standing bid are released with it.
+        host.state_mut().release_candle_bids(&info, now);
+        host.state_mut().refund_highest_bid(&token_state, now);
+        let bidder = token_state.highest_bidder;
+        token_state.highest_bidder = AccountAddress([0u8; 32]);
+        token_state.set_highest_bid(token_state.starting_bid);
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::BidWithdrawn(BidWithdrawnEvent {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                bidder,
+            }),
+        )?;
+        let _ = host.state_mut().tokens.insert(info, token_state);
+        return ContractResult::Ok(());
+    }
+    ensure!(sender.matches_account(&token_state.owner), MarketplaceError::Unauthorized);
+
+    if token_state.has_bidder() {
+        host.state_mut().refund_highest_bid(&token_state, now);
+    }
+    return_escrowed_token(host, &info, &token_state)?;
+    log_cancelled(host, logger, &info, &token_state)?;
+    host.state_mut().close_listing(&info, &mut token_state, now);
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_account_report",
+    parameter = "AccountAddress",
+    return_value = "AccountReport"
+)]
+fn view_account_report<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<AccountReport> {
+    let account: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let report = host
+        .state()
+        .reports
+        .get(&account)
+        .map(|report| report.to_owned())
+        .unwrap_or_default();
+
+    ContractResult::Ok(report)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct PostRatesParams {
+    ccd_eur: Rate,
+    pixp_ccd: Rate,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "post_rates",
+    parameter = "PostRatesParams",
+    mutable,
+    enable_logger
+)]
+fn post_rates<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: PostRatesParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_signer(ctx.sender())?;
+    ensure!(
+        params.ccd_eur.denominator != 0 && params.pixp_ccd.denominator != 0,
+        MarketplaceError::InvalidRate
+    );
+
+    let rates = ReferenceRates {
+        ccd_eur: params.ccd_eur,
+        pixp_ccd: params.pixp_ccd,
+        updated_at: ctx.metadata().slot_time().timestamp_millis(),
+    };
+    host.state_mut().rates = Some(rates.clone());
+
+    host.state_mut().log_event(logger, MarketplaceEvent::RatesPosted(rates))?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, SchemaType)]
+struct RatesView {
+    rates: ReferenceRates,
+    age: u64,
+    stale: bool,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_rates",
+    return_value = "RatesView"
+)]
+fn view_rates<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<RatesView> {
+    let state = host.state();
+    let rates = state.rates.clone().ok_or(MarketplaceError::NoRates)?;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let age = now.saturating_sub(rates.updated_at);
+    let stale = state.max_rate_age != 0 && age > state.max_rate_age;
+
+    ContractResult::Ok(RatesView { rates, age, stale })
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_loyalty_points",
+    parameter = "AccountAddress",
+    return_value = "u64"
+)]
+fn view_loyalty_points<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<u64> {
+    let account: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let points = host
+        .state()
+        .loyalty_points
+        .get(&account)
+        .map_or(0, |points| *points);
+
+    ContractResult::Ok(points)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct RedeemLoyaltyParams {
+    account: AccountAddress,
+    points: u64,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "redeem_loyalty_points",
+    parameter = "RedeemLoyaltyParams",
+    mutable,
+    enable_logger
+)]
+fn redeem_loyalty_points<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: RedeemLoyaltyParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    match (ctx.sender(), host.state().loyalty_redeemer) {
+        (Address::Contract(sender), Some(redeemer)) if sender == redeemer => {}
+        _ => bail!(MarketplaceError::Unauthorized),
+    }
+
+    {
+        let mut balance = host
+            .state_mut()
+            .loyalty_points
+            .entry(params.account)
+            .occupied_or(MarketplaceError::InsufficientPoints)?;
+        ensure!(*balance >= params.points, MarketplaceError::InsufficientPoints);
+        *balance -= params.points;
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::LoyaltyRedeemed(LoyaltyRedeemedEvent {
+            account: params.account,
+            points: params.points,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, SchemaType)]
+struct SellerFeeView {
+    platform_fee_bps: u16,
+    volume: Amount,
+    discount_bps: u16,
+    volume_window: u64,
+    tiers: Vec<FeeTier>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_seller_fee_tier",
+    parameter = "AccountAddress",
+    return_value = "SellerFeeView"
+)]
+fn view_seller_fee_tier<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<SellerFeeView> {
+    let seller: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let state = host.state();
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let volume = state.seller_volume(&seller, now);
+
+    ContractResult::Ok(SellerFeeView {
+        platform_fee_bps: state.platform_fee_bps,
+        volume,
+        discount_bps: state.seller_discount_bps(volume),
+        volume_window: state.volume_window,
+        tiers: state.fee_tiers.clone(),
+    })
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "register_referral_code",
+    parameter = "String",
+    mutable,
+    enable_logger
+)]
+fn register_referral_code<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let code: String = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let referrer = ensure_account_sender(ctx)?;
+    ensure!(
+        !code.is_empty() && code.len() <= MAX_REFERRAL_CODE_LEN,
+        MarketplaceError::InvalidReferralCode
+    );
+    ensure!(
+        host.state().referral_codes.get(&code).is_none(),
+        MarketplaceError::ReferralCodeTaken
+    );
+
+    let _ = host.state_mut().referral_codes.insert(code.clone(), referrer);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ReferralCodeRegistered(ReferralCodeEvent {
+            code,
+            referrer,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "claim_referral_earnings",
+    return_value = "Amount",
+    mutable,
+    enable_logger
+)]
+fn claim_referral_earnings<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<Amount> {
+    let referrer = ensure_account_sender(ctx)?;
+
+    let earnings = host
+        .state_mut()
+        .referral_earnings
+        .remove_and_get(&referrer)
+        .unwrap_or_else(Amount::zero);
+    ensure!(earnings > Amount::zero(), MarketplaceError::NothingToClaim);
+
+    host.invoke_transfer(&referrer, earnings)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Withdrawn(WithdrawalEvent {
+            account: referrer,
+            amount: earnings,
+            source: WithdrawalSource::ReferralEarnings,
+        }),
+    )?;
+
+    ContractResult::Ok(earnings)
+}
+
+#[derive(Serial, SchemaType)]
+struct AuctionSnapshot {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    owner: AccountAddress,
+    highest_bidder: Option<AccountAddress>,
+    highest_bid: Amount,
+    reserve: Amount,
+    bid_count: u32,
+    expiry: u64,
+    display_hash: Option<[u8; 32]>,
+    seller_verified: bool,
+    taken_at: u64,
+}
+
+#[derive(Serial, SchemaType)]
+struct AuctionSnapshotView {
+    snapshot: AuctionSnapshot,
+    digest: [u8; 32],
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct AuctionSnapshotParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_auction_snapshot",
+    parameter = "AuctionSnapshotParams",
+    return_value = "AuctionSnapshotView",
+    crypto_primitives
+)]
+fn view_auction_snapshot<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<AuctionSnapshotView> {
+    let params: AuctionSnapshotParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?;
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+
+    let snapshot = AuctionSnapshot {
+        nft_contract_address: params.nft_contract_address,
+        token_id: params.token_id,
+        owner: token_state.owner,
+        highest_bidder: if token_state.has_bidder() {
+            Some(token_state.highest_bidder)
+        } else {
+            None
+        },
+        highest_bid: token_state.price,
+        reserve: token_state.reserve,
+        bid_count: token_state.bid_count,
+        expiry: token_state.expiry,
+        display_hash: host.state().display_hash(&info),
+        seller_verified: host.state().verified_sellers.contains(&token_state.owner),
+        taken_at: ctx.metadata().slot_time().timestamp_millis(),
+    };
+    let digest = crypto_primitives.hash_sha2_256(&to_bytes(&snapshot)).0;
+
+    ContractResult::Ok(AuctionSnapshotView { snapshot, digest })
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct QuotePurchaseParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    buyer: AccountAddress,
+    amount: ContractTokenAmount,
+    referral_code: Option<String>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "quote_purchase",
+    parameter = "QuotePurchaseParams",
+    return_value = "PurchaseQuote"
+)]
+fn quote_purchase<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<PurchaseQuote> {
+    let params: QuotePurchaseParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let state = host.state();
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut listing = state
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(listing.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    // The quote is in CCD; token-priced listings settle through their own
+    // payment token.
+    ensure!(listing.currency == Currency::Ccd, MarketplaceError::CurrencyMismatch);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    if listing.should_convert_to_fixed(now) {
+        listing.sale_type = TokenSaleTypeState::Fixed;
+        listing.price = cmp::max(listing.price, listing.reserve);
+        listing.participation_deposit = Amount::zero();
+    }
+    ensure!(
+        listing.sale_type == TokenSaleTypeState::Auction || !listing.is_expired(now),
+        MarketplaceError::ExpiredAlready
+    );
+
+    let mut price = match listing.sale_type {
+        TokenSaleTypeState::Fixed => listing.price,
+        TokenSaleTypeState::Auction => listing.min_next_bid(),
+        TokenSaleTypeState::DutchAuction => listing.asking_price(now),
+    };
+    // Fixed and Dutch sales are priced per unit, at the open drop tranche's
+    // price if there is one, as in trade_market.
+    if listing.sale_type != TokenSaleTypeState::Auction {
+        if let Some(drop_id) = listing.drop_id {
+            let drop = state.drops.get(&drop_id).ok_or(MarketplaceError::DropNotFound)?;
+            if let Some(index) = drop.active_tranche(now)? {
+                price = drop.tranches[index].price;
+            }
+        }
+        ensure!(
+            params.amount > TokenAmountU64(0) && params.amount <= listing.amount,
+            MarketplaceError::InvalidQuantity
+        );
+        price = price_of(price, params.amount)?;
+    }
+    let joined = state
+        .auction_participants
+        .get(&info)
+        .is_some_and(|participants| participants.contains(&params.buyer));
+    let participation_deposit = if listing.sale_type == TokenSaleTypeState::Auction && !joined {
+        listing.participation_deposit
+    } else {
+        Amount::zero()
+    };
+
+    listing.referrer = state.resolve_referral(&params.referral_code)?;
+    let royalty = effective_royalty(host, &info, &listing)?;
+    let state = host.state();
+    let breakdown = state.proceeds_breakdown(&listing, royalty.as_ref(), price, now);
+
+    ContractResult::Ok(PurchaseQuote {
+        nft_contract_address: params.nft_contract_address,
+        token_id: params.token_id,
+        sale_type: listing.sale_type,
+        display_hash: host.state().display_hash(&info),
+        seller_verified: host.state().verified_sellers.contains(&listing.owner),
+        total: Amount::from_micro_ccd(
+            price
+                .micro_ccd
+                .checked_add(participation_deposit.micro_ccd)
+                .ok_or(MarketplaceError::AmountOverflow)?,
+        ),
+        price,
+        participation_deposit,
+        breakdown,
+    })
+}
+
+/// Emergency brake: any single signer may pause trading at once. Resuming
+/// takes a `SetPaused(false)` proposal approved by the full threshold.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "pause",
+    mutable,
+    enable_logger
+)]
+fn pause<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let signer = host.state().ensure_signer(ctx.sender())?;
+    host.state().ensure_not_paused()?;
+
+    host.state_mut().paused = true;
+
+    host.state_mut().log_event(logger, MarketplaceEvent::Paused(PausedEvent { signer }))?;
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "propose",
+    parameter = "AdminAction",
+    return_value = "u64",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn propose<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<u64> {
+    let action: AdminAction = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let signer = host.state().ensure_signer(ctx.sender())?;
+
+    let proposal_id = host.state().next_proposal_id;
+    host.state_mut().next_proposal_id += 1;
+
+    let proposal = Proposal {
+        action,
+        approvals: vec![signer],
+    };
+    let executed =
+        host.state().approvals_reach_threshold(&proposal.approvals) && !proposal.action.is_upgrade();
+    if executed {
+        execute_admin_action(host, logger, crypto_primitives, proposal.action)?;
+    } else {
+        let _ = host.state_mut().proposals.insert(proposal_id, proposal);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ProposalMade(ProposalEvent {
+            proposal_id,
+            signer,
+            executed,
+        }),
+    )?;
+
+    ContractResult::Ok(proposal_id)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "approve",
+    parameter = "u64",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn approve<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<()> {
+    let proposal_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let signer = host.state().ensure_signer(ctx.sender())?;
+
+    let mut proposal = host
+        .state()
+        .proposals
+        .get(&proposal_id)
+        .ok_or(MarketplaceError::ProposalNotFound)?
+        .to_owned();
+    ensure!(
+        !proposal.approvals.contains(&signer),
+        MarketplaceError::AlreadyApproved
+    );
+    proposal.approvals.push(signer);
+
+    let executed =
+        host.state().approvals_reach_threshold(&proposal.approvals) && !proposal.action.is_upgrade();
+    if executed {
+        host.state_mut().proposals.remove(&proposal_id);
+        execute_admin_action(host, logger, crypto_primitives, proposal.action)?;
+    } else {
+        let _ = host.state_mut().proposals.insert(proposal_id, proposal);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ProposalApproved(ProposalEvent {
+            proposal_id,
+            signer,
+            executed,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+fn execute_admin_action<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+    action: AdminAction,
+) -> ContractResult<()> {
+    match action {
+        AdminAction::UpdateSigners { signers, threshold } => {
+            ensure_valid_signer_set(&signers, threshold)?;
+            let state = host.state_mut();
+            state.signers = signers;
+            state.threshold = threshold;
+        }
+        AdminAction::SetPaused(paused) => host.state_mut().paused = paused,
+        AdminAction::SetSweepBounty(bounty) => host.state_mut().sweep_bounty = bounty,
+        AdminAction::SetMaxAuctionDuration(duration) => {
+            host.state_mut().max_auction_duration = duration
+        }
+        AdminAction::SetRefundBounty(bounty) => host.state_mut().refund_bounty = bounty,
+        AdminAction::SetRelistCooldown {
+            collection,
+            cooldown,
+        } => {
+            let _ = host.state_mut().relist_cooldowns.insert(collection, cooldown);
+        }
+        AdminAction::SetCompliance(compliance) => {
+            if let Some(compliance) = &compliance {
+                ensure!(compliance.rate_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+            }
+            host.state_mut().compliance = compliance;
+        }
+        AdminAction::SetMaxRateAge(max_age) => host.state_mut().max_rate_age = max_age,
+        AdminAction::SetReceiptContract(receipt) => host.state_mut().receipt = receipt,
+        AdminAction::SetLoyaltyRate(rate) => host.state_mut().loyalty_rate = rate,
+        AdminAction::SetLoyaltyRedeemer(redeemer) => host.state_mut().loyalty_redeemer = redeemer,
+        AdminAction::SetPlatformFee(fee_bps) => {
+            ensure!(fee_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+            host.state_mut().platform_fee_bps = fee_bps;
+        }
+        AdminAction::SetFeeAccount(account) => host.state_mut().fee_account = account,
+        AdminAction::SetFeeTiers { window, tiers } => {
+            for tier in &tiers {
+                ensure!(tier.discount_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+            }
+            let state = host.state_mut();
+            state.volume_window = window;
+            state.fee_tiers = tiers;
+        }
+        AdminAction::SetReferralShare(share_bps) => {
+            ensure!(share_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+            host.state_mut().referral_share_bps = share_bps;
+        }
+        AdminAction::InvalidateCollection(collection) => {
+            host.state_mut().validated_collections.remove(&collection);
+        }
+        AdminAction::SetCollectionStudio { collection, studio } => {
+            let state = host.state_mut();
+            match studio {
+                Some(studio) => {
+                    let _ = state.collection_studios.insert(collection, studio);
+                }
+                None => {
+                    state.collection_studios.remove(&collection);
+                    state.sale_templates.remove(&collection);
+                }
+            }
+        }
+        AdminAction::SetVerifiedSellersOnly(only) => host.state_mut().verified_sellers_only = only,
+        AdminAction::SetCollectionWhitelistOnly(only) => {
+            host.state_mut().collection_whitelist_only = only;
+        }
+        AdminAction::WhitelistCollection(collection) => {
+            host.state_mut().collection_whitelist.insert(collection);
+        }
+        AdminAction::UnwhitelistCollection(collection) => {
+            host.state_mut().collection_whitelist.remove(&collection);
+        }
+        AdminAction::SetSellerBond { threshold, bond } => {
+            let state = host.state_mut();
+            state.bond_threshold = threshold;
+            state.seller_bond = bond;
+        }
+        AdminAction::SetCancelPenalty(penalty_bps) => {
+            ensure!(penalty_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+            host.state_mut().cancel_penalty_bps = penalty_bps;
+        }
+        AdminAction::AddModerator(moderator) => {
+            host.state_mut().moderators.insert(moderator);
+        }
+        AdminAction::RemoveModerator(moderator) => {
+            host.state_mut().moderators.remove(&moderator);
+        }
+        AdminAction::AddIdentityVerifier(verifier) => {
+            host.state_mut().identity_verifiers.insert(verifier);
+        }
+        AdminAction::RemoveIdentityVerifier(verifier) => {
+            host.state_mut().identity_verifiers.remove(&verifier);
+        }
+        AdminAction::RepairTokens(repairs) => {
+            ensure!(repairs.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+            for repair in repairs {
+                let event = host.state_mut().repair_token(crypto_primitives, repair)?;
+                host.state_mut().log_event(logger, MarketplaceEvent::StateRepaired(event))?;
+            }
+        }
+        AdminAction::RepairRefunds(repairs) => {
+            ensure!(repairs.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+            for repair in repairs {
+                let event = host.state_mut().repair_refund(repair)?;
+                host.state_mut().log_event(logger, MarketplaceEvent::StateRepaired(event))?;
+            }
+        }
+        AdminAction::SetKeeperReward(reward_bps) => {
+            ensure!(reward_bps <= MAX_KEEPER_REWARD_BPS, MarketplaceError::InvalidRate);
+            host.state_mut().keeper_reward_bps = reward_bps;
+        }
+        AdminAction::SetMinRevealDeposit(deposit) => host.state_mut().min_reveal_deposit = deposit,
+        AdminAction::SetRefundThresholds { min_age, min_amount } => {
+            let state = host.state_mut();
+            state.refund_min_age = min_age;
+            state.refund_min_amount = min_amount;
+        }
+        // Approved upgrades stay proposals until `upgrade` runs them.
+        AdminAction::Upgrade(_) => bail!(MarketplaceError::Unauthorized),
+    }
+    Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct ExtendAuctionParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    expiry: u64,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "extend_auction",
+    parameter = "ExtendAuctionParams",
+    mutable,
+    enable_logger
+)]
+fn extend_auction<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: ExtendAuctionParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let max_auction_duration = host.state().max_auction_duration;
+    let mut token_state = host
+        .state_mut()
+        .tokens
+        .entry(info)
+        .occupied_or(MarketplaceError::TokenNotListed)?;
+
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+    ensure!(
+        ctx.sender().matches_account(&token_state.owner),
+        MarketplaceError::Unauthorized
+    );
+    ensure!(!token_state.has_bidder(), MarketplaceError::AuctionHasBids);
+    ensure!(params.expiry > token_state.expiry, MarketplaceError::InvalidExpiry);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    ensure!(
+        max_auction_duration == 0 || params.expiry <= now.saturating_add(max_auction_duration),
+        MarketplaceError::ExceedsMaxDuration
+    );
+
+    let old_expiry = token_state.expiry;
+    token_state.expiry = params.expiry;
+    drop(token_state);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::AuctionRescheduled(AuctionRescheduledEvent {
+            nft_contract_address: params.nft_contract_address,
+            token_id: params.token_id,
+            old_expiry,
+            new_expiry: params.expiry,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct ReauctionParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    reserve: Amount,
+    expiry: u64,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "reauction",
+    parameter = "ReauctionParams",
+    mutable,
+    enable_logger
+)]
+fn reauction<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: ReauctionParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+    ensure!(
+        ctx.sender().matches_account(&token_state.owner),
+        MarketplaceError::Unauthorized
+    );
+    ensure!(!token_state.has_bidder(), MarketplaceError::AuctionHasBids);
+    ensure!(
+        token_state.currency == Currency::Ccd || params.reserve == Amount::zero(),
+        MarketplaceError::PaymentTokenUnsupported
+    );
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    ensure!(token_state.is_expired(now), MarketplaceError::InvalidExpiry);
+    ensure!(params.expiry > now, MarketplaceError::InvalidExpiry);
+    let max_auction_duration = host.state().max_auction_duration;
+    ensure!(
+        max_auction_duration == 0 || params.expiry <= now.saturating_add(max_auction_duration),
+        MarketplaceError::ExceedsMaxDuration
+    );
+
+    host.state_mut().release_participants(&info, &token_state, now);
+
+    let old_expiry = token_state.expiry;
+    token_state.expiry = params.expiry;
+    token_state.reserve = params.reserve;
+    token_state.bid_count = 0;
+    token_state.referrer = None;
+    token_state.bid_history_start = host.state().bid_history_end(&info);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::AuctionRescheduled(AuctionRescheduledEvent {
+            nft_contract_address: params.nft_contract_address,
+            token_id: params.token_id,
+            old_expiry,
+            new_expiry: params.expiry,
+        }),
+    )?;
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct UpdateReserveParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    reserve: Amount,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "update_reserve",
+    parameter = "UpdateReserveParams",
+    mutable,
+    enable_logger
+)]
+fn update_reserve<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: UpdateReserveParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state_mut()
+        .tokens
+        .entry(info)
+        .occupied_or(MarketplaceError::TokenNotListed)?;
+
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+    ensure!(
+        ctx.sender().matches_account(&token_state.owner),
+        MarketplaceError::Unauthorized
+    );
+    ensure!(params.reserve < token_state.reserve, MarketplaceError::InvalidReserve);
+
+    let old_reserve = token_state.reserve;
+    token_state.reserve = params.reserve;
+    drop(token_state);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ReserveUpdated(ReserveUpdatedEvent {
+            nft_contract_address: params.nft_contract_address,
+            token_id: params.token_id,
+            old_reserve,
+            new_reserve: params.reserve,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct OfferListingTransferParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    to: AccountAddress,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "offer_listing_transfer",
+    parameter = "OfferListingTransferParams",
+    mutable,
+    enable_logger
+)]
+fn offer_listing_transfer<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: OfferListingTransferParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let (owner, listing_nonce) = {
+        let token_state = host
+            .state()
+            .tokens
+            .get(&info)
+            .ok_or(MarketplaceError::TokenNotListed)?;
+        ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+        ensure!(
+            ctx.sender().matches_account(&token_state.owner),
+            MarketplaceError::Unauthorized
+        );
+        (token_state.owner, token_state.listing_nonce)
+    };
+    ensure!(params.to != owner, MarketplaceError::SelfTrade);
+
+    // A new offer replaces the pending one.
+    let replaced = host.state_mut().listing_transfers.insert(
+        info,
+        PendingListingTransfer {
+            from: owner,
+            to: params.to,
+            listing_nonce,
+        },
+    );
+    if let Some(replaced) = replaced {
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::ListingTransferCancelled(ListingTransferEvent {
+                nft_contract_address: params.nft_contract_address,
+                token_id: params.token_id,
+                from: replaced.from,
+                to: replaced.to,
+            }),
+        )?;
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ListingTransferOffered(ListingTransferEvent {
+            nft_contract_address: params.nft_contract_address,
+            token_id: params.token_id,
+            from: owner,
+            to: params.to,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct AcceptListingTransferParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "accept_listing_transfer",
+    parameter = "AcceptListingTransferParams",
+    mutable,
+    enable_logger
+)]
+fn accept_listing_transfer<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: AcceptListingTransferParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let pending = host
+        .state_mut()
+        .listing_transfers
+        .remove_and_get(&info)
+        .ok_or(MarketplaceError::NoPendingTransfer)?;
+    ensure!(
+        ctx.sender().matches_account(&pending.to),
+        MarketplaceError::Unauthorized
+    );
+
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    ensure!(
+        token_state.owner == pending.from && token_state.listing_nonce == pending.listing_nonce,
+        MarketplaceError::NoPendingTransfer
+    );
+    ensure!(token_state.highest_bidder != pending.to, MarketplaceError::SelfTrade);
+
+    // The new owner must be allowed to list the token themselves.
+    let state = host.state();
+    state.ensure_not_blocked(&info.address, &[pending.to])?;
+    ensure!(
+        !state.verified_sellers_only || state.verified_sellers.contains(&pending.to),
+        MarketplaceError::SellerNotVerified
+    );
+    if let Some(terms) = &token_state.arbiter {
+        ensure!(terms.arbiter != pending.to, MarketplaceError::SelfTrade);
+    }
+    ensure!(
+        token_state.currency == Currency::Ccd || state.penalty_debts.get(&pending.to).is_none(),
+        MarketplaceError::OutstandingPenaltyDebt
+    );
+
+    // An escrowed token stays in custody; only the listing changes hands.
+    if token_state.custodian.is_none() {
+        let is_operator = Cis2Client::is_operator_of(
+            host,
+            Address::Account(pending.from),
+            ctx.self_address(),
+            &params.nft_contract_address,
+        )
+        .map_err(MarketplaceError::from)?;
+        ensure!(is_operator, MarketplaceError::NotOperator);
+
+        Cis2Client::transfer(
+            host,
+            params.token_id,
+            params.nft_contract_address,
+            token_state.amount,
+            Address::Account(pending.from),
+            concordium_cis2::Receiver::Account(pending.to),
+            AdditionalData::empty(),
+        )
+        .map_err(MarketplaceError::from)?;
+    }
+
+    token_state.owner = pending.to;
+    let _ = host.state_mut().tokens.insert(info, token_state);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ListingTransferred(ListingTransferEvent {
+            nft_contract_address: params.nft_contract_address,
+            token_id: params.token_id,
+            from: pending.from,
+            to: pending.to,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct CancelListingTransferParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+}
+
+/// Withdraws a pending listing transfer. Either the offering seller or the
+/// account it was offered to may cancel it.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "cancel_listing_transfer",
+    parameter = "CancelListingTransferParams",
+    mutable,
+    enable_logger
+)]
+fn cancel_listing_transfer<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: CancelListingTransferParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let pending = host
+        .state_mut()
+        .listing_transfers
+        .remove_and_get(&info)
+        .ok_or(MarketplaceError::NoPendingTransfer)?;
+    ensure!(
+        ctx.sender().matches_account(&pending.from) || ctx.sender().matches_account(&pending.to),
+        MarketplaceError::Unauthorized
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ListingTransferCancelled(ListingTransferEvent {
+            nft_contract_address: params.nft_contract_address,
+            token_id: params.token_id,
+            from: pending.from,
+            to: pending.to,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+/// Adds to the sender's deposit, after paying their outstanding penalty
+/// debts out of it.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "deposit",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn deposit<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let account = ensure_account_sender(ctx)?;
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let amount = host.state_mut().settle_penalty_debts(account, amount, now);
+
+    *host
+        .state_mut()
+        .deposits
+        .entry(account)
+        .or_insert(Amount::zero()) += amount;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Deposited(DepositEvent {
+            account,
+            amount,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "withdraw_deposit",
+    parameter = "Amount",
+    mutable,
+    enable_logger
+)]
+fn withdraw_deposit<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let amount: Amount = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let account = ensure_account_sender(ctx)?;
+
+    {
+        let mut balance = host
+            .state_mut()
+            .deposits
+            .entry(account)
+            .occupied_or(MarketplaceError::InsufficientDeposit)?;
+        ensure!(*balance >= amount, MarketplaceError::InsufficientDeposit);
+        *balance -= amount;
+    }
+
+    host.invoke_transfer(&account, amount)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Withdrawn(WithdrawalEvent {
+            account,
+            amount,
+            source: WithdrawalSource::Deposit,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "add_bid_operator",
+    parameter = "AccountAddress",
+    mutable,
+    enable_logger
+)]
+fn add_bid_operator<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let operator: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let principal = ensure_account_sender(ctx)?;
+
+    host.state_mut().bid_operators.insert((principal, operator));
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BidOperatorChanged(BidOperatorEvent {
+            principal,
+            operator,
+            added: true,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "remove_bid_operator",
+    parameter = "AccountAddress",
+    mutable,
+    enable_logger
+)]
+fn remove_bid_operator<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let operator: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let principal = ensure_account_sender(ctx)?;
+
+    host.state_mut().bid_operators.remove(&(principal, operator));
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BidOperatorChanged(BidOperatorEvent {
+            principal,
+            operator,
+            added: false,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct SetIdentityTierParams {
+    account: AccountAddress,
+    tier: u8,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "set_identity_tier",
+    parameter = "SetIdentityTierParams",
+    mutable,
+    enable_logger
+)]
+fn set_identity_tier<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: SetIdentityTierParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let verifier = ensure_account_sender(ctx)?;
+    ensure!(
+        host.state().identity_verifiers.contains(&verifier),
+        MarketplaceError::NotIdentityVerifier
+    );
+
+    if params.tier == 0 {
+        host.state_mut().identity_tiers.remove(&params.account);
+    } else {
+        let _ = host.state_mut().identity_tiers.insert(params.account, params.tier);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::IdentityTierSet(IdentityTierEvent {
+            account: params.account,
+            tier: params.tier,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct RecordResidencyParams {
+    account: AccountAddress,
+    /// ISO 3166-1 alpha-2 country code.
+    country: [u8; 2],
+}
+
+/// Records the country of residence an identity verifier has established
+/// from the account's ID attribute proof. The proof itself is checked by the
+/// verifier off-chain, as contracts cannot verify ID proofs.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "record_residency",
+    parameter = "RecordResidencyParams",
+    mutable,
+    enable_logger
+)]
+fn record_residency<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: RecordResidencyParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let verifier = ensure_account_sender(ctx)?;
+    ensure!(
+        host.state().identity_verifiers.contains(&verifier),
+        MarketplaceError::NotIdentityVerifier
+    );
+
+    let _ = host.state_mut().residencies.insert(params.account, params.country);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::ResidencyRecorded(ResidencyEvent {
+            account: params.account,
+            country: params.country,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct SetSellerVerifiedParams {
+    seller: AccountAddress,
+    verified: bool,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "set_seller_verified",
+    parameter = "SetSellerVerifiedParams",
+    mutable,
+    enable_logger
+)]
+fn set_seller_verified<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: SetSellerVerifiedParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let moderator = ensure_account_sender(ctx)?;
+    ensure!(
+        host.state().moderators.contains(&moderator),
+        MarketplaceError::NotModerator
+    );
+
+    if params.verified {
+        host.state_mut().verified_sellers.insert(params.seller);
+    } else {
+        host.state_mut().verified_sellers.remove(&params.seller);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::SellerVerificationChanged(SellerVerificationEvent {
+            seller: params.seller,
+            verified: params.verified,
+            moderator,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct SetBlockedParams {
+    entry: BlocklistEntry,
+    blocked: bool,
+}
+
+/// Blocks a collection or account from listing, buying and bidding. Existing
+/// listings stay in place but cannot be traded while blocked.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "set_blocked",
+    parameter = "SetBlockedParams",
+    mutable,
+    enable_logger
+)]
+fn set_blocked<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: SetBlockedParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let moderator = ensure_account_sender(ctx)?;
+    ensure!(
+        host.state().moderators.contains(&moderator),
+        MarketplaceError::NotModerator
+    );
+
+    let state = host.state_mut();
+    match (params.entry, params.blocked) {
+        (BlocklistEntry::Collection(collection), true) => {
+            state.blocked_collections.insert(collection);
+        }
+        (BlocklistEntry::Collection(collection), false) => {
+            state.blocked_collections.remove(&collection);
+        }
+        (BlocklistEntry::Account(account), true) => {
+            state.blocked_accounts.insert(account);
+        }
+        (BlocklistEntry::Account(account), false) => {
+            state.blocked_accounts.remove(&account);
+        }
+    }
+
+    state.log_event(
+        logger,
+        MarketplaceEvent::BlocklistChanged(BlocklistEvent {
+            entry: params.entry,
+            blocked: params.blocked,
+            moderator,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, SchemaType)]
+struct VersionInfo {
+    contract_version: String,
+    state_version: u16,
+    /// Token standards the marketplace can trade.
+    standards: Vec<StandardIdentifierOwned>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "version",
+    return_value = "VersionInfo"
+)]
+fn version<S: HasStateApi>(
+    _ctx: &impl HasReceiveContext,
+    _host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<VersionInfo> {
+    ContractResult::Ok(VersionInfo {
+        contract_version: CONTRACT_VERSION.to_string(),
+        state_version: STATE_VERSION,
+        standards: vec![CIS2_STANDARD_IDENTIFIER.to_owned()],
+    })
+}
+
+/// Runs an approved `Upgrade` proposal: swaps the module, then invokes the
+/// migration it names. Any signer may call it. It is a low-level entrypoint
+/// so that the old module does not write its own layout of the root back
+/// over the migrated state when the call returns. The upgrade is undone
+/// unless the new module then loads the state and expects its version.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "upgrade",
+    parameter = "u64",
+    low_level
+)]
+fn upgrade<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<S, StateApiType = S>,
+) -> ContractResult<()> {
+    let proposal_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let params = {
+        let mut root = host
+            .state()
+            .lookup_entry(&[])
+            .ok_or(MarketplaceError::UnsupportedStateVersion)?;
+        let mut state = State::deserial_with_state(host.state(), &mut root)
+            .map_err(|_| MarketplaceError::UnsupportedStateVersion)?;
+        state.ensure_signer(ctx.sender())?;
+        let proposal = state
+            .proposals
+            .get(&proposal_id)
+            .ok_or(MarketplaceError::ProposalNotFound)?
+            .to_owned();
+        ensure!(
+            state.approvals_reach_threshold(&proposal.approvals),
+            MarketplaceError::ProposalNotApproved
+        );
+        let params = match proposal.action {
+            AdminAction::Upgrade(params) => params,
+            _ => bail!(MarketplaceError::NotAnUpgrade),
+        };
+        state.proposals.remove(&proposal_id);
+        params
+    };
+
+    let self_address = ctx.self_address();
+    host.upgrade(params.module).map_err(|_| MarketplaceError::UpgradeFailed)?;
+    if let Some((entrypoint, parameter)) = params.migrate {
+        host.invoke_contract_raw(
+            &self_address,
+            parameter.as_parameter(),
+            entrypoint.as_entrypoint_name(),
+            Amount::zero(),
+        )
+        .map_err(|_| MarketplaceError::MigrationFailed)?;
+    }
+
+    // `version` of the new module only answers once it can load the state.
+    let response = host
+        .invoke_contract_raw_read_only(
+            &self_address,
+            Parameter::empty(),
+            EntrypointName::new_unchecked("version"),
+            Amount::zero(),
+        )
+        .map_err(|_| MarketplaceError::MigrationRequired)?;
+    let (_, expected): (String, u16) = response
+        .ok_or(MarketplaceError::MigrationRequired)?
+        .get()
+        .map_err(|_| MarketplaceError::MigrationRequired)?;
+    ensure!(
+        expected == migration::stored_version(host.state())?,
+        MarketplaceError::MigrationRequired
+    );
+    ContractResult::Ok(())
+}
+
+/// Converts the state left by an older module to the current layout; run by
+/// `upgrade` right after swapping the module. Works on the
+/// raw state, since the old layout need not deserialize as `State`. Returns
+/// the resulting state version.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "migrate",
+    return_value = "u16",
+    low_level
+)]
+fn migrate<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<S, StateApiType = S>,
+) -> ContractResult<u16> {
+    ensure!(
+        ctx.sender() == Address::Contract(ctx.self_address()),
+        MarketplaceError::Unauthorized
+    );
+
+    let version = migration::stored_version(host.state())?;
+    if version != STATE_VERSION {
+        let (state_api, state_builder) = host.state_and_builder();
+        migration::migrate(state_api, state_builder, version)?;
+    }
+    ContractResult::Ok(STATE_VERSION)
+}
+
+/// Moves up to `max` listings from the previous `TokenInfo`-keyed layout to
+/// the compact one. Callable by anyone; returns the number moved.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "migrate_listings",
+    parameter = "u32",
+    return_value = "u32",
+    mutable,
+    enable_logger
+)]
+fn migrate_listings<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u32> {
+    let max: u32 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ensure!(max as usize <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let moved = host.state_mut().tokens.migrate_batch(max as usize);
+
+    host.state_mut()
+        .log_event(logger, MarketplaceEvent::ListingsMigrated(ListingsMigratedEvent { moved }))?;
+
+    ContractResult::Ok(moved)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "get_listing",
+    parameter = "(ContractAddress, ContractTokenId)",
+    return_value = "TokenState"
+)]
+fn get_listing<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<TokenState> {
+    let (nft_contract_address, token_id): (ContractAddress, ContractTokenId) = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let token_state = host
+        .state()
+        .tokens
+        .get(&TokenInfo::new(token_id, nft_contract_address))
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+
+    ContractResult::Ok(token_state)
+}
+
+/// Every listed token, in the order documented in `listings`. Large
+/// marketplaces should use `view_listings_page` instead.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_listings",
+    return_value = "Vec<(TokenInfo, TokenState)>"
+)]
+fn view_listings<S: HasStateApi>(
+    _ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Vec<(TokenInfo, TokenState)>> {
+    let (listings, _) = host.state().tokens.page(None, 0, usize::MAX, |token_state| {
+        token_state.curr_state == TokenListState::Listed
+    });
+    ContractResult::Ok(listings)
+}
+
+/// Every token listed by the given account, in the order documented in
+/// `listings`.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "listings_by_owner",
+    parameter = "AccountAddress",
+    return_value = "Vec<(TokenInfo, TokenState)>"
+)]
+fn listings_by_owner<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Vec<(TokenInfo, TokenState)>> {
+    let owner: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let (listings, _) = host.state().tokens.page(None, 0, usize::MAX, |token_state| {
+        token_state.curr_state == TokenListState::Listed && token_state.owner == owner
+    });
+    ContractResult::Ok(listings)
+}
+
+/// Listed auctions that have not yet expired at the current slot time, in the
+/// order documented in `listings`.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "active_auctions",
+    return_value = "Vec<(TokenInfo, TokenState)>"
+)]
+fn active_auctions<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Vec<(TokenInfo, TokenState)>> {
+    let now = ctx.metadata().slot_time().timestamp_millis();
+
+    let (auctions, _) = host.state().tokens.page(None, 0, usize::MAX, |token_state| {
+        token_state.curr_state == TokenListState::Listed
+            && token_state.sale_type == TokenSaleTypeState::Auction
+            && !token_state.is_expired(now)
+    });
+    ContractResult::Ok(auctions)
+}
+
+/// Where a page starts: after a number of listings, or after the `next`
+/// cursor of the previous page. Cursors stay consistent while listings are
+/// added or removed; offsets are simpler for jumping to a page number.
+#[derive(Serial, Deserial, SchemaType)]
+enum PageStart {
+    Offset(u32),
+    After(ListingKey),
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct ListingsPageParams {
+    start: PageStart,
+    limit: u32,
+}
+
+#[derive(Serial, SchemaType)]
+struct ListingsPage {
+    listings: Vec<(TokenInfo, TokenState)>,
+    next: Option<ListingKey>,
+}
+
+/// Up to `limit` listed tokens in the order documented in `listings`. Pass
+/// the returned `next` as `PageStart::After` to get the following page.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_listings_page",
+    parameter = "ListingsPageParams",
+    return_value = "ListingsPage"
+)]
+fn view_listings_page<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<ListingsPage> {
+    let params: ListingsPageParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ensure!(
+        (1..=MAX_PAGE_SIZE).contains(&(params.limit as usize)),
+        MarketplaceError::InvalidPageSize
+    );
+
+    let (after, skip) = match params.start {
+        PageStart::Offset(offset) => (None, offset as usize),
+        PageStart::After(key) => (Some(key), 0),
+    };
+    let (listings, next) =
+        host.state().tokens.page(after, skip, params.limit as usize, |token_state| {
+            token_state.curr_state == TokenListState::Listed
+        });
+    ContractResult::Ok(ListingsPage { listings, next })
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct BidHistoryParams {
+    token: TokenInfo,
+    offset: u32,
+    limit: u32,
+}
+
+/// Up to `limit` bids of the latest auction on a token, oldest first,
+/// skipping the first `offset`.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_bid_history",
+    parameter = "BidHistoryParams",
+    return_value = "Vec<BidRecord>"
+)]
+fn view_bid_history<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Vec<BidRecord>> {
+    let params: BidHistoryParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ensure!(
+        (1..=MAX_PAGE_SIZE).contains(&(params.limit as usize)),
+        MarketplaceError::InvalidPageSize
+    );
+
+    let state = host.state();
+    let start = state
+        .tokens
+        .get(&params.token)
+        .map_or(0, |token_state| token_state.bid_history_start);
+    let len = state.bid_history_end(&params.token);
+    let first = start.saturating_add(params.offset);
+    let end = cmp::min(len, first.saturating_add(params.limit));
+    let bids = (first..end)
+        .filter_map(|index| {
+            state
+                .bid_history
+                .get(&(params.token.clone(), index))
+                .map(|bid| bid.to_owned())
+        })
+        .collect();
+    ContractResult::Ok(bids)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_listing_details",
+    parameter = "TokenInfo",
+    return_value = "ListingDetails"
+)]
+fn view_listing_details<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<ListingDetails> {
+    let info: TokenInfo = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state()
+        .listing_details
+        .get(&info)
+        .map(|details| details.to_owned())
+        .ok_or(MarketplaceError::TokenNotListed)
+}
+
+/// The `seq` the next event will carry.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_event_seq",
+    return_value = "u64"
+)]
+fn view_event_seq<S: HasStateApi>(
+    _ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<u64> {
+    ContractResult::Ok(host.state().next_event_seq)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_seller_reputation",
+    parameter = "AccountAddress",
+    return_value = "SellerReputation"
+)]
+fn view_seller_reputation<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<SellerReputation> {
+    let seller: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ContractResult::Ok(
+        host.state()
+            .reputations
+            .get(&seller)
+            .map(|reputation| reputation.to_owned())
+            .unwrap_or_default(),
+    )
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_seller_verified",
+    parameter = "AccountAddress",
+    return_value = "bool"
+)]
+fn view_seller_verified<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<bool> {
+    let seller: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ContractResult::Ok(host.state().verified_sellers.contains(&seller))
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct SetSaleTemplateParams {
+    collection: ContractAddress,
+    template: Option<SaleTemplate>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "set_sale_template",
+    parameter = "SetSaleTemplateParams",
+    mutable,
+    enable_logger
+)]
+fn set_sale_template<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: SetSaleTemplateParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let studio = ensure_account_sender(ctx)?;
+    ensure!(
+        host.state()
+            .collection_studios
+            .get(&params.collection)
+            .is_some_and(|registered| *registered == studio),
+        MarketplaceError::NotCollectionStudio
+    );
+
+    match params.template.clone() {
+        Some(template) => {
+            ensure!(template.royalty_bps <= BASIS_POINTS, MarketplaceError::InvalidRate);
+            ensure!(
+                template.max_duration == 0
+                    || (template.min_duration <= template.max_duration
+                        && template.default_duration <= template.max_duration),
+                MarketplaceError::DurationOutOfRange
+            );
+            ensure!(
+                template.default_duration == 0 || template.default_duration >= template.min_duration,
+                MarketplaceError::DurationOutOfRange
+            );
+            let _ = host.state_mut().sale_templates.insert(params.collection, template);
+        }
+        None => {
+            host.state_mut().sale_templates.remove(&params.collection);
+        }
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::SaleTemplateChanged(SaleTemplateEvent {
+            collection: params.collection,
+            template: params.template,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_sale_template",
+    parameter = "ContractAddress",
+    return_value = "Option<SaleTemplate>"
+)]
+fn view_sale_template<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Option<SaleTemplate>> {
+    let collection: ContractAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ContractResult::Ok(
+        host.state()
+            .sale_templates
+            .get(&collection)
+            .map(|template| template.to_owned()),
+    )
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_identity_tier",
+    parameter = "AccountAddress",
+    return_value = "u8"
+)]
+fn view_identity_tier<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<u8> {
+    let account: AccountAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ContractResult::Ok(host.state().identity_tiers.get(&account).map_or(0, |tier| *tier))
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct BidForParams {
+    principal: AccountAddress,
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    amount: Amount,
+    data: AdditionalData,
+    allowlist_proof: Vec<[u8; 32]>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "bid_for",
+    parameter = "BidForParams",
+    return_value = "TradeResult",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn bid_for<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<TradeResult> {
+    let params: BidForParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+
+    let operator = ensure_account_sender(ctx)?;
+    ensure!(
+        host.state().bid_operators.contains(&(params.principal, operator)),
+        MarketplaceError::NotBidOperator
+    );
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    host.state()
+        .ensure_not_blocked(&info.address, &[params.principal, token_state.owner])?;
+    ensure!(token_state.currency == Currency::Ccd, MarketplaceError::CurrencyMismatch);
+    ensure_buyer_allowed(ctx, &token_state)?;
+    ensure_bidder_allowed(crypto_primitives, &token_state, &params.principal, &params.allowlist_proof)?;
+    host.state().ensure_identity_tier(&token_state, &params.principal)?;
+    host.state().ensure_jurisdiction(&token_state, &params.principal)?;
+
+    let participation_deposit = host
+        .state_mut()
+        .join_auction(&info, &token_state, params.principal);
+    {
+        let required = params
+            .amount
+            .micro_ccd
+            .checked_add(participation_deposit.micro_ccd)
+            .map(Amount::from_micro_ccd)
+            .ok_or(MarketplaceError::AmountOverflow)?;
+        let mut balance = host
+            .state_mut()
+            .deposits
+            .entry(params.principal)
+            .occupied_or(MarketplaceError::InsufficientDeposit)?;
+        ensure!(*balance >= required, MarketplaceError::InsufficientDeposit);
+        *balance -= required;
+    }
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    host.state_mut()
+        .place_bid(&info, &mut token_state, params.principal, params.amount.micro_ccd, now)?;
+    token_state.settlement_data = params.data;
+    extend_for_late_bid(host, logger, &info, &mut token_state, now)?;
+    log_bid(host, logger, &info, &token_state, now)?;
+    let result = bid_outcome(host, logger, &info, &mut token_state, now)?;
+
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    ContractResult::Ok(result)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct MakePackageOfferParams {
+    items: Vec<TokenInfo>,
+    expiry: u64,
+    data: AdditionalData,
+    give: Vec<TokenInfo>,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "make_package_offer",
+    parameter = "MakePackageOfferParams",
+    return_value = "u64",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn make_package_offer<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u64> {
+    let params: MakePackageOfferParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let offerer = ensure_account_sender(ctx)?;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let min_items = if params.give.is_empty() { 2 } else { 1 };
+    ensure!(
+        params.items.len() >= min_items
+            && params.items.len() + params.give.len() <= MAX_BATCH_SIZE,
+        MarketplaceError::InvalidOffer
+    );
+    for (i, item) in params.items.iter().enumerate() {
+        ensure!(!params.items[i + 1..].contains(item), MarketplaceError::InvalidOffer);
+    }
+    for (i, item) in params.give.iter().enumerate() {
+        ensure!(
+            !params.give[i + 1..].contains(item) && !params.items.contains(item),
+            MarketplaceError::InvalidOffer
+        );
+    }
+    ensure!(
+        amount > Amount::zero() || !params.give.is_empty(),
+        MarketplaceError::InvalidAmountPaid
+    );
+    ensure!(params.expiry > now, MarketplaceError::InvalidExpiry);
+
+    for collection in distinct_collections(params.give.iter()) {
+        ensure_is_operator(host, ctx, &collection)?;
+    }
+    for item in &params.give {
+        ensure!(
+            host.state().tokens.get(item).is_none_or(|token_state| {
+                token_state.curr_state == TokenListState::UnListed
+            }),
+            MarketplaceError::InvalidOffer
+        );
+        Cis2Client::transfer(
+            host,
+            item.id,
+            item.address,
+            TokenAmountU64(1),
+            Address::Account(offerer),
+            custody(ctx),
+            custody_data(),
+        )
+        .map_err(MarketplaceError::from)?;
+    }
+
+    let offer_id = host.state().next_offer_id;
+    host.state_mut().next_offer_id += 1;
+
+    let approvals = vec![None; params.items.len()];
+    let _ = host.state_mut().package_offers.insert(
+        offer_id,
+        PackageOffer {
+            offerer,
+            amount,
+            expiry: params.expiry,
+            items: params.items,
+            approvals,
+            data: params.data,
+            give: params.give,
+        },
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::PackageOfferMade(PackageOfferEvent {
+            offer_id,
+            offerer,
+            amount,
+        }),
+    )?;
+
+    ContractResult::Ok(offer_id)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "cancel_package_offer",
+    parameter = "u64",
+    mutable,
+    enable_logger
+)]
+fn cancel_package_offer<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let offer_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let offer = host
+        .state_mut()
+        .package_offers
+        .remove_and_get(&offer_id)
+        .ok_or(MarketplaceError::OfferNotFound)?;
+    ensure!(
+        ctx.sender().matches_account(&offer.offerer),
+        MarketplaceError::Unauthorized
+    );
+
+    host.invoke_transfer(&offer.offerer, offer.amount)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+    release_custody(host, ctx, &offer.give, offer.offerer, AdditionalData::empty())?;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::PackageOfferCancelled(PackageOfferEvent {
+            offer_id,
+            offerer: offer.offerer,
+            amount: offer.amount,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "accept_package_offer",
+    parameter = "u64",
+    mutable,
+    enable_logger
+)]
+fn accept_package_offer<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let offer_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let owner = ensure_account_sender(ctx)?;
+
+    let mut offer = host
+        .state()
+        .package_offers
+        .get(&offer_id)
+        .ok_or(MarketplaceError::OfferNotFound)?
+        .to_owned();
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    ensure!(now <= offer.expiry, MarketplaceError::ExpiredAlready);
+    ensure!(owner != offer.offerer, MarketplaceError::SelfTrade);
+
+    let pending: Vec<usize> = (0..offer.items.len())
+        .filter(|i| offer.approvals[*i].is_none())
+        .collect();
+    let mut approved_any = false;
+    for collection in distinct_collections(pending.iter().map(|i| &offer.items[*i])) {
+        let indices: Vec<usize> = pending
+            .iter()
+            .copied()
+            .filter(|i| offer.items[*i].address == collection)
+            .collect();
+        let queries: Vec<BalanceOfQuery<ContractTokenId>> = indices
+            .iter()
+            .map(|i| BalanceOfQuery {
+                token_id: offer.items[*i].id,
+                address: Address::Account(owner),
+            })
+            .collect();
+        let balances: Vec<ContractTokenAmount> = Cis2Client::balance_of_many(host, &collection, &queries)
+            .map_err(MarketplaceError::from)?;
+
+        let held: Vec<usize> = indices
+            .into_iter()
+            .zip(balances)
+            .filter(|(_, balance)| *balance >= TokenAmountU64(1))
+            .map(|(i, _)| i)
+            .collect();
+        if held.is_empty() {
+            continue;
+        }
+        ensure_is_operator(host, ctx, &collection)?;
+        for i in held {
+            offer.approvals[i] = Some(owner);
+            approved_any = true;
+        }
+    }
+    ensure!(approved_any, MarketplaceError::NoBalance);
+    if !offer.give.is_empty() {
+        ensure!(
+            offer.approvals.iter().flatten().all(|approval| *approval == owner),
+            MarketplaceError::InvalidOffer
+        );
+    }
+
+    if offer.approvals.iter().all(Option::is_some) {
+        // Earlier approvers may have moved their tokens or revoked the
+        // marketplace as operator since approving; their approvals lapse.
+        for (approver, items) in stale_package_approvals(host, ctx, &offer, owner)? {
+            for i in &items {
+                offer.approvals[*i] = None;
+            }
+            host.state_mut().log_event(
+                logger,
+                MarketplaceEvent::PackageApprovalRevoked(PackageApprovalEvent {
+                    offer_id,
+                    approver,
+                    items: items.len() as u32,
+                }),
+            )?;
+        }
+    }
+
+    if offer.approvals.iter().any(Option::is_none) {
+        let _ = host.state_mut().package_offers.insert(offer_id, offer);
+        return ContractResult::Ok(());
+    }
+
+    host.state_mut().package_offers.remove(&offer_id);
+    release_custody(host, ctx, &offer.give, owner, offer.data.clone())?;
+
+    let item_count = offer.items.len() as u64;
+    let share = Amount::from_micro_ccd(offer.amount.micro_ccd / item_count);
+    let remainder = offer.amount - share * item_count;
+
+    for (i, (item, approval)) in offer.items.iter().zip(offer.approvals.iter()).enumerate() {
+        let seller = approval.unwrap_abort();
+        Cis2Client::transfer(
+            host,
+            item.id,
+            item.address,
+            TokenAmountU64(1),
+            Address::Account(seller),
+            concordium_cis2::Receiver::Account(offer.offerer),
+            offer.data.clone(),
+        )
+        .map_err(MarketplaceError::from)?;
+
+        let mut token_state = host
+            .state()
+            .tokens
+            .get(item)
+            .map(|token_state| token_state.to_owned())
+            .unwrap_or_else(|| TokenState::unlisted(seller));
+        if token_state.curr_state == TokenListState::Listed {
+            host.state_mut().cancel_listing(item, &mut token_state, now);
+        }
+        token_state.owner = seller;
+
+        let price = if i == 0 { share + remainder } else { share };
+        distribute_proceeds(host, logger, item, &token_state, offer.offerer, price, now)?;
+
+        token_state.owner = offer.offerer;
+        token_state.sold_at = now;
+        let _ = host.state_mut().tokens.insert(item.clone(), token_state);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::PackageOfferAccepted(PackageOfferEvent {
+            offer_id,
+            offerer: offer.offerer,
+            amount: offer.amount,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+/// Approvals on `offer` by accounts other than `owner` which no longer hold
+/// the item or no longer have the marketplace as operator, by approver.
+fn stale_package_approvals<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    ctx: &impl HasReceiveContext,
+    offer: &PackageOffer,
+    owner: AccountAddress,
+) -> ContractResult<Vec<(AccountAddress, Vec<usize>)>> {
+    let mut approvers: Vec<AccountAddress> = Vec::new();
+    for approver in offer.approvals.iter().flatten() {
+        if *approver != owner && !approvers.contains(approver) {
+            approvers.push(*approver);
+        }
+    }
+
+    let mut stale = Vec::new();
+    for approver in approvers {
+        let approved: Vec<usize> = (0..offer.items.len())
+            .filter(|i| offer.approvals[*i] == Some(approver))
+            .collect();
+        let mut lapsed = Vec::new();
+        for collection in distinct_collections(approved.iter().map(|i| &offer.items[*i])) {
+            let indices: Vec<usize> = approved
+                .iter()
+                .copied()
+                .filter(|i| offer.items[*i].address == collection)
+                .collect();
+            let is_operator = Cis2Client::is_operator_of(
+                host,
+                Address::Account(approver),
+                ctx.self_address(),
+                &collection,
+            )
+            .map_err(MarketplaceError::from)?;
+            if !is_operator {
+                lapsed.extend(indices);
+                continue;
+            }
+            let queries: Vec<BalanceOfQuery<ContractTokenId>> = indices
+                .iter()
+                .map(|i| BalanceOfQuery {
+                    token_id: offer.items[*i].id,
+                    address: Address::Account(approver),
+                })
+                .collect();
+            let balances: Vec<ContractTokenAmount> =
+                Cis2Client::balance_of_many(host, &collection, &queries)
+                    .map_err(MarketplaceError::from)?;
+            lapsed.extend(
+                indices
+                    .into_iter()
+                    .zip(balances)
+                    .filter(|(_, balance)| *balance < TokenAmountU64(1))
+                    .map(|(i, _)| i),
+            );
+        }
+        if !lapsed.is_empty() {
+            stale.push((approver, lapsed));
+        }
+    }
+    Ok(stale)
+}
+
+/// Withdraws the sender's approvals of a package offer that is still
+/// waiting for other item holders.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "revoke_package_approval",
+    parameter = "u64",
+    mutable,
+    enable_logger
+)]
+fn revoke_package_approval<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let offer_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let approver = ensure_account_sender(ctx)?;
+    let mut offer = host
+        .state()
+        .package_offers
+        .get(&offer_id)
+        .ok_or(MarketplaceError::OfferNotFound)?
+        .to_owned();
+
+    let mut items = 0u32;
+    for approval in offer.approvals.iter_mut() {
+        if *approval == Some(approver) {
+            *approval = None;
+            items += 1;
+        }
+    }
+    ensure!(items > 0, MarketplaceError::Unauthorized);
+    let _ = host.state_mut().package_offers.insert(offer_id, offer);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::PackageApprovalRevoked(PackageApprovalEvent {
+            offer_id,
+            approver,
+            items,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+/// Releases escrowed proceeds to the seller. The arbiter may release at any
+/// time; the seller may claim once the arbiter's timeout has passed.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "approve_release",
+    parameter = "u64",
+    mutable,
+    enable_logger
+)]
+fn approve_release<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let escrow_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let escrow = host
+        .state_mut()
+        .escrows
+        .remove_and_get(&escrow_id)
+        .ok_or(MarketplaceError::EscrowNotFound)?;
+    let sender = ctx.sender();
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    if !sender.matches_account(&escrow.arbiter) {
+        ensure!(sender.matches_account(&escrow.seller), MarketplaceError::NotArbiter);
+        ensure!(now >= escrow.claimable_at, MarketplaceError::EscrowLocked);
+    }
+
+    host.invoke_transfer(&escrow.seller, escrow.amount)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+    host.state_mut().log_event(logger, MarketplaceEvent::EscrowReleased(escrow.event(escrow_id)))?;
+
+    ContractResult::Ok(())
+}
+
+/// Refunds escrowed proceeds to the buyer on the arbiter's ruling, which
+/// counts as a dispute lost by the seller.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "refund_escrow",
+    parameter = "u64",
+    mutable,
+    enable_logger
+)]
+fn refund_escrow<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let escrow_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let escrow = host
+        .state_mut()
+        .escrows
+        .remove_and_get(&escrow_id)
+        .ok_or(MarketplaceError::EscrowNotFound)?;
+    ensure!(ctx.sender().matches_account(&escrow.arbiter), MarketplaceError::NotArbiter);
+
+    host.state_mut().reputations.entry(escrow.seller).or_default().disputes_lost += 1;
+    host.invoke_transfer(&escrow.buyer, escrow.amount)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+    host.state_mut().log_event(logger, MarketplaceEvent::EscrowRefunded(escrow.event(escrow_id)))?;
+
+    ContractResult::Ok(())
+}
+
+/// Listing terms carried in the `data` of a transfer to the marketplace.
+#[derive(Serial, Deserial, SchemaType)]
+struct ListByTransferParams {
+    price: Amount,
+    sale_type: u8,
+    expiry: u64,
+}
+
+/// Accepts tokens sent to the marketplace. Transfers the marketplace makes
+/// into custody are tagged and accepted as they are. Any other transfer must
+/// carry `ListByTransferParams` in its `data` and lists the token, which stays
+/// in custody until it is sold or the listing is cancelled.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "onReceivingCIS2",
+    parameter = "ContractOnReceivingCis2Params",
+    mutable,
+    enable_logger
+)]
+fn on_receiving_cis2<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let params: ContractOnReceivingCis2Params = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let nft_contract_address = match ctx.sender() {
+        Address::Contract(contract) => contract,
+        Address::Account(_) => bail!(MarketplaceError::Unauthorized),
+    };
+    ensure!(
+        params.from.matches_account(&ctx.invoker()),
+        MarketplaceError::Unauthorized
+    );
+    if params.data.as_ref() == CUSTODY_TAG {
+        return ContractResult::Ok(());
+    }
+
+    let terms: ListByTransferParams =
+        from_bytes(params.data.as_ref()).map_err(|_e| MarketplaceError::ParseParams)?;
+    ensure!(params.amount > TokenAmountU64(0), MarketplaceError::InvalidQuantity);
+    ensure_supports_cis2(host, &nft_contract_address)?;
+
+    let owner = ctx.invoker();
+    let state = host.state();
+    state.ensure_not_paused()?;
+    state.ensure_not_blocked(&nft_contract_address, &[owner])?;
+    ensure!(
+        !state.collection_whitelist_only
+            || state.collection_whitelist.contains(&nft_contract_address),
+        MarketplaceError::CollectionNotWhitelisted
+    );
+    ensure!(
+        !state.verified_sellers_only || state.verified_sellers.contains(&owner),
+        MarketplaceError::SellerNotVerified
+    );
+
+    let info = TokenInfo::new(params.token_id, nft_contract_address);
+    let sale_type = TokenSaleTypeState::from_code(terms.sale_type);
+    ensure!(
+        sale_type != TokenSaleTypeState::DutchAuction,
+        MarketplaceError::NotMatchedSaleType
+    );
+    ensure!(
+        sale_type == TokenSaleTypeState::Fixed || params.amount == TokenAmountU64(1),
+        MarketplaceError::InvalidQuantity
+    );
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut expiry = terms.expiry;
+    let royalty = match state.sale_templates.get(&nft_contract_address) {
+        Some(template) => {
+            let rate_bps = template.apply(sale_type, terms.price, &mut expiry, None, now)?;
+            let studio = *state
+                .collection_studios
+                .get(&nft_contract_address)
+                .ok_or(MarketplaceError::NotCollectionStudio)?;
+            (rate_bps > 0).then_some(Royalty {
+                account: studio,
+                rate_bps,
+            })
+        }
+        None => None,
+    };
+    // A transfer carries no CCD to post a seller bond with.
+    ensure!(
+        state.required_bond(sale_type, terms.price) == Amount::zero(),
+        MarketplaceError::InvalidAmountPaid
+    );
+
+    let mut token_state = state
+        .tokens
+        .get(&info)
+        .map(|token_state| token_state.to_owned())
+        .unwrap_or_else(|| TokenState::unlisted(owner));
+    let cooldown = state
+        .relist_cooldowns
+        .get(&nft_contract_address)
+        .map_or(0, |cooldown| *cooldown);
+    ensure!(
+        token_state.sold_at == 0 || now >= token_state.sold_at.saturating_add(cooldown),
+        MarketplaceError::RelistCooldown
+    );
+
+    if token_state.curr_state == TokenListState::Listed {
+        ensure!(token_state.custodian.is_none(), MarketplaceError::AlreadyListed);
+        host.state_mut().cancel_listing(&info, &mut token_state, now);
+    }
+    token_state.owner = owner;
+    token_state.sale_type = sale_type;
+    token_state.curr_state = TokenListState::Listed;
+    token_state.expiry = expiry;
+    token_state.price = terms.price;
+    token_state.amount = params.amount;
+    token_state.bid_count = 0;
+    token_state.referrer = None;
+    token_state.royalty = royalty;
+    token_state.custodian = Some(ctx.self_address());
+    token_state.listing_nonce = host.state_mut().new_listing_nonce();
+    token_state.bid_history_start = host.state().bid_history_end(&info);
+    token_state.starting_bid = terms.price.micro_ccd;
+    let _ = host.state_mut().tokens.insert(info.clone(), token_state);
+    let _ = host.state_mut().listing_details.insert(
+        info,
+        ListingDetails {
+            memo: Vec::new(),
+            metadata_verified: false,
+            display_hash: None,
+        },
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Listed(ListedEvent {
+            nft_contract_address,
+            token_id: params.token_id,
+            seller: owner,
+            sale_type,
+            price: terms.price,
+            amount: params.amount,
+            expiry,
+        }),
+    )?;
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct CreateMysteryBoxParams {
+    price: Amount,
+    drop_id: Option<u64>,
+    pool: Vec<TokenInfo>,
+    commitment: [u8; 32],
+    reveal_deadline: u64,
+}
+
+/// Creates a mystery box. The attached amount is the creator's deposit, at
+/// least the configured minimum, returned on reveal and slashed if the reveal
+/// deadline passes.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "create_mystery_box",
+    parameter = "CreateMysteryBoxParams",
+    return_value = "u64",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn create_mystery_box<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u64> {
+    let params: CreateMysteryBoxParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let creator = ensure_account_sender(ctx)?;
+
+    ensure!(
+        !params.pool.is_empty() && params.pool.len() <= MAX_BATCH_SIZE,
+        MarketplaceError::BatchTooLarge
+    );
+    ensure!(
+        amount > Amount::zero() && amount >= host.state().min_reveal_deposit,
+        MarketplaceError::InsufficientDeposit
+    );
+    for (i, item) in params.pool.iter().enumerate() {
+        ensure!(!params.pool[i + 1..].contains(item), MarketplaceError::InvalidOffer);
+    }
+    if let Some(drop_id) = params.drop_id {
+        let drop = host
+            .state()
+            .drops
+            .get(&drop_id)
+            .ok_or(MarketplaceError::DropNotFound)?;
+        ensure!(drop.creator == creator, MarketplaceError::Unauthorized);
+    }
+
+    for collection in distinct_collections(params.pool.iter()) {
+        ensure_is_operator(host, ctx, &collection)?;
+    }
+    for item in &params.pool {
+        ensure!(
+            host.state().tokens.get(item).is_none_or(|token_state| {
+                token_state.curr_state == TokenListState::UnListed
+            }),
+            MarketplaceError::InvalidOffer
+        );
+        Cis2Client::transfer(
+            host,
+            item.id,
+            item.address,
+            TokenAmountU64(1),
+            Address::Account(creator),
+            custody(ctx),
+            custody_data(),
+        )
+        .map_err(MarketplaceError::from)?;
+    }
+
+    let box_id = host.state().next_mystery_box_id;
+    host.state_mut().next_mystery_box_id += 1;
+
+    let items = params.pool.len() as u32;
+    let opened = vec![false; params.pool.len()];
+    let _ = host.state_mut().mystery_boxes.insert(
+        box_id,
+        MysteryBox {
+            creator,
+            price: params.price,
+            drop_id: params.drop_id,
+            pool: params.pool,
+            commitment: randomness::Commitment {
+                committer: creator,
+                hash: params.commitment,
+                deposit: amount,
+                reveal_deadline: params.reveal_deadline,
+            },
+            entropy: [0u8; 32],
+            buyers: Vec::new(),
+            opened,
+            revealed: false,
+        },
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::MysteryBoxCreated(MysteryBoxCreatedEvent {
+            box_id,
+            creator,
+            price: params.price,
+            items,
+        }),
+    )?;
+
+    ContractResult::Ok(box_id)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct BuyMysteryBoxParams {
+    box_id: u64,
+    allowlist_proof: Vec<[u8; 32]>,
+    commitment: [u8; 32],
+}
+
+/// Buys the next box. `commitment` is the hash of a secret the buyer reveals
+/// through `reveal_box_secret` after the creator's seed, so that the creator
+/// cannot know the shuffle in advance. Any excess payment is returned through
+/// the refund ledger; the creator is paid when the box is opened.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "buy_mystery_box",
+    parameter = "BuyMysteryBoxParams",
+    return_value = "u32",
+    mutable,
+    payable,
+    enable_logger,
+    crypto_primitives
+)]
+fn buy_mystery_box<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<u32> {
+    let params: BuyMysteryBoxParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let buyer = ensure_account_sender(ctx)?;
+
+    let mut mystery_box = host
+        .state()
+        .mystery_boxes
+        .get(&params.box_id)
+        .ok_or(MarketplaceError::MysteryBoxNotFound)?
+        .to_owned();
+    ensure!(
+        !mystery_box.revealed && host.state().box_seeded_at.get(&params.box_id).is_none(),
+        MarketplaceError::AlreadyRevealed
+    );
+    ensure!(mystery_box.buyers.len() < mystery_box.pool.len(), MarketplaceError::SoldOut);
+    ensure!(buyer != mystery_box.creator, MarketplaceError::SelfTrade);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut price = mystery_box.price;
+    if let Some(drop_id) = mystery_box.drop_id {
+        if let Some(tranche_price) = host.state_mut().record_drop_purchase(
+            crypto_primitives,
+            drop_id,
+            buyer,
+            &params.allowlist_proof,
+            now,
+        )? {
+            price = tranche_price;
+        }
+    }
+    ensure!(amount >= price, MarketplaceError::NotEnoughBalance);
+
+    let mut input = Vec::with_capacity(72);
+    input.extend_from_slice(&mystery_box.entropy);
+    input.extend_from_slice(&buyer.0);
+    input.extend_from_slice(&now.to_le_bytes());
+    mystery_box.entropy = crypto_primitives.hash_sha2_256(&input).0;
+
+    let index = mystery_box.buyers.len() as u32;
+    mystery_box.buyers.push(buyer);
+
+    if amount > price {
+        host.state_mut().credit_refund(buyer, amount - price, now);
+    }
+    let _ = host.state_mut().box_purchases.insert(
+        (params.box_id, index),
+        BoxPurchase {
+            price,
+            commitment: Some(params.commitment),
+        },
+    );
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::MysteryBoxPurchased(MysteryBoxPurchasedEvent {
+            box_id: params.box_id,
+            index,
+            buyer,
+            price,
+        }),
+    )?;
+
+    let _ = host.state_mut().mystery_boxes.insert(params.box_id, mystery_box);
+    ContractResult::Ok(index)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct RevealMysteryBoxParams {
+    box_id: u64,
+    seed: Option<[u8; 32]>,
+}
+
+/// Closes sales of a mystery box and mixes the seed into its entropy. The
+/// creator reveals the committed seed before the deadline; after it anyone may
+/// reveal without a seed, slashing the creator's deposit. The pool is shuffled
+/// once every buyer has revealed their secret or `BOX_SECRET_REVEAL_WINDOW`
+/// has passed.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "reveal_mystery_box",
+    parameter = "RevealMysteryBoxParams",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn reveal_mystery_box<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<()> {
+    let params: RevealMysteryBoxParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let mut mystery_box = host
+        .state()
+        .mystery_boxes
+        .get(&params.box_id)
+        .ok_or(MarketplaceError::MysteryBoxNotFound)?
+        .to_owned();
+    ensure!(
+        !mystery_box.revealed && host.state().box_seeded_at.get(&params.box_id).is_none(),
+        MarketplaceError::AlreadyRevealed
+    );
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let commitment = &mystery_box.commitment;
+    match &params.seed {
+        Some(seed) => {
+            ensure!(
+                ctx.sender().matches_account(&mystery_box.creator),
+                MarketplaceError::Unauthorized
+            );
+            commitment.verify(crypto_primitives, seed, now)?;
+            host.state_mut().credit_refund(commitment.committer, commitment.deposit, now);
+        }
+        None => {
+            ensure!(commitment.is_forfeit(now), MarketplaceError::InvalidReveal);
+            host.state_mut().fee_pool += commitment.deposit;
+        }
+    }
+    mystery_box.entropy =
+        randomness::mix(crypto_primitives, params.seed.as_ref(), &mystery_box.entropy);
+    let _ = host.state_mut().box_seeded_at.insert(params.box_id, now);
+    if host.state().pending_box_secrets(params.box_id, &mystery_box) == 0 {
+        host.state_mut().shuffle_box(crypto_primitives, params.box_id, &mut mystery_box);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::MysteryBoxRevealed(MysteryBoxRevealedEvent {
+            box_id: params.box_id,
+            seed: params.seed,
+            boxes_sold: mystery_box.buyers.len() as u32,
+        }),
+    )?;
+
+    let _ = host.state_mut().mystery_boxes.insert(params.box_id, mystery_box);
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct RevealBoxSecretParams {
+    box_id: u64,
+    index: u32,
+    secret: [u8; 32],
+}
+
+/// Mixes a buyer's secret into the entropy of a mystery box whose seed has
+/// been revealed. Shuffles the pool once it is the last secret outstanding.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "reveal_box_secret",
+    parameter = "RevealBoxSecretParams",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn reveal_box_secret<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<()> {
+    let params: RevealBoxSecretParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let mut mystery_box = host
+        .state()
+        .mystery_boxes
+        .get(&params.box_id)
+        .ok_or(MarketplaceError::MysteryBoxNotFound)?
+        .to_owned();
+    ensure!(!mystery_box.revealed, MarketplaceError::AlreadyRevealed);
+    ensure!(
+        mystery_box
+            .buyers
+            .get(params.index as usize)
+            .is_some_and(|buyer| ctx.sender().matches_account(buyer)),
+        MarketplaceError::Unauthorized
+    );
+    let seeded_at = *host
+        .state()
+        .box_seeded_at
+        .get(&params.box_id)
+        .ok_or(MarketplaceError::NotRevealed)?;
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    ensure!(
+        now <= seeded_at.saturating_add(BOX_SECRET_REVEAL_WINDOW),
+        MarketplaceError::RevealDeadlinePassed
+    );
+
+    {
+        let mut purchase = host
+            .state_mut()
+            .box_purchases
+            .get_mut(&(params.box_id, params.index))
+            .ok_or(MarketplaceError::InvalidReveal)?;
+        ensure!(
+            purchase.commitment == Some(randomness::commit(crypto_primitives, &params.secret)),
+            MarketplaceError::InvalidReveal
+        );
+        purchase.commitment = None;
+    }
+    mystery_box.entropy =
+        randomness::mix(crypto_primitives, Some(&params.secret), &mystery_box.entropy);
+    if host.state().pending_box_secrets(params.box_id, &mystery_box) == 0 {
+        host.state_mut().shuffle_box(crypto_primitives, params.box_id, &mut mystery_box);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BoxSecretRevealed(BoxSecretRevealedEvent {
+            box_id: params.box_id,
+            index: params.index,
+            shuffled: mystery_box.revealed,
+        }),
+    )?;
+
+    let _ = host.state_mut().mystery_boxes.insert(params.box_id, mystery_box);
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct OpenMysteryBoxParams {
+    box_id: u64,
+    index: u32,
+}
+
+/// Hands out an item of a shuffled mystery box and pays its price to the
+/// creator, as a primary sale of that item. Shuffles the pool first if the
+/// window for buyer secrets has passed without all of them revealed.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "open_mystery_box",
+    parameter = "OpenMysteryBoxParams",
+    mutable,
+    enable_logger,
+    crypto_primitives
+)]
+fn open_mystery_box<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> ContractResult<()> {
+    let params: OpenMysteryBoxParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let mut mystery_box = host
+        .state()
+        .mystery_boxes
+        .get(&params.box_id)
+        .ok_or(MarketplaceError::MysteryBoxNotFound)?
+        .to_owned();
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    if !mystery_box.revealed {
+        let seeded_at = *host
+            .state()
+            .box_seeded_at
+            .get(&params.box_id)
+            .ok_or(MarketplaceError::NotRevealed)?;
+        ensure!(
+            now > seeded_at.saturating_add(BOX_SECRET_REVEAL_WINDOW),
+            MarketplaceError::NotRevealed
+        );
+        host.state_mut().shuffle_box(crypto_primitives, params.box_id, &mut mystery_box);
+    }
+
+    let index = params.index as usize;
+    ensure!(index < mystery_box.pool.len(), MarketplaceError::MysteryBoxNotFound);
+    ensure!(!mystery_box.opened[index], MarketplaceError::AlreadyOpened);
+
+    let recipient = mystery_box
+        .buyers
+        .get(index)
+        .copied()
+        .unwrap_or(mystery_box.creator);
+    let item = mystery_box.pool[index].clone();
+    mystery_box.opened[index] = true;
+
+    Cis2Client::transfer(
+        host,
+        item.id,
+        item.address,
+        TokenAmountU64(1),
+        Address::Contract(ctx.self_address()),
+        concordium_cis2::Receiver::Account(recipient),
+        AdditionalData::empty(),
+    )
+    .map_err(MarketplaceError::from)?;
+
+    if index < mystery_box.buyers.len() {
+        let purchase = host
+            .state_mut()
+            .box_purchases
+            .remove_and_get(&(params.box_id, params.index));
+        if let Some(purchase) = purchase {
+            let listing = TokenState::unlisted(mystery_box.creator);
+            distribute_proceeds(host, logger, &item, &listing, recipient, purchase.price, now)?;
+        }
+        let mut token_state = host
+            .state()
+            .tokens
+            .get(&item)
+            .map(|token_state| token_state.to_owned())
+            .unwrap_or_else(|| TokenState::unlisted(recipient));
+        token_state.owner = recipient;
+        token_state.sold_at = now;
+        let _ = host.state_mut().tokens.insert(item.clone(), token_state);
+    }
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::MysteryBoxOpened(MysteryBoxOpenedEvent {
+            box_id: params.box_id,
+            index: params.index,
+            recipient,
+            nft_contract_address: item.address,
+            token_id: item.id,
+        }),
+    )?;
+
+    let _ = host.state_mut().mystery_boxes.insert(params.box_id, mystery_box);
+    ContractResult::Ok(())
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct CreateCurveSaleParams {
+    nft_contract_address: ContractAddress,
+    first_token_id: u32,
+    supply: u32,
+    curve: PriceCurve,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "create_curve_sale",
+    parameter = "CreateCurveSaleParams",
+    return_value = "u64",
+    mutable,
+    enable_logger
+)]
+fn create_curve_sale<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u64> {
+    let params: CreateCurveSaleParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let creator = ensure_account_sender(ctx)?;
+
+    ensure!(params.supply > 0, MarketplaceError::InvalidCurve);
+    ensure!(
+        params.first_token_id.checked_add(params.supply - 1).is_some(),
+        MarketplaceError::InvalidCurve
+    );
+    if let PriceCurve::Step { step_size, .. } = params.curve {
+        ensure!(step_size > 0, MarketplaceError::InvalidCurve);
+    }
+
+    ensure_supports_cis2(host, &params.nft_contract_address)?;
+    ensure_is_operator(host, ctx, &params.nft_contract_address)?;
+
+    let sale_id = host.state().next_curve_sale_id;
+    host.state_mut().next_curve_sale_id += 1;
+    let _ = host.state_mut().curve_sales.insert(
+        sale_id,
+        CurveSale {
+            creator,
+            nft_contract_address: params.nft_contract_address,
+            first_token_id: params.first_token_id,
+            supply: params.supply,
+            sold: 0,
+            curve: params.curve,
+        },
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::CurveSaleCreated(CurveSaleCreatedEvent {
+            sale_id,
+            creator,
+            nft_contract_address: params.nft_contract_address,
+            supply: params.supply,
+        }),
+    )?;
+
+    ContractResult::Ok(sale_id)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_curve_price",
+    parameter = "u64",
+    return_value = "Amount"
+)]
+fn view_curve_price<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Amount> {
+    let sale_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let sale = host
+        .state()
+        .curve_sales
+        .get(&sale_id)
+        .ok_or(MarketplaceError::CurveSaleNotFound)?;
+    ensure!(sale.sold < sale.supply, MarketplaceError::SoldOut);
+
+    ContractResult::Ok(sale.curve.price_at(sale.sold))
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "buy_from_curve",
+    parameter = "u64",
+    return_value = "SettlementReceipt",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn buy_from_curve<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<SettlementReceipt> {
+    let sale_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let buyer = ensure_account_sender(ctx)?;
+
+    let mut sale = host
+        .state()
+        .curve_sales
+        .get(&sale_id)
+        .ok_or(MarketplaceError::CurveSaleNotFound)?
+        .to_owned();
+    ensure!(sale.sold < sale.supply, MarketplaceError::SoldOut);
+    ensure!(buyer != sale.creator, MarketplaceError::SelfTrade);
+
+    let price = sale.curve.price_at(sale.sold);
+    ensure!(amount >= price, MarketplaceError::InvalidAmountPaid);
+
+    let info = TokenInfo::new(
+        TokenIdU32(sale.first_token_id + sale.sold),
+        sale.nft_contract_address,
+    );
+    sale.sold += 1;
+
+    Cis2Client::transfer(
+        host,
+        info.id,
+        info.address,
+        TokenAmountU64(1),
+        Address::Account(sale.creator),
+        concordium_cis2::Receiver::Account(buyer),
+        AdditionalData::empty(),
+    )
+    .map_err(MarketplaceError::from)?;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let listing = TokenState::unlisted(sale.creator);
+    let receipt = distribute_proceeds(host, logger, &info, &listing, buyer, price, now)?;
+    if amount > price {
+        host.state_mut().credit_refund(buyer, amount - price, now);
+    }
+
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .map(|token_state| token_state.to_owned())
+        .unwrap_or_else(|| TokenState::unlisted(sale.creator));
+    if token_state.curr_state == TokenListState::Listed {
+        host.state_mut().cancel_listing(&info, &mut token_state, now);
+    }
+    token_state.owner = buyer;
+    token_state.sold_at = now;
+    let _ = host.state_mut().tokens.insert(info, token_state);
+    let _ = host.state_mut().curve_sales.insert(sale_id, sale);
+    ContractResult::Ok(receipt)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct PlaceBuyOrderParams {
+    collection: ContractAddress,
+    data: AdditionalData,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "place_buy_order",
+    parameter = "PlaceBuyOrderParams",
+    return_value = "u64",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn place_buy_order<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u64> {
+    let params: PlaceBuyOrderParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+    let collection = params.collection;
+
+    host.state().ensure_not_paused()?;
+    let buyer = ensure_account_sender(ctx)?;
+    ensure!(amount > Amount::zero(), MarketplaceError::InvalidAmountPaid);
+    ensure_supports_cis2(host, &collection)?;
+
+    let order_id = host.state().next_buy_order_id;
+    host.state_mut().next_buy_order_id += 1;
+    host.state_mut().insert_buy_order(
+        order_id,
+        BuyOrder {
+            buyer,
+            collection,
+            price: amount,
+            data: params.data,
+        },
+    );
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BuyOrderPlaced(BuyOrderEvent {
+            order_id,
+            buyer,
+            collection,
+            price: amount,
+        }),
+    )?;
+
+    ContractResult::Ok(order_id)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "cancel_buy_order",
+    parameter = "u64",
+    mutable,
+    enable_logger
+)]
+fn cancel_buy_order<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let order_id: u64 = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let order = host
+        .state()
+        .buy_orders
+        .get(&order_id)
+        .ok_or(MarketplaceError::BuyOrderNotFound)?
+        .to_owned();
+    ensure!(
+        ctx.sender().matches_account(&order.buyer),
+        MarketplaceError::Unauthorized
+    );
+    host.state_mut().remove_buy_order(order_id);
+
+    host.invoke_transfer(&order.buyer, order.price)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BuyOrderCancelled(BuyOrderEvent {
+            order_id,
+            buyer: order.buyer,
+            collection: order.collection,
+            price: order.price,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "view_best_buy_order",
+    parameter = "ContractAddress",
+    return_value = "Option<(u64, Amount)>"
+)]
+fn view_best_buy_order<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> ContractResult<Option<(u64, Amount)>> {
+    let collection: ContractAddress = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    let state = host.state();
+    let best = state.best_buy_order(&collection).and_then(|order_id| {
+        state
+            .buy_orders
+            .get(&order_id)
+            .map(|order| (order_id, order.price))
+    });
+
+    ContractResult::Ok(best)
+}
+
+#[derive(Serial, Deserial, SchemaType)]
+struct SellIntoBidParams {
+    nft_contract_address: ContractAddress,
+    token_id: ContractTokenId,
+    min_price: Amount,
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "sell_into_bid",
+    parameter = "SellIntoBidParams",
+    return_value = "SettlementReceipt",
+    mutable,
+    enable_logger
+)]
+fn sell_into_bid<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<SettlementReceipt> {
+    let params: SellIntoBidParams = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    let seller = ensure_account_sender(ctx)?;
+
+    let order_id = host
+        .state()
+        .best_buy_order(&params.nft_contract_address)
+        .ok_or(MarketplaceError::NoBuyOrders)?;
+    let order = host
+        .state_mut()
+        .remove_buy_order(order_id)
+        .ok_or(MarketplaceError::BuyOrderNotFound)?;
+    ensure!(order.price >= params.min_price, MarketplaceError::NotEnoughBalance);
+    ensure!(order.buyer != seller, MarketplaceError::SelfTrade);
+
+    ensure_balance(host, params.token_id, &params.nft_contract_address, ctx, TokenAmountU64(1))?;
+    ensure_is_operator(host, ctx, &params.nft_contract_address)?;
+
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
+    Cis2Client::transfer(
+        host,
+        info.id,
+        info.address,
+        TokenAmountU64(1),
+        Address::Account(seller),
+        concordium_cis2::Receiver::Account(order.buyer),
+        order.data.clone(),
+    )
+    .map_err(MarketplaceError::from)?;
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut token_state = host
+        .state()
+        .tokens
+        .get(&info)
+        .map(|token_state| token_state.to_owned())
+        .unwrap_or_else(|| TokenState::unlisted(seller));
+    if token_state.curr_state == TokenListState::Listed {
+        host.state_mut().cancel_listing(&info, &mut token_state, now);
+    }
+    token_state.owner = seller;
+
+    let receipt = distribute_proceeds(host, logger, &info, &token_state, order.buyer, order.price, now)?;
+
+    token_state.owner = order.buyer;
+    token_state.sold_at = now;
+    let _ = host.state_mut().tokens.insert(info, token_state);
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BuyOrderFilled(BuyOrderEvent {
+            order_id,
+            buyer: order.buyer,
+            collection: order.collection,
+            price: order.price,
+        }),
+    )?;
+
+    ContractResult::Ok(receipt)
+}
+
+fn deliver_and_settle_auction<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<SettlementReceipt> {
+    deliver_auctioned_token(host, info, token_state)?;
+    settle_auction(host, logger, info, token_state, now)
+}
+
+/// Sends an escrowed token back to its owner ahead of closing the listing
+/// without a sale; listings that never took custody are left alone.
+fn return_escrowed_token<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    info: &TokenInfo,
+    token_state: &TokenState,
+) -> ContractResult<()> {
+    if token_state.custodian.is_none() {
+        return Ok(());
+    }
+    Cis2Client::transfer(
+        host,
+        info.id,
+        info.address,
+        token_state.amount,
+        token_state.holder(),
+        concordium_cis2::Receiver::Account(token_state.owner),
+        AdditionalData::empty(),
+    )
+    .map_err(MarketplaceError::from)?;
+    Ok(())
+}
+
+fn deliver_auctioned_token<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    info: &TokenInfo,
+    token_state: &TokenState,
+) -> ContractResult<()> {
+    Cis2Client::transfer(
+        host,
+        info.id,
+        info.address,
+        token_state.amount,
+        token_state.holder(),
+        concordium_cis2::Receiver::Account(token_state.highest_bidder),
+        token_state.settlement_data.clone(),
+    )
+    .map_err(MarketplaceError::from)?;
+    Ok(())
+}
+
+/// Settles an auction bid in a CIS-2 token once the token is delivered; the
+/// winning bid is already in custody.
+fn settle_token_auction<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    payment: PaymentToken,
+    now: u64,
+) -> ContractResult<TokenSettlementReceipt> {
+    let winner = token_state.highest_bidder;
+    let receipt = settle_token_sale(host, logger, info, token_state, payment, winner, true, now)?;
+
+    token_state.owner = winner;
+    token_state.sold_at = now;
+    host.state_mut().close_listing(info, token_state, now);
+    Ok(receipt)
+}
+
+/// Turns an expired auction without bids into a fixed-price listing at the
+/// higher of its price and reserve, if the seller opted in. Returns whether
+/// the listing was converted.
+fn convert_bidless_auction<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<bool> {
+    if !token_state.should_convert_to_fixed(now) {
+        return Ok(false);
+    }
+
+    host.state_mut().release_participants(info, token_state, now);
+    token_state.sale_type = TokenSaleTypeState::Fixed;
+    token_state.price = cmp::max(token_state.price, token_state.reserve);
+    token_state.expiry = 0;
+    token_state.bid_count = 0;
+    token_state.buy_now = None;
+    token_state.participation_deposit = Amount::zero();
+    token_state.convert_to_fixed = false;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::AuctionConverted(AuctionConvertedEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            price: token_state.price,
+        }),
+    )?;
+    Ok(true)
+}
+
+/// Pushes back the expiry of an anti-sniping auction that received a bid
+/// close to its end.
+fn extend_for_late_bid<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<()> {
+    let anti_snipe = match &token_state.anti_snipe {
+        Some(anti_snipe) => anti_snipe,
+        None => return Ok(()),
+    };
+    if token_state.expiry.saturating_sub(now) > anti_snipe.threshold {
+        return Ok(());
+    }
+    let old_expiry = token_state.expiry;
+    token_state.expiry = old_expiry.saturating_add(anti_snipe.extension);
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Extended(AuctionRescheduledEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            old_expiry,
+            new_expiry: token_state.expiry,
+        }),
+    )?;
+    Ok(())
+}
+
+fn bid_outcome<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<TradeResult> {
+    let bid = BidResult {
+        nft_contract_address: info.address,
+        token_id: info.id,
+        bidder: token_state.highest_bidder,
+        amount: token_state.price,
+        bid_count: token_state.bid_count,
+        expiry: token_state.expiry,
+    };
+    match settle_if_buy_now(host, logger, info, token_state, now)? {
+        Some(receipt) => Ok(TradeResult::BuyNowSettled(receipt)),
+        None => Ok(TradeResult::BidPlaced(bid)),
+    }
+}
+
+fn settle_if_buy_now<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<Option<SettlementReceipt>> {
+    match token_state.buy_now {
+        Some(buy_now) if token_state.price >= buy_now => {}
+        _ => return Ok(None),
+    }
+
+    let receipt = deliver_and_settle_auction(host, logger, info, token_state, now)?;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BuyNowSettled(BuyNowSettledEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            seller: receipt.seller,
+            buyer: receipt.buyer,
+            price: receipt.price,
+        }),
+    )?;
+
+    Ok(Some(receipt))
+}
+
+fn forfeit_bond<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<()> {
+    if let Some(bond) = token_state.bond.take() {
+        host.state_mut().credit_refund(token_state.highest_bidder, bond.amount, now);
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::BondForfeited(BondForfeitedEvent {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                seller: bond.account,
+                bidder: token_state.highest_bidder,
+                amount: bond.amount,
+            }),
+        )?;
+    }
+    Ok(())
+}
+
+fn settle_auction<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &mut TokenState,
+    now: u64,
+) -> ContractResult<SettlementReceipt> {
+    let receipt = distribute_proceeds(
+        host,
+        logger,
+        info,
+        token_state,
+        token_state.highest_bidder,
+        token_state.price,
+        now,
+    )?;
+
+    token_state.owner = token_state.highest_bidder;
+    token_state.sold_at = now;
+    host.state_mut().close_listing(info, token_state, now);
+    host.state_mut()
+        .log_event(logger, MarketplaceEvent::Finalized(receipt.sale_event()))?;
+    Ok(receipt)
+}
+
+/// Logs an accepted bid and the running summary of its auction.
+fn log_bid<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &TokenState,
+    now: u64,
+) -> ContractResult<()> {
+    let event = match &token_state.currency {
+        Currency::Ccd => MarketplaceEvent::BidPlaced(BidPlacedEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            seller: token_state.owner,
+            bidder: token_state.highest_bidder,
+            price: token_state.price,
+        }),
+        Currency::Cis2(payment) => MarketplaceEvent::TokenBidPlaced(TokenBidEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            seller: token_state.owner,
+            bidder: token_state.highest_bidder,
+            payment: payment.clone(),
+        }),
+    };
+    host.state_mut().log_event(logger, event)?;
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::BidHeartbeat(BidHeartbeatEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            highest_bidder: token_state.highest_bidder,
+            highest_bid: token_state.highest_bid(),
+            bid_count: token_state.bid_count,
+            time_remaining: token_state.expiry.saturating_sub(now),
+        }),
+    )
+}
+
+fn log_cancelled<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    token_state: &TokenState,
+) -> ContractResult<()> {
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Cancelled(CancelledEvent {
+            nft_contract_address: info.address,
+            token_id: info.id,
+            seller: token_state.owner,
+        }),
+    )
+}
+
+fn distribute_proceeds<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    listing: &TokenState,
+    buyer: AccountAddress,
+    price: Amount,
+    now: u64,
+) -> ContractResult<SettlementReceipt> {
+    let seller = listing.owner;
+    let royalty = effective_royalty(host, info, listing)?;
+    let breakdown = host.state().proceeds_breakdown(listing, royalty.as_ref(), price, now);
+
+    {
+        let state = host.state_mut();
+        if let Some(referrer) = listing.referrer {
+            *state.referral_earnings.entry(referrer).or_insert(Amount::zero()) +=
+                breakdown.referral_fee;
+        }
+        state.fee_pool += breakdown.platform_fee - breakdown.referral_fee;
+        state.record_seller_volume(seller, price, now);
+        state.reports.entry(seller).or_default().fees_paid += breakdown.platform_fee;
+    }
+
+    if breakdown.compliance_withholding > Amount::zero() {
+        let account = host.state().compliance.as_ref().unwrap_abort().account;
+        host.invoke_transfer(&account, breakdown.compliance_withholding)
+            .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::ComplianceWithheld(ComplianceWithheldEvent {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                seller,
+                account,
+                amount: breakdown.compliance_withholding,
+            }),
+        )?;
+    }
+
+    if breakdown.royalty > Amount::zero() {
+        let account = royalty.as_ref().unwrap_abort().account;
+        host.invoke_transfer(&account, breakdown.royalty)
+            .map_err(|_| MarketplaceError::InvokeTransferError)?;
+        host.state_mut().reports.entry(account).or_default().royalties_received += breakdown.royalty;
+
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::RoyaltyPaid(RoyaltyPaidEvent {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                seller,
+                account,
+                amount: breakdown.royalty,
+            }),
+        )?;
+    }
+
+    if breakdown.donation > Amount::zero() {
+        let charity = listing.charity.as_ref().unwrap_abort().account;
+        host.invoke_transfer(&charity, breakdown.donation)
+            .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::DonationPaid(DonationPaidEvent {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                seller,
+                charity,
+                amount: breakdown.donation,
+            }),
+        )?;
+    }
+
+    if let Some(keeper) = listing.keeper {
+        host.state_mut().credit_refund(keeper, breakdown.keeper_reward, now);
+    }
+
+    let seller_proceeds = host
+        .state_mut()
+        .settle_penalty_debts(seller, breakdown.seller_proceeds, now);
+    match &listing.arbiter {
+        Some(terms) => {
+            let escrow = Escrow {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                seller,
+                buyer,
+                arbiter: terms.arbiter,
+                amount: seller_proceeds,
+                claimable_at: now.saturating_add(terms.timeout),
+            };
+            let escrow_id = host.state().next_escrow_id;
+            host.state_mut().next_escrow_id += 1;
+            host.state_mut().log_event(
+                logger,
+                MarketplaceEvent::EscrowOpened(escrow.event(escrow_id)),
+            )?;
+            let _ = host.state_mut().escrows.insert(escrow_id, escrow);
+        }
+        None => {
+            host.invoke_transfer(&seller, seller_proceeds)
+                .map_err(|_| MarketplaceError::InvokeTransferError)?;
+        }
+    }
+    host.state_mut().record_sale(seller, buyer, price);
+    host.state_mut().accrue_loyalty(buyer, price);
+
+    mint_receipt(host, logger, info, seller, buyer, price, None)?;
+
+    let receipt = SettlementReceipt {
+        nft_contract_address: info.address,
+        token_id: info.id,
+        seller,
+        buyer,
+        price,
+        fees: breakdown.platform_fee + breakdown.compliance_withholding,
+        royalty: breakdown.royalty,
+        donation: breakdown.donation,
+        seller_proceeds,
+    };
+    host.state_mut()
+        .log_event(logger, MarketplaceEvent::Purchased(receipt.sale_event()))?;
+    Ok(receipt)
+}
+
+/// Settles a sale priced in a CIS-2 token. The shares are those of a CCD
+/// sale of the same amount, paid in the token: the buyer's tokens are moved
+/// straight to the payees, or, if `escrowed` in the marketplace's custody as a
+/// winning bid, credited to them. The platform fee goes to the fee account,
+/// since the fee pool only holds CCD, and to the seller while none is
+/// configured. Penalty debts are owed in CCD: a seller who has any cannot sell
+/// for tokens, and cannot claim the credited proceeds of a won auction until
+/// the debts are paid.
+#[allow(clippy::too_many_arguments)]
+fn settle_token_sale<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    listing: &TokenState,
+    payment: PaymentToken,
+    buyer: AccountAddress,
+    escrowed: bool,
+    now: u64,
+) -> ContractResult<TokenSettlementReceipt> {
+    let seller = listing.owner;
+    ensure!(
+        escrowed || host.state().penalty_debts.get(&seller).is_none(),
+        MarketplaceError::OutstandingPenaltyDebt
+    );
+    let royalty_share = effective_royalty(host, info, listing)?;
+    let state = host.state();
+    let breakdown = state.proceeds_breakdown(
+        listing,
+        royalty_share.as_ref(),
+        Amount::from_micro_ccd(payment.amount.0),
+        now,
+    );
+    let in_token = |amount: Amount| TokenAmountU64(amount.micro_ccd);
+    let fee_account = state.fee_account;
+    let mut fees = in_token(breakdown.platform_fee - breakdown.referral_fee);
+    let mut seller_proceeds = in_token(breakdown.seller_proceeds);
+    if fee_account.is_none() {
+        seller_proceeds.0 += fees.0;
+        fees = TokenAmountU64(0);
+    }
+    let compliance_account = state.compliance.as_ref().map(|compliance| compliance.account);
+
+    let payees = [
+        (fee_account, fees),
+        (listing.referrer, in_token(breakdown.referral_fee)),
+        (compliance_account, in_token(breakdown.compliance_withholding)),
+        (royalty_share.map(|royalty| royalty.account), in_token(breakdown.royalty)),
+        (listing.charity.as_ref().map(|charity| charity.account), in_token(breakdown.donation)),
+        (listing.keeper, in_token(breakdown.keeper_reward)),
+        (Some(seller), seller_proceeds),
+    ];
+    for (account, share) in payees {
+        if let (Some(account), true) = (account, share.0 > 0) {
+            if escrowed {
+                host.state_mut().credit_token(account, &payment, share);
+                continue;
+            }
+            Cis2Client::transfer(
+                host,
+                payment.token_id.clone(),
+                payment.contract,
+                share,
+                Address::Account(buyer),
+                concordium_cis2::Receiver::Account(account),
+                AdditionalData::empty(),
+            )
+            .map_err(MarketplaceError::from)?;
+        }
+    }
+    // Token sales count towards reputation but not towards CCD volumes.
+    host.state_mut().record_sale(seller, buyer, Amount::zero());
+    mint_receipt(host, logger, info, seller, buyer, Amount::zero(), Some(payment.clone()))?;
+
+    let receipt = TokenSettlementReceipt {
+        nft_contract_address: info.address,
+        token_id: info.id,
+        seller,
+        buyer,
+        payment,
+        fees: TokenAmountU64(
+            fees.0 + in_token(breakdown.referral_fee + breakdown.compliance_withholding).0,
+        ),
+        royalty: in_token(breakdown.royalty),
+        seller_proceeds,
+    };
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::PurchasedWithToken(receipt.clone()),
+    )?;
+    Ok(receipt)
+}
+
+/// Mints a receipt of the sale through the configured receipt contract. The
+/// sale stands if the mint fails; the failure is logged instead.
+#[allow(clippy::too_many_arguments)]
+fn mint_receipt<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+    info: &TokenInfo,
+    seller: AccountAddress,
+    buyer: AccountAddress,
+    price: Amount,
+    payment: Option<PaymentToken>,
+) -> ContractResult<()> {
+    let receipt = match host.state().receipt.clone() {
+        Some(receipt) => receipt,
+        None => return Ok(()),
+    };
+    let params = MintReceiptParams {
+        owner: buyer,
+        nft_contract_address: info.address,
+        token_id: info.id,
+        seller,
+        price,
+        payment,
+    };
+    let minted = host.invoke_contract(
+        &receipt.contract,
+        &params,
+        receipt.entrypoint.as_entrypoint_name(),
+        Amount::zero(),
+    );
+    if minted.is_err() {
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::ReceiptMintFailed(ReceiptMintFailedEvent {
+                nft_contract_address: info.address,
+                token_id: info.id,
+                buyer,
+            }),
+        )?;
+    }
+    Ok(())
+}
+
+/// The royalty a sale pays: whatever the collection reports through the
+/// CIS-2 royalty extension, or the listing's own royalty if the collection
+/// does not implement it.
+fn effective_royalty<S: HasStateApi>(
+    host: &impl HasHost<State<S>, StateApiType = S>,
+    info: &TokenInfo,
+    listing: &TokenState,
+) -> ContractResult<Option<Royalty>> {
+    let royalty = match Cis2Client::royalty_info(host, info.id, &info.address)? {
+        Some(royalty) => Some(Royalty {
+            account: royalty.receiver,
+            rate_bps: cmp::min(royalty.rate_bps, BASIS_POINTS),
+        }),
+        None => listing.royalty.clone(),
+    };
+    Ok(royalty)
+}
+
+/// Price of `quantity` units at `unit_price` each.
+fn price_of(unit_price: Amount, quantity: ContractTokenAmount) -> ContractResult<Amount> {
+    let price = unit_price
+        .micro_ccd
+        .checked_mul(quantity.0)
+        .ok_or(MarketplaceError::AmountOverflow)?;
+    Ok(Amount::from_micro_ccd(price))
+}
+
+fn bps_of(amount: Amount, bps: u16) -> Amount {
+    let share = u128::from(amount.micro_ccd) * u128::from(bps) / u128::from(BASIS_POINTS);
+    Amount::from_micro_ccd(share as u64)
+}
+
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "sweep_expired",
+    parameter = "Vec<TokenInfo>",
+    mutable,
+    enable_logger
+)]
+fn sweep_expired<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let tokens: Vec<TokenInfo> = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    ensure!(tokens.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut swept = 0u64;
+
+    for info in tokens {
+        let mut token_state = match host.state().tokens.get(&info) {
+            Some(token_state) => token_state.to_owned(),
+            None => continue,
+        };
+        if token_state.curr_state != TokenListState::Listed || !token_state.is_expired(now) {
+            continue;
+        }
+
+        if convert_bidless_auction(host, logger, &info, &mut token_state, now)? {
+            let _ = host.state_mut().tokens.insert(info, token_state);
+            swept += 1;
+            continue;
+        }
+
+        if token_state.candle.is_some() && token_state.has_bidder() {
+            continue;
+        }
+
+        if token_state.sale_type == TokenSaleTypeState::Auction && token_state.has_bidder() {
+            let delivered = token_state.reserve_met()
+                && Cis2Client::transfer(
+                    host,
+                    info.id,
+                    info.address,
+                    token_state.amount,
+                    token_state.holder(),
+                    concordium_cis2::Receiver::Account(token_state.highest_bidder),
+                    token_state.settlement_data.clone(),
+                )
+                .is_ok();
+            if delivered {
+                match token_state.currency.clone() {
+                    Currency::Ccd => {
+                        settle_auction(host, logger, &info, &mut token_state, now)?;
+                    }
+                    Currency::Cis2(payment) => {
+                        settle_token_auction(host, logger, &info, &mut token_state, payment, now)?;
+                    }
+                }
+            } else {
+                if token_state.reserve_met() {
+                    forfeit_bond(host, logger, &info, &mut token_state, now)?;
+                }
+                host.state_mut().refund_highest_bid(&token_state, now);
+                return_escrowed_token(host, &info, &token_state)?;
+                host.state_mut().close_listing(&info, &mut token_state, now);
+            }
+        } else {
+            return_escrowed_token(host, &info, &token_state)?;
+            host.state_mut().close_listing(&info, &mut token_state, now);
+        }
+
+        let _ = host.state_mut().tokens.insert(info, token_state);
+        swept += 1;
+    }
+
+    let bounty =
+        Amount::from_micro_ccd(host.state().sweep_bounty.micro_ccd.saturating_mul(swept));
+    pay_keeper_bounty(host, &ctx.invoker(), bounty)?;
+
+    ContractResult::Ok(())
+}
+
+/// Cancels the given listings whose seller no longer holds the token or no
+/// longer has the marketplace as operator. Bids are refunded and a posted
+/// bond goes to the highest bidder. Callable by anyone; returns the number
+/// of listings invalidated.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "revalidate",
+    parameter = "Vec<TokenInfo>",
+    return_value = "u32",
+    mutable,
+    enable_logger
+)]
+fn revalidate<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<u32> {
+    let tokens: Vec<TokenInfo> = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    host.state().ensure_not_paused()?;
+    ensure!(tokens.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let listings: Vec<(TokenInfo, TokenState)> = tokens
+        .into_iter()
+        .filter_map(|info| {
+            let token_state = host.state().tokens.get(&info)?.to_owned();
+            // Escrowed tokens cannot leave the seller's control behind our back.
+            (token_state.curr_state == TokenListState::Listed && token_state.custodian.is_none())
+                .then_some((info, token_state))
+        })
+        .collect();
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut invalidated = 0u32;
+    for collection in distinct_collections(listings.iter().map(|(info, _)| info)) {
+        let group: Vec<&(TokenInfo, TokenState)> = listings
+            .iter()
+            .filter(|(info, _)| info.address == collection)
+            .collect();
+        let balance_queries: Vec<BalanceOfQuery<ContractTokenId>> = group
+            .iter()
+            .map(|(info, token_state)| BalanceOfQuery {
+                token_id: info.id,
+                address: Address::Account(token_state.owner),
+            })
+            .collect();
+        let balances: Vec<ContractTokenAmount> =
+            Cis2Client::balance_of_many(host, &collection, &balance_queries)
+                .map_err(MarketplaceError::from)?;
+        let operator_queries: Vec<OperatorOfQuery> = group
+            .iter()
+            .map(|(_, token_state)| OperatorOfQuery {
+                owner: Address::Account(token_state.owner),
+                address: Address::Contract(ctx.self_address()),
+            })
+            .collect();
+        let approvals = Cis2Client::operator_of_many(host, &collection, &operator_queries)
+            .map_err(MarketplaceError::from)?;
+
+        for (((info, token_state), balance), approved) in group.into_iter().zip(balances).zip(approvals) {
+            let reason = if balance < token_state.amount {
+                InvalidationReason::NotHeld
+            } else if !approved {
+                InvalidationReason::NotApproved
+            } else {
+                continue;
+            };
+
+            let mut token_state = token_state.clone();
+            let owner = token_state.owner;
+            if token_state.has_bidder() {
+                forfeit_bond(host, logger, info, &mut token_state, now)?;
+            }
+            host.state_mut().cancel_listing(info, &mut token_state, now);
+            let _ = host.state_mut().tokens.insert(info.clone(), token_state);
+            invalidated += 1;
+
+            host.state_mut().log_event(
+                logger,
+                MarketplaceEvent::ListingInvalidated(ListingInvalidatedEvent {
+                    nft_contract_address: info.address,
+                    token_id: info.id,
+                    owner,
+                    reason,
+                }),
+            )?;
+        }
+    }
+
+    ContractResult::Ok(invalidated)
+}
+
+/// Pushes out the refunds of accounts that have left them unclaimed, paying
+/// the caller a bounty per refund. Refunds younger than `refund_min_age` or
+/// smaller than `refund_min_amount` are skipped; refunds credited before
+/// their age was recorded count as old enough.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "process_refunds",
+    parameter = "Vec<AccountAddress>",
+    mutable,
+    enable_logger
+)]
+fn process_refunds<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    let accounts: Vec<AccountAddress> = ctx
+        .parameter_cursor()
+        .get()
+        .map_err(|_e| MarketplaceError::ParseParams)?;
+
+    ensure!(accounts.len() <= MAX_BATCH_SIZE, MarketplaceError::BatchTooLarge);
+
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    let mut processed = 0u64;
+    for account in accounts {
+        let state = host.state();
+        let refund = match state.refunds.get(&account) {
+            Some(refund) => *refund,
+            None => continue,
+        };
+        let credited_at = state.refund_credited_at.get(&account).map_or(0, |at| *at);
+        if refund == Amount::zero()
+            || refund < state.refund_min_amount
+            || now < credited_at.saturating_add(state.refund_min_age)
+        {
+            continue;
         }
+        host.state_mut().refunds.remove(&account);
+        host.state_mut().refund_credited_at.remove(&account);
+        host.invoke_transfer(&account, refund)
+            .map_err(|_| MarketplaceError::InvokeTransferError)?;
+        host.state_mut().log_event(
+            logger,
+            MarketplaceEvent::Withdrawn(WithdrawalEvent {
+                account,
+                amount: refund,
+                source: WithdrawalSource::Refund,
+            }),
+        )?;
+        processed += 1;
     }
-}
 
-#[init(contract = "Pixpel-NFTMarketplace")]
-fn init<S: HasStateApi>(
-    _ctx: &impl HasInitContext,
-    state_builder: &mut StateBuilder<S>,
-) -> InitResult<State<S>> {
-    Ok(State::new(state_builder))
-}
+    let bounty =
+        Amount::from_micro_ccd(host.state().refund_bounty.micro_ccd.saturating_mul(processed));
+    pay_keeper_bounty(host, &ctx.invoker(), bounty)?;
 
-#[derive(Serial, Deserial, SchemaType)]
-struct PlaceIntoMarketParams {
-    nft_contract_address: ContractAddress,
-    token_id: ContractTokenId,
-    price: Amount,
-    sale_type: u8,
-    expiry: u64,
+    ContractResult::Ok(())
 }
 
+/// Withdraws `amount` out of the sender's balance in the refund ledger,
+/// leaving the rest credited; `claim_all` withdraws everything. Fails with
+/// `NothingToClaim` when nothing is credited, as `claim_all` does, and with
+/// `InsufficientRefund` when `amount` is zero or exceeds the balance.
 #[receive(
     contract = "Pixpel-NFTMarketplace",
-    name = "place_into_market",
-    parameter = "PlaceIntoMarketParams",
-    mutable
+    name = "withdraw_refund",
+    parameter = "Amount",
+    mutable,
+    enable_logger
 )]
-fn add<S: HasStateApi>(
+fn withdraw_refund<S: HasStateApi>(
     ctx: &impl HasReceiveContext,
     host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
 ) -> ContractResult<()> {
-    let params: PlaceIntoMarketParams = ctx
+    let amount: Amount = ctx
         .parameter_cursor()
         .get()
         .map_err(|_e| MarketplaceError::ParseParams)?;
 
-    ensure_supports_cis2(host, &params.nft_contract_address)?;
-    ensure_is_operator(host, ctx, &params.nft_contract_address)?;
-    ensure_balance(host, params.token_id, &params.nft_contract_address, ctx)?;
+    let account = ensure_account_sender(ctx)?;
 
-    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
-    let sale_type;
-    if params.sale_type == 0 {
-        sale_type = TokenSaleTypeState::Fixed;
+    let remaining = {
+        let mut balance = host
+            .state_mut()
+            .refunds
+            .entry(account)
+            .occupied_or(MarketplaceError::NothingToClaim)?;
+        ensure!(*balance > Amount::zero(), MarketplaceError::NothingToClaim);
+        ensure!(
+            amount > Amount::zero() && *balance >= amount,
+            MarketplaceError::InsufficientRefund
+        );
+        *balance -= amount;
+        *balance
+    };
+    if remaining == Amount::zero() {
+        host.state_mut().refunds.remove(&account);
+        host.state_mut().refund_credited_at.remove(&account);
     } else {
-        sale_type = TokenSaleTypeState::Auction;
+        let now = ctx.metadata().slot_time().timestamp_millis();
+        let _ = host.state_mut().refund_credited_at.insert(account, now);
     }
 
-    let curr_state = TokenListState::Listed;
-    let owner = ctx.invoker();
-    let highest_bidder = AccountAddress([0u8; 32]);
-    let expiry = 0u64;
-    let price = params.price;
+    host.invoke_transfer(&account, amount)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
 
-    if host.state_mut().tokens.get(&info).is_some() {
-        let mut token_state = host
-            .state_mut()
-            .tokens
-            .entry(info)
-            .occupied_or(MarketplaceError::TokenNotListed)?;
-        token_state.owner = owner;
-        token_state.highest_bidder = highest_bidder;
-        token_state.sale_type = sale_type;
-        token_state.curr_state = curr_state;
-        token_state.expiry = params.expiry;
-        token_state.price = params.price;
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Withdrawn(WithdrawalEvent {
+            account,
+            amount,
+            source: WithdrawalSource::Refund,
+        }),
+    )?;
+
+    ContractResult::Ok(())
+}
+
+/// Pays out all CCD the marketplace owes the sender: credited refunds and
+/// referral earnings.
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "claim_all",
+    return_value = "Amount",
+    mutable,
+    enable_logger
+)]
+fn claim_all<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<Amount> {
+    let account = ensure_account_sender(ctx)?;
+
+    let state = host.state_mut();
+    let refunds = state.refunds.remove_and_get(&account).unwrap_or_else(Amount::zero);
+    state.refund_credited_at.remove(&account);
+    let earnings = state
+        .referral_earnings
+        .remove_and_get(&account)
+        .unwrap_or_else(Amount::zero);
+    let claimable = refunds + earnings;
+    ensure!(claimable > Amount::zero(), MarketplaceError::NothingToClaim);
+
+    host.invoke_transfer(&account, claimable)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
+
+    for (amount, source) in [
+        (refunds, WithdrawalSource::Refund),
+        (earnings, WithdrawalSource::ReferralEarnings),
+    ] {
+        if amount > Amount::zero() {
+            host.state_mut().log_event(
+                logger,
+                MarketplaceEvent::Withdrawn(WithdrawalEvent {
+                    account,
+                    amount,
+                    source,
+                }),
+            )?;
+        }
+    }
+
+    ContractResult::Ok(claimable)
+}
+
+fn pay_keeper_bounty<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    keeper: &AccountAddress,
+    bounty: Amount,
+) -> ContractResult<()> {
+    let bounty = if bounty > host.state().fee_pool {
+        host.state().fee_pool
     } else {
-        host.state_mut().tokens.insert(
-            info,
-            TokenState {
-                sale_type,
-                curr_state,
-                owner,
-                expiry,
-                highest_bidder,
-                price
-            },
-        );
+        bounty
+    };
+    if bounty > Amount::zero() {
+        host.state_mut().fee_pool -= bounty;
+        host.invoke_transfer(keeper, bounty)
+            .map_err(|_| MarketplaceError::InvokeTransferError)?;
     }
-    ContractResult::Ok(())
+    Ok(())
 }
 
 #[derive(Serial, Deserial, SchemaType)]
-struct TradeNftParams {
+struct BidInTokenParams {
     nft_contract_address: ContractAddress,
     token_id: ContractTokenId,
-    price: Amount,
-    sale_type: u8
+    amount: TokenAmountU64,
+    allowlist_proof: Vec<[u8; 32]>,
 }
 
+/// Bids on an auction listed in a CIS-2 token, under the same rules as CCD
+/// bids. The bid is taken into custody and the previous highest bid credited
+/// back to its bidder.
 #[receive(
     contract = "Pixpel-NFTMarketplace",
-    name = "trade_market",
-    parameter = "TradeNftParams",
+    name = "bid_in_token",
+    parameter = "BidInTokenParams",
     mutable,
-    payable
+    enable_logger,
+    crypto_primitives
 )]
-fn trade_nft<S:HasStateApi>(
+fn bid_in_token<S: HasStateApi>(
     ctx: &impl HasReceiveContext,
     host: &mut impl HasHost<State<S>, StateApiType = S>,
-    amount: Amount
+    logger: &mut impl HasLogger,
+    crypto_primitives: &impl HasCryptoPrimitives,
 ) -> ContractResult<()> {
-    let params: TradeNftParams = ctx
+    let params: BidInTokenParams = ctx
         .parameter_cursor()
         .get()
         .map_err(|_e| MarketplaceError::ParseParams)?;
 
-    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
-    ensure!(host.state_mut().tokens.get(&info).is_some(), MarketplaceError::TokenNotListed);
+    host.state().ensure_not_paused()?;
+    let bidder = ensure_account_sender(ctx)?;
 
+    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
     let mut token_state = host
-        .state_mut()
+        .state()
         .tokens
-        .entry(info)
-        .occupied_or(MarketplaceError::TokenNotListed)?.to_owned();
-
-    let price = token_state.price;
-    ensure!(
-        amount.cmp(&price).is_gt(),
-        MarketplaceError::NotEnoughBalance
-    );
-    
-    if params.sale_type == 0 {
-        ensure!(token_state.sale_type == TokenSaleTypeState::Fixed, MarketplaceError::NotMatchedSaleType);
-
-        Cis2Client::transfer(
-            host,
-            params.token_id,
-            params.nft_contract_address,
-            concordium_cis2::TokenAmountU8(1),
-            token_state.owner,
-            concordium_cis2::Receiver::Account(ctx.invoker()),
-        )
-        .map_err(MarketplaceError::Cis2ClientError)?;
+        .get(&info)
+        .ok_or(MarketplaceError::TokenNotListed)?
+        .to_owned();
+    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::TokenNotListed);
+    let payment = match &token_state.currency {
+        Currency::Cis2(payment) => payment.clone(),
+        Currency::Ccd => bail!(MarketplaceError::CurrencyMismatch),
+    };
 
-        host.invoke_transfer(&token_state.owner, amount)
-            .map_err(|_| MarketplaceError::InvokeTransferError)?;
-            
-        token_state.owner = ctx.invoker();
-        token_state.sale_type = TokenSaleTypeState::Fixed;
-        token_state.curr_state = TokenListState::UnListed;
-        token_state.expiry = 0u64;
-        token_state.highest_bidder = AccountAddress([0u8;32]);
-        token_state.price = Amount { micro_ccd: 0u64 };
-    } else if params.sale_type == 1 {
-        ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
+    host.state()
+        .ensure_not_blocked(&info.address, &[bidder, token_state.owner])?;
+    ensure_bidder_allowed(crypto_primitives, &token_state, &bidder, &params.allowlist_proof)?;
+    host.state().ensure_identity_tier(&token_state, &bidder)?;
+    host.state().ensure_jurisdiction(&token_state, &bidder)?;
 
-        let slot_time = ctx.metadata().slot_time();
+    let now = ctx.metadata().slot_time().timestamp_millis();
+    // Token auctions take no participation deposit, so joining is free.
+    host.state_mut().join_auction(&info, &token_state, bidder);
+    host.state_mut()
+        .place_bid(&info, &mut token_state, bidder, params.amount.0, now)?;
 
-        ensure!(concordium_std::Timestamp::timestamp_millis(&slot_time) <= token_state.expiry, MarketplaceError::ExpiredAlready);
-        ensure!(ctx.invoker() != token_state.owner, MarketplaceError::CanNotBidYourSelf);
-        if token_state.highest_bidder != AccountAddress([0u8; 32]) {
-            host.invoke_transfer(&token_state.highest_bidder, token_state.price )
-            .map_err(|_| MarketplaceError::InvokeTransferError)?;
-        }
+    Cis2Client::transfer(
+        host,
+        payment.token_id.clone(),
+        payment.contract,
+        params.amount,
+        Address::Account(bidder),
+        custody(ctx),
+        custody_data(),
+    )
+    .map_err(MarketplaceError::from)?;
 
-        token_state.highest_bidder = ctx.invoker();
-        token_state.price = amount;
-    }
+    extend_for_late_bid(host, logger, &info, &mut token_state, now)?;
+    log_bid(host, logger, &info, &token_state, now)?;
 
+    let _ = host.state_mut().tokens.insert(info, token_state);
     ContractResult::Ok(())
 }
 
 #[derive(Serial, Deserial, SchemaType)]
-struct CancelTradeParams {
-    nft_contract_address: ContractAddress,
-    token_id: ContractTokenId,
-    sale_type: u8,
+struct ClaimTokenCreditsParams {
+    contract: ContractAddress,
+    token_id: TokenIdVec,
 }
 
+/// Pays out the sender's credited CIS-2 tokens of one kind: outbid token bids
+/// and proceeds of auctions settled in that token. Refused while the sender
+/// owes penalty debts.
 #[receive(
     contract = "Pixpel-NFTMarketplace",
-    name = "cancel_trade",
-    parameter = "CancelTradeParams",
-    mutable
+    name = "claim_token_credits",
+    parameter = "ClaimTokenCreditsParams",
+    return_value = "TokenAmountU64",
+    mutable,
+    enable_logger
 )]
-fn cancel_trade<S:HasStateApi>(
+fn claim_token_credits<S: HasStateApi>(
     ctx: &impl HasReceiveContext,
-    host: &mut impl HasHost<State<S>, StateApiType = S>
-) -> ContractResult<()> {
-    let params: CancelTradeParams = ctx
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
+) -> ContractResult<TokenAmountU64> {
+    let params: ClaimTokenCreditsParams = ctx
         .parameter_cursor()
         .get()
         .map_err(|_e| MarketplaceError::ParseParams)?;
 
-    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
-    ensure!(host.state_mut().tokens.get(&info).is_some(), MarketplaceError::TokenNotListed);
-    
-    let mut token_state = host
-        .state_mut()
-        .tokens
-        .entry(info)
-        .occupied_or(MarketplaceError::TokenNotListed)?.to_owned();
-        
-    ensure!(token_state.curr_state == TokenListState::Listed, MarketplaceError::CanceledAlready);
-    let sender = ctx.sender();
+    let account = ensure_account_sender(ctx)?;
     ensure!(
-        sender.matches_account(&token_state.owner),
-        MarketplaceError::Unauthorized
+        host.state().penalty_debts.get(&account).is_none(),
+        MarketplaceError::OutstandingPenaltyDebt
     );
+    let credit = host
+        .state_mut()
+        .token_credits
+        .remove_and_get(&(account, params.contract, params.token_id.clone()))
+        .unwrap_or(TokenAmountU64(0));
+    ensure!(credit.0 > 0, MarketplaceError::NothingToClaim);
 
-    if params.sale_type == 0 {
-        ensure!(token_state.sale_type == TokenSaleTypeState::Fixed, MarketplaceError::NotMatchedSaleType);   
-    } else if params.sale_type == 1 {
-        ensure!(token_state.sale_type == TokenSaleTypeState::Auction, MarketplaceError::NotMatchedSaleType);
-    }
+    Cis2Client::transfer(
+        host,
+        params.token_id.clone(),
+        params.contract,
+        credit,
+        Address::Contract(ctx.self_address()),
+        concordium_cis2::Receiver::Account(account),
+        AdditionalData::empty(),
+    )
+    .map_err(MarketplaceError::from)?;
 
-    token_state.sale_type = TokenSaleTypeState::Fixed;
-    token_state.curr_state = TokenListState::UnListed;
-    token_state.expiry = 0u64;
-    token_state.highest_bidder = AccountAddress([0u8; 32]);
-    token_state.price = Amount { micro_ccd: 0u64 };
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::TokenCreditsClaimed(TokenCreditsClaimedEvent {
+            account,
+            contract: params.contract,
+            token_id: params.token_id,
+            amount: credit,
+        }),
+    )?;
 
-    ContractResult::Ok(())
+    ContractResult::Ok(credit)
 }
 
-#[derive(Serial, Deserial, SchemaType)]
-struct FinaliseTradeParams {
-    nft_contract_address: ContractAddress,
-    token_id: ContractTokenId,
-    sale_type: u8,
+#[receive(
+    contract = "Pixpel-NFTMarketplace",
+    name = "fund_fee_pool",
+    mutable,
+    payable,
+    enable_logger
+)]
+fn fund_fee_pool<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+    logger: &mut impl HasLogger,
+) -> ContractResult<()> {
+    host.state_mut().fee_pool += amount;
+
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::FeePoolFunded(FeePoolFundedEvent {
+            from: ctx.sender(),
+            amount,
+        }),
+    )?;
+    ContractResult::Ok(())
 }
 
+/// Pays `amount` out of the fee pool to the fee account. Only the fee
+/// account itself may withdraw; sweep bounties draw on the same pool.
 #[receive(
     contract = "Pixpel-NFTMarketplace",
-    name = "finalise_trade",
-    parameter = "FinaliseTradeParams",
-    mutable
+    name = "withdraw_fees",
+    parameter = "Amount",
+    mutable,
+    enable_logger
 )]
-fn finalise_trade<S:HasStateApi>(
+fn withdraw_fees<S: HasStateApi>(
     ctx: &impl HasReceiveContext,
-    host: &mut impl HasHost<State<S>, StateApiType = S>
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    logger: &mut impl HasLogger,
 ) -> ContractResult<()> {
-    let params: FinaliseTradeParams = ctx
+    let amount: Amount = ctx
         .parameter_cursor()
         .get()
         .map_err(|_e| MarketplaceError::ParseParams)?;
-    
-    ensure!(params.sale_type.cmp(&1u8).is_ge(), MarketplaceError::NotMatchedSaleType);
-    
-    let info = TokenInfo::new(params.token_id, params.nft_contract_address);
-    ensure!(host.state_mut().tokens.get(&info).is_some(), MarketplaceError::TokenNotListed);
-    
-    let mut token_state = host
-        .state_mut()
-        .tokens
-        .entry(info)
-        .occupied_or(MarketplaceError::TokenNotListed)?.to_owned();
-    
-    let sender = ctx.sender();
-    ensure!(
-        sender.matches_account(&token_state.owner),
-        MarketplaceError::Unauthorized  
-    );
-
-    if token_state.highest_bidder != AccountAddress([0u8; 32]) {
-        host.invoke_transfer(&token_state.owner, token_state.price )
-            .map_err(|_| MarketplaceError::InvokeTransferError)?;
 
-        Cis2Client::transfer(
-            host,
-            params.token_id,
-            params.nft_contract_address,
-            concordium_cis2::TokenAmountU8(1),
-            token_state.owner,
-            concordium_cis2::Receiver::Account(ctx.invoker()),
-        )
-        .map_err(MarketplaceError::Cis2ClientError)?;
+    let sender = ensure_account_sender(ctx)?;
+    ensure!(host.state().fee_account == Some(sender), MarketplaceError::Unauthorized);
+    ensure!(amount <= host.state().fee_pool, MarketplaceError::InsufficientFeePool);
 
-        token_state.owner = ctx.invoker();
-        token_state.sale_type = TokenSaleTypeState::Fixed;
-        token_state.curr_state = TokenListState::UnListed;
-        token_state.expiry = 0u64;
-        token_state.highest_bidder = AccountAddress([0u8; 32]);
-        token_state.price = Amount { micro_ccd: 0u64 };
-    } else {
-        bail!(MarketplaceError::NotBidded)
-    }
+    host.state_mut().fee_pool -= amount;
+    host.invoke_transfer(&sender, amount)
+        .map_err(|_| MarketplaceError::InvokeTransferError)?;
 
+    host.state_mut().log_event(
+        logger,
+        MarketplaceEvent::Withdrawn(WithdrawalEvent {
+            account: sender,
+            amount,
+            source: WithdrawalSource::Fees,
+        }),
+    )?;
     ContractResult::Ok(())
 }
 
-pub struct Cis2Client;
-
-impl Cis2Client {
-    pub(crate) fn supports_cis2<S: HasStateApi>(
-        host: &mut impl HasHost<State<S>, StateApiType = S>,
-        nft_contract_address: &ContractAddress,
-    ) -> Result<bool, Cis2ClientError> {
-        let params = SupportsQueryParams {
-            queries: vec![StandardIdentifierOwned::new_unchecked("CIS-2".to_string())],
-        };
-        let parsed_res: SupportsQueryResponse = Cis2Client::invoke_contract_read_only(
-            host,
-            nft_contract_address,
-            SUPPORTS_ENTRYPOINT_NAME,
-            &params,
-        )?;
-        let supports_cis2: bool = {
-            let f = parsed_res
-                .results
-                .first()
-                .ok_or(Cis2ClientError::InvokeContractError)?;
-            match f {
-                SupportResult::NoSupport => false,
-                SupportResult::Support => true,
-                SupportResult::SupportBy(_) => false,
-            }
-        };
-
-        Ok(supports_cis2)
+fn distinct_collections<'a>(items: impl Iterator<Item = &'a TokenInfo>) -> Vec<ContractAddress> {
+    let mut collections = Vec::new();
+    for item in items {
+        if !collections.contains(&item.address) {
+            collections.push(item.address);
+        }
     }
+    collections
+}
 
-    pub(crate) fn is_operator_of<S: HasStateApi>(
-        host: &mut impl HasHost<State<S>, StateApiType = S>,
-        owner: Address,
-        current_contract_address: ContractAddress,
-        nft_contract_address: &ContractAddress,
-    ) -> Result<bool, Cis2ClientError> {
-        let params = &OperatorOfQueryParams {
-            queries: vec![OperatorOfQuery {
-                owner,
-                address: Address::Contract(current_contract_address),
-            }],
-        };
-
-        let parsed_res: OperatorOfQueryResponse = Cis2Client::invoke_contract_read_only(
-            host,
-            nft_contract_address,
-            OPERATOR_OF_ENTRYPOINT_NAME,
-            params,
-        )?;
-
-        let is_operator = parsed_res
-            .0
-            .first()
-            .ok_or(Cis2ClientError::InvokeContractError)?
-            .to_owned();
-
-        Ok(is_operator)
-    }
+/// Receiver for tokens taken into the marketplace's custody. Such transfers
+/// carry `custody_data`, which `onReceivingCIS2` accepts without listing.
+fn custody(ctx: &impl HasReceiveContext<()>) -> Receiver {
+    Receiver::Contract(
+        ctx.self_address(),
+        OwnedEntrypointName::new_unchecked(ON_RECEIVING_CIS2_ENTRYPOINT_NAME.to_string()),
+    )
+}
 
-    pub(crate) fn has_balance<S: HasStateApi>(
-        host: &mut impl HasHost<State<S>, StateApiType = S>,
-        token_id: ContractTokenId,
-        nft_contract_address: &ContractAddress,
-        owner: Address,
-    ) -> Result<bool, Cis2ClientError> {
-        let params = ContractBalanceOfQueryParams {
-            queries: vec![BalanceOfQuery {
-                token_id,
-                address: owner,
-            }],
-        };
+fn custody_data() -> AdditionalData {
+    AdditionalData::from(CUSTODY_TAG.to_vec())
+}
 
-        let parsed_res: ContractBalanceOfQueryResponse = Cis2Client::invoke_contract_read_only(
+fn release_custody<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    ctx: &impl HasReceiveContext<()>,
+    items: &[TokenInfo],
+    to: AccountAddress,
+    data: AdditionalData,
+) -> ContractResult<()> {
+    for item in items {
+        Cis2Client::transfer(
             host,
-            nft_contract_address,
-            BALANCE_OF_ENTRYPOINT_NAME,
-            &params,
-        )?;
-
-        let is_operator = parsed_res
-            .0
-            .first()
-            .ok_or(Cis2ClientError::InvokeContractError)?
-            .to_owned();
+            item.id,
+            item.address,
+            TokenAmountU64(1),
+            Address::Contract(ctx.self_address()),
+            concordium_cis2::Receiver::Account(to),
+            data.clone(),
+        )
+        .map_err(MarketplaceError::from)?;
+    }
+    Ok(())
+}
 
-        Result::Ok(is_operator.cmp(&TokenAmountU8(1)).is_ge())
+fn verify_allowlist_proof(
+    crypto_primitives: &impl HasCryptoPrimitives,
+    root: &[u8; 32],
+    account: &AccountAddress,
+    proof: &[[u8; 32]],
+) -> bool {
+    let mut node = crypto_primitives.hash_sha2_256(&account.0).0;
+    for sibling in proof {
+        let mut pair = [0u8; 64];
+        if node <= *sibling {
+            pair[..32].copy_from_slice(&node);
+            pair[32..].copy_from_slice(sibling);
+        } else {
+            pair[..32].copy_from_slice(sibling);
+            pair[32..].copy_from_slice(&node);
+        }
+        node = crypto_primitives.hash_sha2_256(&pair).0;
     }
+    node == *root
+}
 
-    pub(crate) fn transfer<S: HasStateApi>(
-        host: &mut impl HasHost<State<S>, StateApiType = S>,
-        token_id: TokenIdU32,
-        nft_contract_address: ContractAddress,
-        amount: ContractTokenAmount,
-        from: AccountAddress,
-        to: Receiver,
-    ) -> Result<bool, Cis2ClientError> {
-        let params: TransferParameter = TransferParams(vec![Transfer {
-            token_id,
-            amount,
-            from: concordium_std::Address::Account(from),
-            data: AdditionalData::empty(),
-            to,
-        }]);
+fn ensure_account_sender(ctx: &impl HasReceiveContext<()>) -> Result<AccountAddress, MarketplaceError> {
+    match ctx.sender() {
+        Address::Account(account) => Ok(account),
+        Address::Contract(_) => bail!(MarketplaceError::CalledByAContract),
+    }
+}
 
-        Cis2Client::invoke_contract_read_only(
-            host,
-            &nft_contract_address,
-            TRANSFER_ENTRYPOINT_NAME,
-            &params,
-        )?;
+fn ensure_buyer_allowed(
+    ctx: &impl HasReceiveContext<()>,
+    token_state: &TokenState,
+) -> Result<(), MarketplaceError> {
+    ensure!(
+        token_state.allow_contract_buyers || matches!(ctx.sender(), Address::Account(_)),
+        MarketplaceError::ContractBuyerNotAllowed
+    );
+    Ok(())
+}
 
-        Result::Ok(true)
-    }
-
-    fn invoke_contract_read_only<S: HasStateApi, R: Deserial, P: Serial>(
-        host: &mut impl HasHost<State<S>, StateApiType = S>,
-        contract_address: &ContractAddress,
-        entrypoint_name: &str,
-        params: &P,
-    ) -> Result<R, Cis2ClientError> {
-        let invoke_contract_result = host
-            .invoke_contract_read_only(
-                contract_address,
-                params,
-                EntrypointName::new(entrypoint_name).unwrap_abort(),
-                Amount::from_ccd(0),
-            )
-            .map_err(|_e| Cis2ClientError::InvokeContractError)?;
-        let mut invoke_contract_res = match invoke_contract_result {
-            Some(s) => s,
-            None => return Result::Err(Cis2ClientError::InvokeContractError),
-        };
-        let parsed_res =
-            R::deserial(&mut invoke_contract_res).map_err(|_e| Cis2ClientError::ParseResult)?;
+fn ensure_bidder_allowed(
+    crypto_primitives: &impl HasCryptoPrimitives,
+    token_state: &TokenState,
+    bidder: &AccountAddress,
+    allowlist_proof: &[[u8; 32]],
+) -> Result<(), MarketplaceError> {
+    let allowed = match &token_state.bidder_allowlist {
+        None => true,
+        Some(BidderAllowlist::Accounts(accounts)) => accounts.contains(bidder),
+        Some(BidderAllowlist::MerkleRoot(root)) => {
+            verify_allowlist_proof(crypto_primitives, root, bidder, allowlist_proof)
+        }
+    };
+    ensure!(allowed, MarketplaceError::BidderNotAllowlisted);
+    Ok(())
+}
 
-        Ok(parsed_res)
-    }
+fn ensure_not_self_trade(
+    ctx: &impl HasReceiveContext<()>,
+    seller: &AccountAddress,
+) -> Result<(), MarketplaceError> {
+    ensure!(
+        ctx.invoker() != *seller && !ctx.sender().matches_account(seller),
+        MarketplaceError::SelfTrade
+    );
+    Ok(())
 }
 
 fn ensure_supports_cis2<S: HasStateApi>(
     host: &mut impl HasHost<State<S>, StateApiType = S>,
     nft_contract_address: &ContractAddress,
 ) -> Result<(), MarketplaceError> {
-    let supports_cis2 = Cis2Client::supports_cis2(host, nft_contract_address)
-        .map_err(MarketplaceError::Cis2ClientError)?;
+    if host.state().validated_collections.contains(nft_contract_address) {
+        return Ok(());
+    }
+
+    let supports_cis2 = match Cis2Client::supports_cis2(host, nft_contract_address) {
+        Ok(supports_cis2) => supports_cis2,
+        Err(Cis2ClientError::InvokeContractError(Cis2CallError {
+            kind: Cis2CallErrorKind::MissingContract,
+            ..
+        })) => bail!(MarketplaceError::CollectionNotFound),
+        Err(error) => bail!(MarketplaceError::from(error)),
+    };
     ensure!(supports_cis2, MarketplaceError::CollectionNotCis2);
+
+    host.state_mut().validated_collections.insert(*nft_contract_address);
+    Ok(())
+}
+
+fn ensure_metadata_hash<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    info: &TokenInfo,
+    expected: &Option<[u8; 32]>,
+) -> Result<(), MarketplaceError> {
+    if let Some(expected) = expected {
+        let metadata = Cis2Client::token_metadata(host, info.id, &info.address)
+            .map_err(MarketplaceError::from)?;
+        ensure!(metadata.hash == Some(*expected), MarketplaceError::MetadataMismatch);
+    }
     Ok(())
 }
 
@@ -532,7 +8391,7 @@ fn ensure_is_operator<S: HasStateApi>(
         ctx.self_address(),
         nft_contract_address,
     )
-    .map_err(MarketplaceError::Cis2ClientError)?;
+    .map_err(MarketplaceError::from)?;
     ensure!(is_operator, MarketplaceError::NotOperator);
     Ok(())
 }
@@ -542,9 +8401,16 @@ fn ensure_balance<S: HasStateApi>(
     token_id: ContractTokenId,
     nft_contract_address: &ContractAddress,
     ctx: &impl HasReceiveContext<()>,
+    amount: ContractTokenAmount,
 ) -> Result<(), MarketplaceError> {
-    let has_balance = Cis2Client::has_balance(host, token_id, nft_contract_address, ctx.sender())
-        .map_err(MarketplaceError::Cis2ClientError)?;
+    let has_balance = Cis2Client::has_balance(
+        host,
+        token_id,
+        nft_contract_address,
+        ctx.sender(),
+        amount,
+    )
+    .map_err(MarketplaceError::from)?;
     ensure!(has_balance, MarketplaceError::NoBalance);
     Ok(())
 }
@@ -0,0 +1,334 @@
+//! Listing records and the order in which views return them.
+//!
+//! Records are keyed by [`ListingKey`]: the big-endian index the collection
+//! was given when it was first listed, followed by the big-endian token id.
+//! Contract state iterates keys in ascending byte order, so views return
+//! listings ascending by collection index and then by token id. Collection
+//! indices are never reassigned, so adding or removing listings does not move
+//! the others, and a cursor holding the last key of a page resumes at the same
+//! place on every node and across upgrades that keep this layout.
+//!
+//! Records still stored under the previous `TokenInfo` keys are read through
+//! and moved over on first write or by `migrate_listings`. They are not
+//! returned by pages until moved.
+
+use crate::{ContractTokenId, TokenInfo, TokenState};
+use concordium_cis2::TokenIdU32;
+use concordium_std::*;
+
+#[derive(Serialize, SchemaType, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct ListingKey([u8; 8]);
+
+impl ListingKey {
+    pub(crate) fn new(collection: u32, id: ContractTokenId) -> Self {
+        let mut key = [0u8; 8];
+        key[..4].copy_from_slice(&collection.to_be_bytes());
+        key[4..].copy_from_slice(&id.0.to_be_bytes());
+        ListingKey(key)
+    }
+
+    fn collection(&self) -> u32 {
+        let mut index = [0u8; 4];
+        index.copy_from_slice(&self.0[..4]);
+        u32::from_be_bytes(index)
+    }
+
+    fn token_id(&self) -> ContractTokenId {
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&self.0[4..]);
+        TokenIdU32(u32::from_be_bytes(id))
+    }
+}
+
+/// Listing records with collections interned to a `u32` index. Once
+/// `legacy_remaining` reaches zero the legacy map is no longer consulted.
+#[derive(Serial, DeserialWithState)]
+#[concordium(state_parameter = "S")]
+pub(crate) struct Listings<S> {
+    collection_indices: StateMap<ContractAddress, u32, S>,
+    collections: StateMap<u32, ContractAddress, S>,
+    next_collection_index: u32,
+    entries: StateMap<ListingKey, TokenState, S>,
+    legacy: StateMap<TokenInfo, TokenState, S>,
+    legacy_remaining: u64,
+}
+
+impl<S: HasStateApi> Listings<S> {
+    pub(crate) fn new(state_builder: &mut StateBuilder<S>) -> Self {
+        Listings {
+            collection_indices: state_builder.new_map(),
+            collections: state_builder.new_map(),
+            next_collection_index: 0,
+            entries: state_builder.new_map(),
+            legacy: state_builder.new_map(),
+            legacy_remaining: 0,
+        }
+    }
+
+    pub(crate) fn key(&self, info: &TokenInfo) -> Option<ListingKey> {
+        self.collection_indices
+            .get(&info.address)
+            .map(|index| ListingKey::new(*index, info.id))
+    }
+
+    fn intern(&mut self, info: &TokenInfo) -> ListingKey {
+        if let Some(key) = self.key(info) {
+            return key;
+        }
+        let index = self.next_collection_index;
+        self.next_collection_index += 1;
+        let _ = self.collection_indices.insert(info.address, index);
+        let _ = self.collections.insert(index, info.address);
+        ListingKey::new(index, info.id)
+    }
+
+    pub(crate) fn get(&self, info: &TokenInfo) -> Option<StateRef<'_, TokenState>> {
+        self.key(info)
+            .and_then(|key| self.entries.get(&key))
+            .or_else(|| (self.legacy_remaining > 0).then(|| self.legacy.get(info)).flatten())
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        info: TokenInfo,
+        token_state: TokenState,
+    ) -> Option<TokenState> {
+        self.migrate(&info);
+        let key = self.intern(&info);
+        self.entries.insert(key, token_state)
+    }
+
+    pub(crate) fn remove(&mut self, info: &TokenInfo) {
+        self.migrate(info);
+        if let Some(key) = self.key(info) {
+            self.entries.remove(&key);
+        }
+    }
+
+    pub(crate) fn entry(&mut self, info: TokenInfo) -> Entry<'_, ListingKey, TokenState, S> {
+        self.migrate(&info);
+        let key = self.intern(&info);
+        self.entries.entry(key)
+    }
+
+    fn migrate(&mut self, info: &TokenInfo) -> bool {
+        if self.legacy_remaining == 0 {
+            return false;
+        }
+        match self.legacy.remove_and_get(info) {
+            Some(token_state) => {
+                self.legacy_remaining -= 1;
+                let key = self.intern(info);
+                let _ = self.entries.insert(key, token_state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves up to `max` legacy records to the compact layout.
+    pub(crate) fn migrate_batch(&mut self, max: usize) -> u32 {
+        let pending: Vec<TokenInfo> =
+            self.legacy.iter().take(max).map(|(info, _)| info.clone()).collect();
+        pending.iter().filter(|info| self.migrate(info)).count() as u32
+    }
+
+    /// Up to `limit` records matching `filter`, in key order, starting after
+    /// the key `after` and skipping a further `skip` matches. The returned
+    /// cursor is the key of the last record and is `None` once no records
+    /// remain.
+    pub(crate) fn page(
+        &self,
+        after: Option<ListingKey>,
+        skip: usize,
+        limit: usize,
+        filter: impl Fn(&TokenState) -> bool,
+    ) -> (Vec<(TokenInfo, TokenState)>, Option<ListingKey>) {
+        let mut records = self
+            .entries
+            .iter()
+            .filter(|(key, _)| after.is_none_or(|after| **key > after))
+            .filter(|(_, token_state)| filter(token_state))
+            .skip(skip);
+        let mut page = Vec::new();
+        let mut last = None;
+        for (key, token_state) in records.by_ref().take(limit) {
+            let address = self
+                .collections
+                .get(&key.collection())
+                .map(|address| *address)
+                .unwrap_abort();
+            page.push((TokenInfo::new(key.token_id(), address), token_state.to_owned()));
+            last = Some(*key);
+        }
+        let cursor = last.filter(|_| records.next().is_some());
+        (page, cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_state::MeteredStateApi, TokenListState};
+
+    fn collection(index: u64) -> ContractAddress {
+        ContractAddress::new(index, 0)
+    }
+
+    fn listed(owner: AccountAddress) -> TokenState {
+        let mut token_state = TokenState::unlisted(owner);
+        token_state.curr_state = TokenListState::Listed;
+        token_state
+    }
+
+    fn listings(api: &MeteredStateApi, tokens: &[(u64, u32)]) -> Listings<MeteredStateApi> {
+        let mut state_builder = StateBuilder::open(api.clone());
+        let mut listings = Listings::new(&mut state_builder);
+        for (address, id) in tokens {
+            let info = TokenInfo::new(TokenIdU32(*id), collection(*address));
+            let _ = listings.insert(info, listed(AccountAddress([1u8; 32])));
+        }
+        listings
+    }
+
+    fn tokens(page: &[(TokenInfo, TokenState)]) -> Vec<(u64, u32)> {
+        page.iter().map(|(info, _)| (info.address.index, info.id.0)).collect()
+    }
+
+    #[test]
+    fn keys_order_by_collection_then_token() {
+        let keys = [
+            ListingKey::new(0, TokenIdU32(1)),
+            ListingKey::new(0, TokenIdU32(256)),
+            ListingKey::new(1, TokenIdU32(0)),
+            ListingKey::new(256, TokenIdU32(0)),
+        ];
+
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(keys.windows(2).all(|pair| to_bytes(&pair[0]) < to_bytes(&pair[1])));
+    }
+
+    #[test]
+    fn pages_follow_first_listing_order_of_collections() {
+        let api = MeteredStateApi::default();
+        let listings = listings(&api, &[(9, 300), (3, 2), (9, 1), (3, 1), (9, 2)]);
+
+        let (page, cursor) = listings.page(None, 0, 10, |_| true);
+
+        assert_eq!(tokens(&page), [(9, 1), (9, 2), (9, 300), (3, 1), (3, 2)]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn pages_concatenate_to_the_full_order() {
+        let api = MeteredStateApi::default();
+        let all: Vec<(u64, u32)> = (0..3).flat_map(|c| (0..4).map(move |id| (c, id))).collect();
+        let listings = listings(&api, &all);
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = listings.page(cursor, 0, 5, |_| true);
+            assert!(page.len() <= 5);
+            seen.extend(tokens(&page));
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen, all);
+    }
+
+    #[test]
+    fn cursor_resumes_after_changes_before_it() {
+        let api = MeteredStateApi::default();
+        let mut listings = listings(&api, &[(1, 1), (1, 2), (1, 3), (1, 4)]);
+        let (first, cursor) = listings.page(None, 0, 2, |_| true);
+        assert_eq!(tokens(&first), [(1, 1), (1, 2)]);
+
+        listings.remove(&TokenInfo::new(TokenIdU32(1), collection(1)));
+        let _ = listings.insert(
+            TokenInfo::new(TokenIdU32(0), collection(1)),
+            listed(AccountAddress([1u8; 32])),
+        );
+        let _ = listings.insert(
+            TokenInfo::new(TokenIdU32(5), collection(1)),
+            listed(AccountAddress([1u8; 32])),
+        );
+        let (second, cursor) = listings.page(cursor, 0, 10, |_| true);
+
+        assert_eq!(tokens(&second), [(1, 3), (1, 4), (1, 5)]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn collection_keeps_its_index_after_all_listings_leave() {
+        let api = MeteredStateApi::default();
+        let mut listings = listings(&api, &[(7, 1), (8, 1)]);
+        let info = TokenInfo::new(TokenIdU32(1), collection(7));
+        let key = listings.key(&info);
+
+        listings.remove(&info);
+        let _ = listings.insert(info.clone(), listed(AccountAddress([1u8; 32])));
+
+        assert_eq!(listings.key(&info), key);
+        assert_eq!(tokens(&listings.page(None, 0, 10, |_| true).0), [(7, 1), (8, 1)]);
+    }
+
+    #[test]
+    fn filtered_pages_resume_after_the_last_match() {
+        let api = MeteredStateApi::default();
+        let mut listings = listings(&api, &[]);
+        for id in 1..=5 {
+            let mut token_state = listed(AccountAddress([1u8; 32]));
+            token_state.price = Amount::from_micro_ccd(id.into());
+            let _ = listings.insert(TokenInfo::new(TokenIdU32(id), collection(1)), token_state);
+        }
+        let odd = |token_state: &TokenState| token_state.price.micro_ccd % 2 == 1;
+
+        let (first, cursor) = listings.page(None, 0, 2, odd);
+        assert_eq!(tokens(&first), [(1, 1), (1, 3)]);
+        let (second, cursor) = listings.page(cursor, 0, 2, odd);
+        assert_eq!(tokens(&second), [(1, 5)]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn offset_and_cursor_pages_agree() {
+        let api = MeteredStateApi::default();
+        let all: Vec<(u64, u32)> = (0..2).flat_map(|c| (0..5).map(move |id| (c, id))).collect();
+        let listings = listings(&api, &all);
+
+        let (first, cursor) = listings.page(None, 0, 4, |_| true);
+        let (by_cursor, next_by_cursor) = listings.page(cursor, 0, 4, |_| true);
+        let (by_offset, next_by_offset) = listings.page(None, first.len(), 4, |_| true);
+
+        assert_eq!(tokens(&by_cursor), tokens(&by_offset));
+        assert_eq!(next_by_cursor, next_by_offset);
+        assert_eq!(tokens(&listings.page(None, 10, 4, |_| true).0), []);
+    }
+
+    #[test]
+    fn drained_legacy_map_is_not_consulted() {
+        let api = MeteredStateApi::default();
+        let mut listings = listings(&api, &[]);
+        let info = TokenInfo::new(TokenIdU32(1), collection(1));
+        let unlisted = TokenInfo::new(TokenIdU32(2), collection(1));
+        let _ = listings.insert(info.clone(), listed(AccountAddress([1u8; 32])));
+
+        let found = api.measure(|| assert!(listings.get(&info).is_some()));
+        let found_directly = api.measure(|| {
+            let key = listings.key(&info).unwrap();
+            assert!(listings.entries.get(&key).is_some());
+        });
+        let missing = api.measure(|| assert!(listings.get(&unlisted).is_none()));
+        let missing_directly = api.measure(|| {
+            let key = listings.key(&unlisted).unwrap();
+            assert!(listings.entries.get(&key).is_none());
+        });
+
+        assert_eq!(found, found_directly);
+        assert_eq!(missing, missing_directly);
+    }
+}
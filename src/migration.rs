@@ -0,0 +1,294 @@
+//! Conversion of state written by an older module to the current layout.
+//!
+//! Every layout change since the state was versioned appended fields, to the
+//! root record or to the records of a map, so an old record is a prefix of the
+//! new one. Each step below appends the serialized defaults of the fields one
+//! version added; [`migrate`] runs the steps from the stored version up to
+//! [`STATE_VERSION`] and rewrites each affected record once. State written
+//! before version 8 does not record its version and cannot be migrated.
+
+use crate::{
+    AdminAction, AntiSnipe, BidIncrement, BidRecord, BoxPurchase, Currency, DutchAuction,
+    MarketplaceError, TokenInfo, DEFAULT_REFUND_MIN_AGE, STATE_VERSION,
+};
+use concordium_cis2::{TokenAmountU64, TokenIdVec};
+use concordium_std::*;
+
+/// Oldest layout [`migrate`] can convert.
+pub(crate) const OLDEST_MIGRATABLE_VERSION: u16 = 8;
+
+type StatePrefix = [u8; 8];
+
+/// Leading fields of the root record, unchanged in every migratable layout.
+/// Only the prefixes of the maps whose records a step rewrites are kept.
+#[derive(Deserial)]
+struct RootHead {
+    _state_version: u16,
+    _collection_indices: StatePrefix,
+    _collections: StatePrefix,
+    _next_collection_index: u32,
+    listings: StatePrefix,
+    legacy_listings: StatePrefix,
+    _legacy_remaining: u64,
+    _signers: Vec<AccountAddress>,
+    _threshold: u8,
+    proposals: StatePrefix,
+    _next_proposal_id: u64,
+    _paused: bool,
+    _fee_pool: Amount,
+    _sweep_bounty: Amount,
+    _max_auction_duration: u64,
+    _refunds: StatePrefix,
+    _refund_bounty: Amount,
+    listing_transfers: StatePrefix,
+}
+
+/// Bytes to append to the root record and to every stored `TokenState`, and
+/// whether pending listing transfers are dropped.
+#[derive(Default)]
+struct Tails {
+    root: Vec<u8>,
+    token_state: Vec<u8>,
+    drop_listing_transfers: bool,
+}
+
+/// Adds to `tails` the defaults of the fields layout `version + 1` added.
+fn step<S: HasStateApi>(
+    version: u16,
+    state_builder: &mut StateBuilder<S>,
+    tails: &mut Tails,
+) -> Result<(), MarketplaceError> {
+    match version {
+        // Listings payable in a CIS-2 token. The `Option<PaymentToken>` added
+        // here became `Currency` in 10, which encodes the same way.
+        8 => tails.token_state.extend(to_bytes(&Currency::Ccd)),
+        // Token credits owed to accounts.
+        9 => tails.root.extend(to_bytes(
+            &state_builder
+                .new_map::<(AccountAddress, ContractAddress, TokenIdVec), TokenAmountU64>(),
+        )),
+        // Escrowed listings.
+        10 => tails.token_state.extend(to_bytes(&None::<ContractAddress>)),
+        // Quantity listings; older listings hold a single token.
+        11 => tails.token_state.extend(to_bytes(&TokenAmountU64(1))),
+        // Minimum bid increments.
+        12 => tails.token_state.extend(to_bytes(&None::<BidIncrement>)),
+        // Anti-sniping extensions.
+        13 => tails.token_state.extend(to_bytes(&None::<AntiSnipe>)),
+        // Dutch auctions.
+        14 => tails.token_state.extend(to_bytes(&None::<DutchAuction>)),
+        // Keeper rewards.
+        15 => {
+            tails.root.extend(to_bytes(&0u16));
+            tails.token_state.extend(to_bytes(&None::<AccountAddress>));
+        }
+        // Bid history.
+        16 => {
+            tails.root.extend(to_bytes(&state_builder.new_map::<(TokenInfo, u32), BidRecord>()));
+            tails.root.extend(to_bytes(&state_builder.new_map::<TokenInfo, u32>()));
+        }
+        // Listing nonces. Pending transfers offered before them cannot be
+        // told apart from offers on an earlier listing of the token.
+        17 => {
+            tails.root.extend(to_bytes(&1u64));
+            tails.token_state.extend(to_bytes(&0u64));
+            tails.drop_listing_transfers = true;
+        }
+        // Bid histories kept across relisting. Relisting used to restart the
+        // history at index zero, so existing listings start there.
+        18 => tails.token_state.extend(to_bytes(&0u32)),
+        // Minimum deposit behind a reveal commitment.
+        19 => tails.root.extend(to_bytes(&Amount::zero())),
+        // Mystery box purchases awaiting payout and buyer secrets.
+        20 => {
+            tails.root.extend(to_bytes(&state_builder.new_map::<(u64, u32), BoxPurchase>()));
+            tails.root.extend(to_bytes(&state_builder.new_map::<u64, u64>()));
+        }
+        // Starting bids restored when a bid is withdrawn. Existing listings
+        // fall back to zero.
+        21 => tails.token_state.extend(to_bytes(&0u64)),
+        // Refund ages and the thresholds keepers are paid above. Refunds
+        // credited before count as old.
+        22 => {
+            tails.root.extend(to_bytes(&state_builder.new_map::<AccountAddress, u64>()));
+            tails.root.extend(to_bytes(&DEFAULT_REFUND_MIN_AGE));
+            tails.root.extend(to_bytes(&Amount::zero()));
+        }
+        _ => return Err(MarketplaceError::UnsupportedStateVersion),
+    }
+    Ok(())
+}
+
+/// Version recorded in the root record.
+pub(crate) fn stored_version<S: HasStateApi>(state_api: &S) -> Result<u16, MarketplaceError> {
+    let mut root = state_api.lookup_entry(&[]).ok_or(MarketplaceError::UnsupportedStateVersion)?;
+    u16::deserial(&mut root).map_err(|_| MarketplaceError::UnsupportedStateVersion)
+}
+
+/// Converts the state from layout `from` to [`STATE_VERSION`]. Steps that
+/// extend `TokenState` rewrite every stored listing in this one call, and drop
+/// pending `RepairTokens` proposals, whose snapshots no longer match.
+pub(crate) fn migrate<S: HasStateApi>(
+    state_api: &mut S,
+    state_builder: &mut StateBuilder<S>,
+    from: u16,
+) -> Result<(), MarketplaceError> {
+    ensure!(
+        (OLDEST_MIGRATABLE_VERSION..=STATE_VERSION).contains(&from),
+        MarketplaceError::UnsupportedStateVersion
+    );
+    let mut tails = Tails::default();
+    for version in from..STATE_VERSION {
+        step(version, state_builder, &mut tails)?;
+    }
+
+    let mut root = state_api.lookup_entry(&[]).ok_or(MarketplaceError::UnsupportedStateVersion)?;
+    let head = RootHead::deserial(&mut root).map_err(|_| MarketplaceError::MigrationFailed)?;
+    root.move_to_start();
+    STATE_VERSION.serial(&mut root).map_err(|_| MarketplaceError::MigrationFailed)?;
+    append(&mut root, &tails.root)?;
+
+    if !tails.token_state.is_empty() {
+        for prefix in [head.listings, head.legacy_listings] {
+            for key in keys(state_api, &prefix, |_| true)? {
+                let mut entry =
+                    state_api.lookup_entry(&key).ok_or(MarketplaceError::MigrationFailed)?;
+                append(&mut entry, &tails.token_state)?;
+            }
+        }
+        let repair_tag = to_bytes(&AdminAction::RepairTokens(Vec::new()))[0];
+        for key in keys(state_api, &head.proposals, |tag| tag == repair_tag)? {
+            let entry = state_api.lookup_entry(&key).ok_or(MarketplaceError::MigrationFailed)?;
+            state_api.delete_entry(entry).map_err(|_| MarketplaceError::MigrationFailed)?;
+        }
+    }
+    if tails.drop_listing_transfers {
+        state_api
+            .delete_prefix(&head.listing_transfers)
+            .map_err(|_| MarketplaceError::MigrationFailed)?;
+    }
+    Ok(())
+}
+
+fn append<E: HasStateEntry>(entry: &mut E, tail: &[u8]) -> Result<(), MarketplaceError> {
+    if tail.is_empty() {
+        return Ok(());
+    }
+    entry.seek(SeekFrom::End(0)).map_err(|_| MarketplaceError::MigrationFailed)?;
+    entry.write_all(tail).map_err(|_| MarketplaceError::MigrationFailed)
+}
+
+/// Keys of the records under `prefix` whose first byte passes `filter`. The
+/// keys are collected first, since the subtree stays locked while iterated.
+fn keys<S: HasStateApi>(
+    state_api: &mut S,
+    prefix: &[u8],
+    filter: impl Fn(u8) -> bool,
+) -> Result<Vec<Vec<u8>>, MarketplaceError> {
+    let mut iter = state_api.iterator(prefix).map_err(|_| MarketplaceError::MigrationFailed)?;
+    let keys = iter
+        .by_ref()
+        .filter_map(|mut entry| {
+            let first = entry.read_u8().unwrap_or_default();
+            filter(first).then(|| entry.get_key().to_vec())
+        })
+        .collect();
+    state_api.delete_iterator(iter);
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_state::MeteredStateApi, PendingListingTransfer, State, TokenListState, TokenState,
+    };
+    use concordium_cis2::TokenIdU32;
+
+    /// Bytes the root record and a `TokenState` gained from version 8 on.
+    const ROOT_GROWTH: u32 = 8 + 2 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8;
+    const TOKEN_STATE_GROWTH: u32 = 7 + 8 + 4 + 8;
+
+    fn info() -> TokenInfo {
+        TokenInfo::new(TokenIdU32(7), ContractAddress::new(3, 0))
+    }
+
+    /// A current-layout state with one listing, truncated to what version 8
+    /// stored.
+    fn version_8_state(api: &MeteredStateApi) -> Vec<u8> {
+        let mut state_builder = StateBuilder::open(api.clone());
+        let mut state = State::new(&mut state_builder, vec![AccountAddress([1u8; 32])], 1);
+        let mut token_state = TokenState::unlisted(AccountAddress([2u8; 32]));
+        token_state.curr_state = TokenListState::Listed;
+        let listing = to_bytes(&token_state);
+        let _ = state.tokens.insert(info(), token_state);
+        let _ = state.listing_transfers.insert(
+            info(),
+            PendingListingTransfer {
+                from: AccountAddress([2u8; 32]),
+                to: AccountAddress([3u8; 32]),
+                listing_nonce: 0,
+            },
+        );
+
+        let mut root = api.clone().create_entry(&[]).unwrap();
+        state.serial(&mut root).unwrap();
+        let size = root.size().unwrap();
+        root.truncate(size - ROOT_GROWTH).unwrap();
+        root.move_to_start();
+        8u16.serial(&mut root).unwrap();
+
+        let head = RootHead::deserial(&mut api.lookup_entry(&[]).unwrap()).unwrap();
+        let mut key = head.listings.to_vec();
+        key.extend(to_bytes(&state.tokens.key(&info()).unwrap()));
+        let mut entry = api.lookup_entry(&key).unwrap();
+        let size = entry.size().unwrap();
+        entry.truncate(size - TOKEN_STATE_GROWTH).unwrap();
+        listing
+    }
+
+    #[test]
+    fn migrates_version_8_to_current() {
+        let api = MeteredStateApi::default();
+        let listing = version_8_state(&api);
+
+        let mut state_builder = StateBuilder::open(api.clone());
+        migrate(&mut api.clone(), &mut state_builder, 8).unwrap();
+
+        assert_eq!(stored_version(&api), Ok(STATE_VERSION));
+        let state: State<MeteredStateApi> =
+            State::deserial_with_state(&api, &mut api.lookup_entry(&[]).unwrap()).unwrap();
+        assert_eq!(state.keeper_reward_bps, 0);
+        assert_eq!(state.next_listing_nonce, 1);
+        assert!(state.listing_transfers.get(&info()).is_none());
+        let token_state = state.tokens.get(&info()).unwrap();
+        assert_eq!(to_bytes(&*token_state), listing);
+    }
+
+    #[test]
+    fn current_version_is_left_alone() {
+        let api = MeteredStateApi::default();
+        let mut state_builder = StateBuilder::open(api.clone());
+        let state = State::new(&mut state_builder, vec![AccountAddress([1u8; 32])], 1);
+        let mut root = api.clone().create_entry(&[]).unwrap();
+        state.serial(&mut root).unwrap();
+        let before = to_bytes(&state);
+
+        migrate(&mut api.clone(), &mut state_builder, STATE_VERSION).unwrap();
+
+        let mut root = api.lookup_entry(&[]).unwrap();
+        let mut after = vec![0u8; root.size().unwrap() as usize];
+        root.read_exact(&mut after).unwrap();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn rejects_unversioned_state() {
+        let api = MeteredStateApi::default();
+        let mut state_builder = StateBuilder::open(api.clone());
+        assert_eq!(
+            migrate(&mut api.clone(), &mut state_builder, 7),
+            Err(MarketplaceError::UnsupportedStateVersion)
+        );
+    }
+}
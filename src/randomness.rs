@@ -0,0 +1,226 @@
+//! Commit–reveal randomness shared by mystery boxes and candle auctions.
+//!
+//! A committer publishes the hash of a secret seed and locks a deposit. The
+//! revealed seed is mixed with entropy gathered in the meantime before values
+//! are drawn from it. A committer who lets the reveal deadline pass forfeits
+//! the deposit, and callers fall back to the gathered entropy alone.
+
+use concordium_std::*;
+
+#[derive(Serialize, SchemaType, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Commitment {
+    pub(crate) committer: AccountAddress,
+    pub(crate) hash: [u8; 32],
+    pub(crate) deposit: Amount,
+    pub(crate) reveal_deadline: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RevealError {
+    Mismatch,
+    DeadlinePassed,
+}
+
+impl Commitment {
+    /// Checks a revealed `seed` against the commitment.
+    pub(crate) fn verify(
+        &self,
+        crypto_primitives: &impl HasCryptoPrimitives,
+        seed: &[u8; 32],
+        now: u64,
+    ) -> Result<(), RevealError> {
+        if now > self.reveal_deadline {
+            return Err(RevealError::DeadlinePassed);
+        }
+        if commit(crypto_primitives, seed) != self.hash {
+            return Err(RevealError::Mismatch);
+        }
+        Ok(())
+    }
+
+    /// Whether the deadline has passed, so that an unrevealed commitment
+    /// forfeits its deposit.
+    pub(crate) fn is_forfeit(&self, now: u64) -> bool {
+        now > self.reveal_deadline
+    }
+}
+
+pub(crate) fn commit(crypto_primitives: &impl HasCryptoPrimitives, seed: &[u8; 32]) -> [u8; 32] {
+    crypto_primitives.hash_sha2_256(seed).0
+}
+
+/// Combines a revealed seed (or none, after forfeiture) with gathered entropy.
+pub(crate) fn mix(
+    crypto_primitives: &impl HasCryptoPrimitives,
+    seed: Option<&[u8; 32]>,
+    entropy: &[u8],
+) -> [u8; 32] {
+    let mut input = Vec::with_capacity(32 + entropy.len());
+    input.extend_from_slice(seed.unwrap_or(&[0u8; 32]));
+    input.extend_from_slice(entropy);
+    crypto_primitives.hash_sha2_256(&input).0
+}
+
+/// A value in `0..bound` taken from `random`; zero if `bound` is zero.
+pub(crate) fn draw(random: &[u8; 32], bound: u64) -> u64 {
+    if bound == 0 {
+        return 0;
+    }
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&random[..8]);
+    u64::from_le_bytes(word) % bound
+}
+
+/// Fisher–Yates shuffle of `items`, rehashing `random` for every swap.
+pub(crate) fn shuffle<T>(
+    crypto_primitives: &impl HasCryptoPrimitives,
+    mut random: [u8; 32],
+    items: &mut [T],
+) {
+    for i in (1..items.len()).rev() {
+        random = crypto_primitives.hash_sha2_256(&random).0;
+        let j = draw(&random, i as u64 + 1) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic stand-in for the host's SHA2-256; the tests only rely on
+    /// it being a function of its input that spreads over all 32 bytes. The
+    /// other primitives are never used here and return fixed values.
+    struct TestHash;
+
+    impl HasCryptoPrimitives for TestHash {
+        fn verify_ed25519_signature(
+            &self,
+            _public_key: PublicKeyEd25519,
+            _signature: SignatureEd25519,
+            _message: &[u8],
+        ) -> bool {
+            false
+        }
+
+        fn verify_ecdsa_secp256k1_signature(
+            &self,
+            _public_key: PublicKeyEcdsaSecp256k1,
+            _signature: SignatureEcdsaSecp256k1,
+            _message_hash: [u8; 32],
+        ) -> bool {
+            false
+        }
+
+        fn hash_sha2_256(&self, data: &[u8]) -> HashSha2256 {
+            let mut out = [0u8; 32];
+            let mut acc = 0xcbf2_9ce4_8422_2325u64;
+            for (i, chunk) in out.chunks_mut(8).enumerate() {
+                for byte in data {
+                    acc = (acc ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+                }
+                acc = (acc ^ i as u64).wrapping_mul(0x0100_0000_01b3);
+                chunk.copy_from_slice(&acc.to_le_bytes());
+            }
+            HashSha2256(out)
+        }
+
+        fn hash_sha3_256(&self, _data: &[u8]) -> HashSha3256 {
+            HashSha3256([0u8; 32])
+        }
+
+        fn hash_keccak_256(&self, _data: &[u8]) -> HashKeccak256 {
+            HashKeccak256([0u8; 32])
+        }
+    }
+
+    fn commitment(crypto_primitives: &TestHash, seed: &[u8; 32]) -> Commitment {
+        Commitment {
+            committer: AccountAddress([1u8; 32]),
+            hash: commit(crypto_primitives, seed),
+            deposit: Amount::from_ccd(10),
+            reveal_deadline: 100,
+        }
+    }
+
+    #[test]
+    fn reveal_accepts_committed_seed() {
+        let crypto_primitives = TestHash;
+        let seed = [7u8; 32];
+        let commitment = commitment(&crypto_primitives, &seed);
+
+        assert_eq!(commitment.verify(&crypto_primitives, &seed, 100), Ok(()));
+    }
+
+    #[test]
+    fn reveal_rejects_other_seed() {
+        let crypto_primitives = TestHash;
+        let commitment = commitment(&crypto_primitives, &[7u8; 32]);
+
+        assert_eq!(
+            commitment.verify(&crypto_primitives, &[8u8; 32], 50),
+            Err(RevealError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn reveal_rejected_after_deadline() {
+        let crypto_primitives = TestHash;
+        let seed = [7u8; 32];
+        let commitment = commitment(&crypto_primitives, &seed);
+
+        assert_eq!(
+            commitment.verify(&crypto_primitives, &seed, 101),
+            Err(RevealError::DeadlinePassed)
+        );
+    }
+
+    #[test]
+    fn deposit_forfeit_only_after_deadline() {
+        let crypto_primitives = TestHash;
+        let commitment = commitment(&crypto_primitives, &[7u8; 32]);
+
+        assert!(!commitment.is_forfeit(100));
+        assert!(commitment.is_forfeit(101));
+    }
+
+    #[test]
+    fn mix_depends_on_seed_and_entropy() {
+        let crypto_primitives = TestHash;
+        let seed = [7u8; 32];
+
+        let base = mix(&crypto_primitives, Some(&seed), &[1, 2, 3]);
+        assert_eq!(base, mix(&crypto_primitives, Some(&seed), &[1, 2, 3]));
+        assert_ne!(base, mix(&crypto_primitives, Some(&[8u8; 32]), &[1, 2, 3]));
+        assert_ne!(base, mix(&crypto_primitives, Some(&seed), &[1, 2, 4]));
+        assert_ne!(base, mix(&crypto_primitives, None, &[1, 2, 3]));
+    }
+
+    #[test]
+    fn draw_stays_within_bound() {
+        let random = [0xffu8; 32];
+
+        assert_eq!(draw(&random, 0), 0);
+        assert_eq!(draw(&random, 1), 0);
+        for bound in 2..50 {
+            assert!(draw(&random, bound) < bound);
+        }
+    }
+
+    #[test]
+    fn shuffle_is_deterministic_permutation() {
+        let crypto_primitives = TestHash;
+        let random = mix(&crypto_primitives, Some(&[7u8; 32]), &[]);
+
+        let mut first: Vec<u32> = (0..20).collect();
+        shuffle(&crypto_primitives, random, &mut first);
+        let mut second: Vec<u32> = (0..20).collect();
+        shuffle(&crypto_primitives, random, &mut second);
+        assert_eq!(first, second);
+
+        let mut sorted = first.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..20).collect::<Vec<u32>>());
+        assert_ne!(first, sorted);
+    }
+}
@@ -0,0 +1,195 @@
+//! In-memory contract state for unit tests. It keeps entries in key order, as
+//! the chain does, and counts host calls and bytes moved so tests can compare
+//! the energy of different access patterns.
+
+use concordium_std::*;
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Meter {
+    pub(crate) calls: u64,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+}
+
+impl Meter {
+    /// Approximates the on-chain schedule; comparisons made with it hold for
+    /// any weights that charge calls and bytes positively.
+    pub(crate) fn energy(&self) -> u64 {
+        self.calls * 100 + self.bytes_read + self.bytes_written
+    }
+}
+
+type Store = BTreeMap<Vec<u8>, Rc<RefCell<Vec<u8>>>>;
+
+#[derive(Default, Clone)]
+pub(crate) struct MeteredStateApi {
+    store: Rc<RefCell<Store>>,
+    meter: Rc<RefCell<Meter>>,
+}
+
+impl MeteredStateApi {
+    fn charge(&self, bytes_read: usize, bytes_written: usize) {
+        let mut meter = self.meter.borrow_mut();
+        meter.calls += 1;
+        meter.bytes_read += bytes_read as u64;
+        meter.bytes_written += bytes_written as u64;
+    }
+
+    fn open(&self, key: &[u8], data: Rc<RefCell<Vec<u8>>>) -> MeteredEntry {
+        MeteredEntry {
+            api: self.clone(),
+            key: key.to_vec(),
+            data,
+            position: 0,
+        }
+    }
+
+    /// Runs `f` and returns what it cost.
+    pub(crate) fn measure(&self, f: impl FnOnce()) -> Meter {
+        let before = *self.meter.borrow();
+        f();
+        let after = *self.meter.borrow();
+        Meter {
+            calls: after.calls - before.calls,
+            bytes_read: after.bytes_read - before.bytes_read,
+            bytes_written: after.bytes_written - before.bytes_written,
+        }
+    }
+}
+
+pub(crate) struct MeteredEntry {
+    api: MeteredStateApi,
+    key: Vec<u8>,
+    data: Rc<RefCell<Vec<u8>>>,
+    position: u32,
+}
+
+impl Read for MeteredEntry {
+    fn read(&mut self, buf: &mut [u8]) -> ParseResult<usize> {
+        let data = self.data.borrow();
+        let start = (self.position as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.position += len as u32;
+        self.api.charge(len, 0);
+        Ok(len)
+    }
+}
+
+impl Write for MeteredEntry {
+    type Err = ();
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Err> {
+        let mut data = self.data.borrow_mut();
+        let start = self.position as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.position += buf.len() as u32;
+        self.api.charge(0, buf.len());
+        Ok(buf.len())
+    }
+}
+
+impl Seek for MeteredEntry {
+    type Err = ();
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u32, Self::Err> {
+        let len = self.data.borrow().len() as i64;
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(delta) => len + i64::from(delta),
+            SeekFrom::Current(delta) => i64::from(self.position) + i64::from(delta),
+        };
+        ensure!((0..=len).contains(&position));
+        self.position = position as u32;
+        Ok(self.position)
+    }
+
+    fn cursor_position(&self) -> u32 {
+        self.position
+    }
+}
+
+impl HasStateEntry for MeteredEntry {
+    type Error = ();
+    type StateEntryData = ();
+    type StateEntryKey = ();
+
+    fn move_to_start(&mut self) {
+        self.position = 0;
+    }
+
+    fn size(&self) -> Result<u32, Self::Error> {
+        self.api.charge(0, 0);
+        Ok(self.data.borrow().len() as u32)
+    }
+
+    fn truncate(&mut self, new_size: u32) -> Result<(), Self::Error> {
+        self.api.charge(0, 0);
+        self.data.borrow_mut().truncate(new_size as usize);
+        self.position = self.position.min(new_size);
+        Ok(())
+    }
+
+    fn get_key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn resize(&mut self, new_size: u32) -> Result<(), Self::Error> {
+        self.api.charge(0, 0);
+        self.data.borrow_mut().resize(new_size as usize, 0);
+        self.position = self.position.min(new_size);
+        Ok(())
+    }
+}
+
+impl HasStateApi for MeteredStateApi {
+    type EntryType = MeteredEntry;
+    type IterType = std::vec::IntoIter<MeteredEntry>;
+
+    fn create_entry(&mut self, key: &[u8]) -> Result<Self::EntryType, StateError> {
+        self.charge(0, 0);
+        let data = Rc::new(RefCell::new(Vec::new()));
+        self.store.borrow_mut().insert(key.to_vec(), data.clone());
+        Ok(self.open(key, data))
+    }
+
+    fn lookup_entry(&self, key: &[u8]) -> Option<Self::EntryType> {
+        self.charge(0, 0);
+        let data = self.store.borrow().get(key)?.clone();
+        Some(self.open(key, data))
+    }
+
+    fn delete_entry(&mut self, entry: Self::EntryType) -> Result<(), StateError> {
+        self.charge(0, 0);
+        self.store.borrow_mut().remove(&entry.key);
+        Ok(())
+    }
+
+    fn delete_prefix(&mut self, prefix: &[u8]) -> Result<bool, StateError> {
+        self.charge(0, 0);
+        let mut store = self.store.borrow_mut();
+        let before = store.len();
+        store.retain(|key, _| !key.starts_with(prefix));
+        Ok(store.len() != before)
+    }
+
+    fn iterator(&self, prefix: &[u8]) -> Result<Self::IterType, StateError> {
+        self.charge(0, 0);
+        let entries: Vec<MeteredEntry> = self
+            .store
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, data)| self.open(key, data.clone()))
+            .collect();
+        Ok(entries.into_iter())
+    }
+
+    fn delete_iterator(&mut self, _iter: Self::IterType) {
+        self.charge(0, 0);
+    }
+}
@@ -0,0 +1,467 @@
+//! Entrypoint tests against a mocked CIS-2 collection. The collection keeps
+//! its ledger in the test, so the tests can follow tokens in and out of the
+//! marketplace's custody.
+// The unit-test host is deprecated in favour of a crate not vendored here.
+#![allow(deprecated)]
+
+use super::*;
+use crate::cis2_client::ROYALTY_INFO_ENTRYPOINT_NAME;
+use concordium_std::test_infrastructure::*;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
+
+const SIGNER_A: AccountAddress = AccountAddress([1u8; 32]);
+const SIGNER_B: AccountAddress = AccountAddress([2u8; 32]);
+const SIGNER_C: AccountAddress = AccountAddress([3u8; 32]);
+const FEE_ACCOUNT: AccountAddress = AccountAddress([4u8; 32]);
+const SELLER: AccountAddress = AccountAddress([5u8; 32]);
+const BUYER: AccountAddress = AccountAddress([6u8; 32]);
+const BIDDER: AccountAddress = AccountAddress([7u8; 32]);
+const OUTBIDDER: AccountAddress = AccountAddress([8u8; 32]);
+const MARKET: ContractAddress = ContractAddress {
+    index: 1,
+    subindex: 0,
+};
+const COLLECTION: ContractAddress = ContractAddress {
+    index: 2,
+    subindex: 0,
+};
+const NOW: u64 = 1_000_000;
+const DAY: u64 = 24 * 60 * 60 * 1000;
+
+type TestMarket = TestHost<State<TestStateApi>>;
+
+/// Ledger of the mocked collection: balances by token and holder, and the
+/// `(owner, operator)` pairs.
+#[derive(Clone, Default)]
+struct Collection {
+    balances: Rc<RefCell<BTreeMap<(u32, Address), u64>>>,
+    operators: Rc<RefCell<BTreeSet<(Address, Address)>>>,
+}
+
+impl Collection {
+    /// Mints `token` to `owner`, who makes the marketplace their operator.
+    fn mint(&self, token: u32, owner: AccountAddress) {
+        self.balances.borrow_mut().insert((token, Address::Account(owner)), 1);
+        self.operators
+            .borrow_mut()
+            .insert((Address::Account(owner), Address::Contract(MARKET)));
+    }
+
+    fn balance(&self, token: u32, holder: Address) -> u64 {
+        self.balances.borrow().get(&(token, holder)).copied().unwrap_or(0)
+    }
+
+    fn mock(&self, host: &mut TestMarket) {
+        let entrypoint = |name: &str| OwnedEntrypointName::new_unchecked(name.to_string());
+        host.setup_mock_entrypoint(
+            COLLECTION,
+            entrypoint(SUPPORTS_ENTRYPOINT_NAME),
+            MockFn::new_v1(|_, _, _, _| {
+                Ok((false, SupportsQueryResponse {
+                    results: vec![SupportResult::Support],
+                }))
+            }),
+        );
+
+        let operators = self.operators.clone();
+        host.setup_mock_entrypoint(
+            COLLECTION,
+            entrypoint(OPERATOR_OF_ENTRYPOINT_NAME),
+            MockFn::new_v1(move |parameter: Parameter, _, _, _| {
+                let params: OperatorOfQueryParams = from_bytes(parameter.as_ref()).unwrap();
+                let operators = operators.borrow();
+                let results = params
+                    .queries
+                    .iter()
+                    .map(|query| operators.contains(&(query.owner, query.address)))
+                    .collect();
+                Ok((false, OperatorOfQueryResponse(results)))
+            }),
+        );
+
+        let balances = self.balances.clone();
+        host.setup_mock_entrypoint(
+            COLLECTION,
+            entrypoint(BALANCE_OF_ENTRYPOINT_NAME),
+            MockFn::new_v1(move |parameter: Parameter, _, _, _| {
+                let params: BalanceOfQueryParams<ContractTokenId> =
+                    from_bytes(parameter.as_ref()).unwrap();
+                let balances = balances.borrow();
+                let results = params
+                    .queries
+                    .iter()
+                    .map(|query| {
+                        let balance = balances.get(&(query.token_id.0, query.address));
+                        TokenAmountU64(balance.copied().unwrap_or(0))
+                    })
+                    .collect();
+                Ok((false, BalanceOfQueryResponse::<ContractTokenAmount>(results)))
+            }),
+        );
+
+        let balances = self.balances.clone();
+        host.setup_mock_entrypoint(
+            COLLECTION,
+            entrypoint(TRANSFER_ENTRYPOINT_NAME),
+            MockFn::new_v1(move |parameter: Parameter, _, _, _| {
+                let params: TransferParams<ContractTokenId, ContractTokenAmount> =
+                    from_bytes(parameter.as_ref()).unwrap();
+                let mut balances = balances.borrow_mut();
+                for transfer in params.0 {
+                    let to = match transfer.to {
+                        Receiver::Account(account) => Address::Account(account),
+                        Receiver::Contract(contract, _) => Address::Contract(contract),
+                    };
+                    let from = balances.entry((transfer.token_id.0, transfer.from)).or_insert(0);
+                    if *from < transfer.amount.0 {
+                        return Err(CallContractError::LogicReject {
+                            reason: -42000002,
+                            return_value: (),
+                        });
+                    }
+                    *from -= transfer.amount.0;
+                    *balances.entry((transfer.token_id.0, to)).or_insert(0) += transfer.amount.0;
+                }
+                Ok((false, ()))
+            }),
+        );
+
+        host.setup_mock_entrypoint(
+            COLLECTION,
+            entrypoint(ROYALTY_INFO_ENTRYPOINT_NAME),
+            MockFn::returning_err::<()>(CallContractError::MissingEntrypoint),
+        );
+    }
+}
+
+/// A marketplace run by a 2-of-3 signer set, and the collection it trades.
+fn marketplace() -> (TestMarket, Collection) {
+    let params = to_bytes(&InitParams {
+        signers: vec![SIGNER_A, SIGNER_B, SIGNER_C],
+        threshold: 2,
+        platform_fee_bps: 250,
+        fee_account: Some(FEE_ACCOUNT),
+    });
+    let mut ctx = TestInitContext::empty();
+    ctx.set_init_origin(SIGNER_A).set_parameter(&params);
+    let mut state_builder = TestStateBuilder::new();
+    let state = init(&ctx, &mut state_builder).unwrap();
+
+    let mut host = TestHost::new(state, state_builder);
+    host.set_self_address(MARKET);
+    let collection = Collection::default();
+    collection.mock(&mut host);
+    (host, collection)
+}
+
+fn receive_ctx(sender: AccountAddress, parameter: &[u8], now: u64) -> TestReceiveContext<'_> {
+    let mut ctx = TestReceiveContext::empty();
+    ctx.set_sender(Address::Account(sender))
+        .set_invoker(sender)
+        .set_owner(SIGNER_A)
+        .set_self_address(MARKET)
+        .set_parameter(parameter)
+        .set_metadata_slot_time(Timestamp::from_timestamp_millis(now));
+    ctx
+}
+
+fn info(token: u32) -> TokenInfo {
+    TokenInfo::new(TokenIdU32(token), COLLECTION)
+}
+
+fn listing(host: &TestMarket, token: u32) -> TokenState {
+    host.state().tokens.get(&info(token)).unwrap().to_owned()
+}
+
+fn logged(seq: u64, event: MarketplaceEvent) -> Vec<u8> {
+    to_bytes(&LoggedEvent {
+        seq,
+        event,
+    })
+}
+
+fn listing_params(token: u32, sale_type: u8, price: Amount) -> PlaceIntoMarketParams {
+    PlaceIntoMarketParams {
+        nft_contract_address: COLLECTION,
+        token_id: TokenIdU32(token),
+        price,
+        sale_type,
+        expiry: NOW + DAY,
+        drop_id: None,
+        memo: Vec::new(),
+        reserve: Amount::zero(),
+        buy_now: None,
+        charity: None,
+        min_bid_interval: 0,
+        participation_deposit: Amount::zero(),
+        convert_to_fixed: false,
+        allow_contract_buyers: false,
+        metadata_hash: None,
+        display_hash: None,
+        bidder_allowlist: None,
+        required_tier: 0,
+        disallowed_countries: Vec::new(),
+        candle: None,
+        royalty_bps: None,
+        royalty_recipient: None,
+        arbiter: None,
+        currency: Currency::Ccd,
+        amount: TokenAmountU64(1),
+        min_increment: None,
+        anti_snipe: None,
+        dutch_auction: None,
+    }
+}
+
+fn list(host: &mut TestMarket, token: u32, sale_type: u8, price: Amount) {
+    let params = to_bytes(&listing_params(token, sale_type, price));
+    let ctx = receive_ctx(SELLER, &params, NOW);
+    let mut logger = TestLogger::init();
+    add(&ctx, host, Amount::zero(), &mut logger).unwrap();
+}
+
+/// Calls `trade_nft` as `buyer` with `amount` attached, which the host is
+/// credited with as the chain would.
+fn trade(
+    host: &mut TestMarket,
+    buyer: AccountAddress,
+    token: u32,
+    sale_type: u8,
+    amount: Amount,
+) -> ContractResult<TradeResult> {
+    let params = to_bytes(&TradeNftParams {
+        nft_contract_address: COLLECTION,
+        token_id: TokenIdU32(token),
+        price: amount,
+        amount: TokenAmountU64(1),
+        sale_type,
+        referral_code: None,
+        allowlist_proof: Vec::new(),
+        data: AdditionalData::empty(),
+    });
+    let ctx = receive_ctx(buyer, &params, NOW + 1);
+    let mut logger = TestLogger::init();
+    let balance = host.self_balance();
+    host.set_self_balance(balance + amount);
+    trade_nft(&ctx, host, amount, &mut logger, &TestCryptoPrimitives::new())
+}
+
+fn approve_proposal(
+    host: &mut TestMarket,
+    signer: AccountAddress,
+    proposal_id: u64,
+) -> ContractResult<()> {
+    let params = to_bytes(&proposal_id);
+    let ctx = receive_ctx(signer, &params, NOW);
+    let mut logger = TestLogger::init();
+    approve(&ctx, host, &mut logger, &TestCryptoPrimitives::new())
+}
+
+fn exit(host: &mut TestMarket, sender: AccountAddress, token: u32) -> ContractResult<()> {
+    let params = to_bytes(&EmergencyExitParams {
+        nft_contract_address: COLLECTION,
+        token_id: TokenIdU32(token),
+    });
+    let ctx = receive_ctx(sender, &params, NOW + 2);
+    let mut logger = TestLogger::init();
+    emergency_exit(&ctx, host, &mut logger)
+}
+
+#[test]
+fn proposal_executes_at_threshold() {
+    let (mut host, _) = marketplace();
+    let bounty = Amount::from_ccd(5);
+
+    let params = to_bytes(&AdminAction::SetSweepBounty(bounty));
+    let ctx = receive_ctx(SIGNER_A, &params, NOW);
+    let mut logger = TestLogger::init();
+    let proposal_id = propose(&ctx, &mut host, &mut logger, &TestCryptoPrimitives::new()).unwrap();
+    assert_eq!(host.state().sweep_bounty, Amount::zero());
+    assert_eq!(
+        logger.logs,
+        vec![logged(
+            0,
+            MarketplaceEvent::ProposalMade(ProposalEvent {
+                proposal_id,
+                signer: SIGNER_A,
+                executed: false,
+            })
+        )]
+    );
+
+    assert_eq!(
+        approve_proposal(&mut host, SELLER, proposal_id),
+        Err(MarketplaceError::Unauthorized)
+    );
+    assert_eq!(
+        approve_proposal(&mut host, SIGNER_A, proposal_id),
+        Err(MarketplaceError::AlreadyApproved)
+    );
+    assert_eq!(host.state().sweep_bounty, Amount::zero());
+
+    approve_proposal(&mut host, SIGNER_B, proposal_id).unwrap();
+    assert_eq!(host.state().sweep_bounty, bounty);
+    assert!(host.state().proposals.get(&proposal_id).is_none());
+    assert_eq!(
+        approve_proposal(&mut host, SIGNER_C, proposal_id),
+        Err(MarketplaceError::ProposalNotFound)
+    );
+}
+
+#[test]
+fn proposal_from_non_signer_is_rejected() {
+    let (mut host, _) = marketplace();
+
+    let params = to_bytes(&AdminAction::SetPaused(true));
+    let ctx = receive_ctx(SELLER, &params, NOW);
+    let mut logger = TestLogger::init();
+    assert_eq!(
+        propose(&ctx, &mut host, &mut logger, &TestCryptoPrimitives::new()),
+        Err(MarketplaceError::Unauthorized)
+    );
+    assert!(!host.state().paused);
+}
+
+#[test]
+fn listed_token_is_held_in_custody_and_sold() {
+    let (mut host, collection) = marketplace();
+    collection.mint(1, SELLER);
+    let price = Amount::from_ccd(100);
+
+    list(&mut host, 1, 0, price);
+    assert_eq!(collection.balance(1, Address::Account(SELLER)), 0);
+    assert_eq!(collection.balance(1, Address::Contract(MARKET)), 1);
+    assert_eq!(listing(&host, 1).custodian, Some(MARKET));
+
+    assert_eq!(
+        trade(&mut host, BUYER, 1, 0, Amount::from_ccd(99)).err(),
+        Some(MarketplaceError::NotEnoughBalance)
+    );
+    let receipt = match trade(&mut host, BUYER, 1, 0, price).unwrap() {
+        TradeResult::Purchased(receipt) => receipt,
+        _ => panic!("expected a CCD purchase"),
+    };
+    assert_eq!(collection.balance(1, Address::Contract(MARKET)), 0);
+    assert_eq!(collection.balance(1, Address::Account(BUYER)), 1);
+    assert_eq!(receipt.seller_proceeds + receipt.fees, price);
+    assert_eq!(host.get_transfers_to(SELLER), vec![receipt.seller_proceeds]);
+
+    let token_state = listing(&host, 1);
+    assert_eq!(token_state.curr_state, TokenListState::UnListed);
+    assert_eq!(token_state.owner, BUYER);
+    assert_eq!(token_state.custodian, None);
+}
+
+#[test]
+fn cancelled_listing_returns_the_token() {
+    let (mut host, collection) = marketplace();
+    collection.mint(1, SELLER);
+    list(&mut host, 1, 0, Amount::from_ccd(100));
+
+    let params = to_bytes(&CancelTradeParams {
+        nft_contract_address: COLLECTION,
+        token_id: TokenIdU32(1),
+        sale_type: 0,
+    });
+    let ctx = receive_ctx(BUYER, &params, NOW + 1);
+    let mut logger = TestLogger::init();
+    assert_eq!(
+        cancel_trade(&ctx, &mut host, &mut logger),
+        Err(MarketplaceError::Unauthorized)
+    );
+
+    let ctx = receive_ctx(SELLER, &params, NOW + 1);
+    cancel_trade(&ctx, &mut host, &mut logger).unwrap();
+    assert_eq!(collection.balance(1, Address::Contract(MARKET)), 0);
+    assert_eq!(collection.balance(1, Address::Account(SELLER)), 1);
+    assert_eq!(listing(&host, 1).curr_state, TokenListState::UnListed);
+    assert_eq!(logger.logs.len(), 1);
+
+    assert_eq!(
+        trade(&mut host, BUYER, 1, 0, Amount::from_ccd(100)).err(),
+        Some(MarketplaceError::TokenNotListed)
+    );
+}
+
+#[test]
+fn outbid_bid_is_credited_and_withdrawn() {
+    let (mut host, collection) = marketplace();
+    collection.mint(1, SELLER);
+    list(&mut host, 1, 1, Amount::from_ccd(10));
+
+    trade(&mut host, BIDDER, 1, 1, Amount::from_ccd(11)).unwrap();
+    assert_eq!(
+        trade(&mut host, OUTBIDDER, 1, 1, Amount::from_ccd(11)).err(),
+        Some(MarketplaceError::NotEnoughBalance)
+    );
+    trade(&mut host, OUTBIDDER, 1, 1, Amount::from_ccd(15)).unwrap();
+    assert_eq!(listing(&host, 1).highest_bidder, OUTBIDDER);
+    assert_eq!(
+        host.state().refunds.get(&BIDDER).map(|refund| *refund),
+        Some(Amount::from_ccd(11))
+    );
+    assert!(host.get_transfers().is_empty());
+
+    let mut logger = TestLogger::init();
+    let params = to_bytes(&Amount::from_ccd(12));
+    let ctx = receive_ctx(BIDDER, &params, NOW + 2);
+    assert_eq!(
+        withdraw_refund(&ctx, &mut host, &mut logger),
+        Err(MarketplaceError::InsufficientRefund)
+    );
+
+    let params = to_bytes(&Amount::from_ccd(4));
+    let ctx = receive_ctx(BIDDER, &params, NOW + 2);
+    withdraw_refund(&ctx, &mut host, &mut logger).unwrap();
+    assert_eq!(
+        host.state().refunds.get(&BIDDER).map(|refund| *refund),
+        Some(Amount::from_ccd(7))
+    );
+
+    let ctx = receive_ctx(BIDDER, &[], NOW + 2);
+    assert_eq!(claim_all(&ctx, &mut host, &mut logger), Ok(Amount::from_ccd(7)));
+    assert_eq!(
+        host.get_transfers_to(BIDDER),
+        vec![Amount::from_ccd(4), Amount::from_ccd(7)]
+    );
+    assert!(host.state().refunds.get(&BIDDER).is_none());
+    assert_eq!(claim_all(&ctx, &mut host, &mut logger), Err(MarketplaceError::NothingToClaim));
+}
+
+#[test]
+fn emergency_exit_releases_bid_and_token() {
+    let (mut host, collection) = marketplace();
+    collection.mint(1, SELLER);
+    list(&mut host, 1, 1, Amount::from_ccd(10));
+    trade(&mut host, BIDDER, 1, 1, Amount::from_ccd(11)).unwrap();
+
+    assert_eq!(exit(&mut host, BIDDER, 1), Err(MarketplaceError::NotPaused));
+
+    let ctx = receive_ctx(SIGNER_C, &[], NOW + 2);
+    let mut logger = TestLogger::init();
+    pause(&ctx, &mut host, &mut logger).unwrap();
+    assert_eq!(
+        trade(&mut host, OUTBIDDER, 1, 1, Amount::from_ccd(20)).err(),
+        Some(MarketplaceError::ContractPaused)
+    );
+    assert_eq!(exit(&mut host, OUTBIDDER, 1), Err(MarketplaceError::Unauthorized));
+
+    exit(&mut host, BIDDER, 1).unwrap();
+    assert_eq!(
+        host.state().refunds.get(&BIDDER).map(|refund| *refund),
+        Some(Amount::from_ccd(11))
+    );
+    let token_state = listing(&host, 1);
+    assert_eq!(token_state.curr_state, TokenListState::Listed);
+    assert!(!token_state.has_bidder());
+    assert_eq!(token_state.price, Amount::from_ccd(10));
+
+    exit(&mut host, SELLER, 1).unwrap();
+    assert_eq!(collection.balance(1, Address::Account(SELLER)), 1);
+    assert_eq!(listing(&host, 1).curr_state, TokenListState::UnListed);
+    assert_eq!(exit(&mut host, SELLER, 1), Err(MarketplaceError::TokenNotListed));
+}